@@ -0,0 +1,105 @@
+/*
+ * C API for ad_libber, from src/capi.rs. Build the library with the capi feature, eg
+ *
+ *     cargo build --release --features capi
+ *
+ * Every function taking an engine returns ADLIB_OK or a negative error code, and
+ * adlib_last_error says why. Strings handed out belong to the engine and stay valid until
+ * the next call handing out the same kind of string, or until adlib_engine_destroy.
+ *
+ * This file is written by hand, not generated. Add a declaration here for every function
+ * exported from src/capi.rs. The test_header_matches test there fails unless this file
+ * declares exactly what it exports, with the same signatures, and the same constants.
+ */
+
+#ifndef AD_LIBBER_H
+#define AD_LIBBER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ADLIB_OK (0)
+#define ADLIB_ERR_NULL (-1)
+#define ADLIB_ERR_UTF8 (-2)
+#define ADLIB_ERR_NOT_FOUND (-3)
+#define ADLIB_ERR_PARSE (-4)
+#define ADLIB_ERR_OUT_OF_RANGE (-5)
+/* Something went wrong inside, the engine refuses every call but destroy from then on */
+#define ADLIB_ERR_PANIC (-6)
+
+/* Span flags */
+#define ADLIB_JIGGLY 1u
+#define ADLIB_WIDE 2u
+
+typedef struct AdlibEngine AdlibEngine;
+
+/* NULL if the engine couldn't be made */
+AdlibEngine *adlib_engine_create(void);
+/* NULL is ignored */
+void adlib_engine_destroy(AdlibEngine *engine);
+/* Why the last call that failed did, empty if none has */
+const char *adlib_last_error(const AdlibEngine *engine);
+
+/* Prepended to filenames, eg "dialogue/" to load dialogue/intro.adlib as "intro" */
+int adlib_set_base_path(AdlibEngine *engine, const char *path);
+/* Eg "fr" to read intro.fr.adlib where it exists, NULL or empty for the base files */
+int adlib_set_locale(AdlibEngine *engine, const char *locale);
+/* Lets scripts use (name ...) for the host, blocking non-zero waits for
+ * adlib_resume_custom_command. Call before loading the files that use it. */
+int adlib_register_custom_command(AdlibEngine *engine, const char *name, int blocking);
+/* Non-zero lets any unknown command through instead of failing to parse */
+int adlib_set_custom_command_pass_through(AdlibEngine *engine, int pass_through);
+/* Carries on after a blocking custom command, resumed may be NULL */
+int adlib_resume_custom_command(AdlibEngine *engine, int *resumed);
+/* ADLIB_ERR_NOT_FOUND if the file can't be read, ADLIB_ERR_PARSE if it's broken */
+int adlib_load(AdlibEngine *engine, const char *filename);
+/* Starts a section unless it's already playing, with oneshot non-zero it's skipped once seen */
+int adlib_queue(AdlibEngine *engine, const char *filename, const char *section, int oneshot);
+
+/* dt is in 60ths of a second */
+int adlib_tick(AdlibEngine *engine, float dt);
+/* Finishes revealing the line or moves past a finished one, advanced may be NULL */
+int adlib_advance(AdlibEngine *engine, int *advanced);
+/* Whether anything is playing, including text lingering after the end */
+int adlib_is_playing(AdlibEngine *engine, int *playing);
+
+/* Captures the text on screen, then read each span with adlib_span. # ends a line. */
+int adlib_spans_reset(AdlibEngine *engine, size_t *count);
+/* flags may be NULL */
+int adlib_span(AdlibEngine *engine, size_t index, const char **text, uint32_t *flags);
+
+/* Pops the oldest event, has_event is 0 when there are none */
+int adlib_next_event(AdlibEngine *engine, int *has_event, const char **name, const char **arg);
+/* Empty when nobody is speaking */
+int adlib_current_talker(AdlibEngine *engine, const char **name);
+
+/* 0 unless the dialogue is waiting on a choice */
+int adlib_choice_count(AdlibEngine *engine, size_t *count);
+int adlib_choice_text(AdlibEngine *engine, size_t index, const char **text);
+int adlib_choose(AdlibEngine *engine, size_t index);
+
+int adlib_set_variable_number(AdlibEngine *engine, const char *name, double value);
+int adlib_set_variable_string(AdlibEngine *engine, const char *name, const char *value);
+/* Unset variables are 0 */
+int adlib_get_variable_number(AdlibEngine *engine, const char *name, double *value);
+/* Numbers come back formatted, unset variables empty */
+int adlib_get_variable_string(AdlibEngine *engine, const char *name, const char **value);
+
+/* Times a section has started, or with line non-NULL that line. Lines are their #id: tag or
+ * their 1-based number in the section. */
+int adlib_seen_count(AdlibEngine *engine, const char *filename, const char *section, const char *line, uint32_t *count);
+/* 0 if there's no such section */
+int adlib_unseen_line_count(AdlibEngine *engine, const char *filename, const char *section, size_t *count);
+int adlib_export_seen(AdlibEngine *engine, const char **text);
+/* ADLIB_ERR_PARSE leaves the counts as they were */
+int adlib_import_seen(AdlibEngine *engine, const char *text);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
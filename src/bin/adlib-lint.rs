@@ -0,0 +1,99 @@
+// Checks .adlib files for mistakes and prints each as file:line:column, exiting non-zero if
+// there were any. Directories are searched for .adlib files.
+//
+// adlib-lint [--commands <file>] [--entry-points <file>] <file.adlib|dir>...
+//
+// --commands lists the custom commands the game handles, one per line with "blocking" after
+// those the game resumes, anything else is an unknown command. --entry-points lists the sections the game queues, one per line as
+// "section" or "file:section" with an optional trailing *, anything they don't lead to is
+// unreachable. Blank lines and lines starting with # are skipped in both.
+
+#![allow(unused_parens)]
+
+use std::path::Path;
+use std::process::ExitCode;
+
+use ad_libber::custom_commands::CustomCommands;
+use ad_libber::lint;
+
+fn read(filename : &str) -> Result<String, String> {
+    std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))
+}
+
+fn read_list(filename : &str) -> Result<Vec<String>, String> {
+    Ok(read(filename)?.lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with("#"))
+        .map(|x| x.to_owned())
+        .collect())
+}
+
+fn find_files(path : &Path, out : &mut Vec<String>) -> Result<(), String> {
+    if (path.is_dir()) {
+        let entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut paths = entries.filter_map(|x| x.ok()).map(|x| x.path()).collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            if (path.is_dir() || path.extension().map(|x| x == "adlib").unwrap_or(false)) {
+                find_files(&path, out)?;
+            }
+        }
+    }
+    else {
+        out.push(path.to_string_lossy().into_owned());
+    }
+
+    Ok(())
+}
+
+fn run(args : &[&str]) -> Result<usize, String> {
+    let mut custom_commands = CustomCommands::default();
+    let mut entry_points = vec![];
+    let mut filenames = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--commands" => {
+                let list = args.next().ok_or("--commands needs a file")?;
+                custom_commands = CustomCommands::parse_list(&read(list)?);
+            },
+            "--entry-points" => {
+                let list = args.next().ok_or("--entry-points needs a file")?;
+                entry_points = read_list(list)?;
+            },
+            path => find_files(Path::new(path), &mut filenames)?,
+        }
+    }
+
+    if (filenames.is_empty()) {
+        return Err("usage: adlib-lint [--commands <file>] [--entry-points <file>] <file.adlib|dir>...".to_owned());
+    }
+
+    let files = filenames.iter()
+        .map(|x| read(x).map(|contents| (x.clone(), contents)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let errors = lint::lint(&files, &custom_commands, &entry_points);
+    for error in &errors {
+        println!("{}", error);
+    }
+    Ok(errors.len())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|x| &x[..]).collect::<Vec<_>>();
+
+    match run(&args) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(count) => {
+            eprintln!("{} problems", count);
+            ExitCode::FAILURE
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(2)
+        },
+    }
+}
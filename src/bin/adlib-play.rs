@@ -0,0 +1,357 @@
+// Plays a section in the terminal to try out text without starting the game.
+//
+// adlib-play [--fast] [--commands <file>] <file.adlib> <section>
+//
+// Enter hurries the line along or moves on, a number picks a choice and q quits. Jiggly
+// text is drawn in shifting colours and wide text spaced out. --fast skips the typewriter
+// and prints each screen of text once it's complete, without colours when it isn't going to
+// a terminal. --commands lists the game's custom commands, the same as for adlib-lint.
+
+#![allow(unused_parens)]
+
+use std::io::{IsTerminal, Write};
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use ad_libber::custom_commands::CustomCommands;
+use ad_libber::dialogue::Annotation;
+use ad_libber::event::DialogueEvent;
+use ad_libber::interop::global_state::{GlobalState, MAIN_INSTANCE};
+use ad_libber::interop::queue_params::QueueParams;
+
+const FRAME : Duration = Duration::from_micros(16_667);
+
+const RESET : &str = "\x1b[0m";
+const DIM : &str = "\x1b[2m";
+const BOLD : &str = "\x1b[1m";
+const JIGGLY_COLOURS : [&str; 4] = ["\x1b[33m", "\x1b[35m", "\x1b[36m", "\x1b[32m"];
+
+// Text since the last clear and the annotations on each span
+type Spans = Vec<(String, Vec<Annotation>)>;
+
+fn capture(state : &GlobalState) -> Spans {
+    let mut spans = vec![];
+    let mut iter = state.main.engine.current_string_iter();
+    while let Some((text, span)) = iter.next() {
+        spans.push((text.to_owned(), span.annotations.clone()));
+    }
+    spans
+}
+
+#[derive(Default)]
+struct Screen
+{
+    // Speaker of each line since the last clear
+    speakers : Vec<Option<String>>,
+    waiting : bool,
+    // Terminal rows drawn last frame, to move back over them
+    rows : usize,
+    frame : usize,
+    fast : bool,
+    // Output isn't a terminal, so no colours or moving the cursor
+    plain : bool,
+    // Fast mode draws when the text is about to go, this is what it was before the tick
+    before_tick : Spans,
+}
+
+impl Screen {
+    fn style(&self, code : &'static str) -> &'static str {
+        if (self.plain) { "" } else { code }
+    }
+
+    fn on_event(&mut self, event : &DialogueEvent) {
+        match event {
+            DialogueEvent::LineStarted { talker } => self.speakers.push(talker.clone()),
+            DialogueEvent::WaitStarted { .. } => self.waiting = true,
+            DialogueEvent::WaitEnded => self.waiting = false,
+            DialogueEvent::Clear => {
+                if (self.fast && !self.before_tick.is_empty()) {
+                    let spans = std::mem::take(&mut self.before_tick);
+                    self.draw(&spans, None);
+                }
+                // Leave the old text in the scrollback
+                println!("{}--{}", self.style(DIM), self.style(RESET));
+                self.speakers.clear();
+                self.waiting = false;
+                self.rows = 0;
+            },
+            DialogueEvent::Custom { blocking : true, .. } => {
+                self.print_note(&format!("[{}] enter to carry on", event.arg()));
+            },
+            DialogueEvent::Custom { .. } | DialogueEvent::VoStarted { .. } => {
+                self.print_note(&format!("[{} {}]", event.name(), event.arg()));
+            },
+            _ => {},
+        }
+    }
+
+    fn pump_events(&mut self, state : &mut GlobalState) {
+        for event in state.main.engine.drain_events() {
+            self.on_event(&event);
+        }
+    }
+
+    // Notes go above the text so redrawing doesn't wipe them
+    fn print_note(&mut self, note : &str) {
+        self.erase();
+        println!("{}{}{}", self.style(DIM), note, self.style(RESET));
+    }
+
+    fn erase(&mut self) {
+        if (self.rows > 0 && !self.plain) {
+            print!("\x1b[{}A\r\x1b[J", self.rows);
+        }
+        self.rows = 0;
+    }
+
+    fn draw(&mut self, spans : &Spans, choices : Option<Vec<String>>) {
+        self.erase();
+        self.frame += 1;
+        let (reset, dim, bold) = (self.style(RESET), self.style(DIM), self.style(BOLD));
+
+        let width = std::env::var("COLUMNS").ok().and_then(|x| x.parse().ok()).unwrap_or(80usize).max(1);
+        let mut out = String::new();
+        let mut line = 0;
+        let mut line_len = 0;
+        let start_line = |out : &mut String, line : usize| {
+            if let Some(Some(speaker)) = self.speakers.get(line) {
+                out.push_str(&format!("{}{}:{} ", bold, speaker, reset));
+                speaker.chars().count() + 2
+            }
+            else {
+                0
+            }
+        };
+        line_len += start_line(&mut out, line);
+
+        // Lines end in #, only start the next one once there's something on it
+        let mut newline = false;
+        // Commands between spans leave a space that isn't part of either
+        let mut space = false;
+        let mut line_has_text = false;
+        for (text, annotations) in spans {
+            let jiggly = annotations.contains(&Annotation::Jiggly);
+            let wide = annotations.contains(&Annotation::Wide);
+            space |= line_has_text && !newline;
+
+            for (i, c) in text.chars().enumerate() {
+                if (c == '#') {
+                    newline = true;
+                    space = false;
+                    continue;
+                }
+                if (newline) {
+                    out.push('\n');
+                    self.rows += 1 + line_len / width;
+                    line += 1;
+                    line_len = start_line(&mut out, line);
+                    newline = false;
+                }
+                if (space) {
+                    out.push(' ');
+                    line_len += 1;
+                    space = false;
+                }
+
+                if (jiggly && !self.fast) {
+                    out.push_str(self.style(JIGGLY_COLOURS[(i + self.frame / 6) % JIGGLY_COLOURS.len()]));
+                }
+                else if (jiggly) {
+                    out.push_str(self.style(JIGGLY_COLOURS[0]));
+                }
+                if (wide) {
+                    out.push_str(bold);
+                }
+
+                out.push(c);
+                line_len += 1;
+                line_has_text = true;
+                if (wide && i + 1 < text.chars().count()) {
+                    out.push(' ');
+                    line_len += 1;
+                }
+                out.push_str(reset);
+            }
+        }
+
+        if (self.waiting && !self.fast) {
+            out.push_str(&format!("{}{}{}", dim, ["   ", ".  ", ".. ", "..."][(self.frame / 15) % 4], reset));
+            line_len += 3;
+        }
+
+        for (i, choice) in choices.iter().flatten().enumerate() {
+            out.push_str(&format!("\n  {}{}.{} {}", bold, i + 1, reset, choice));
+            self.rows += 1 + line_len / width;
+            line_len = choice.chars().count() + 5;
+        }
+
+        out.push('\n');
+        self.rows += 1 + line_len / width;
+
+        print!("{}", out);
+        std::io::stdout().flush().ok();
+    }
+}
+
+fn draw_state(screen : &mut Screen, state : &GlobalState) {
+    let choices = state.main.engine.choices().map(|x| x.iter().map(|x| x.text.clone()).collect());
+    screen.draw(&capture(state), choices);
+}
+
+fn spawn_input() -> Receiver<String> {
+    let (send, receive) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            match line {
+                Ok(line) => if (send.send(line.trim().to_owned()).is_err()) { break; },
+                Err(_) => break,
+            }
+        }
+    });
+    receive
+}
+
+// Returns false to quit.
+fn handle_input(state : &mut GlobalState, screen : &mut Screen, input : &str) -> bool {
+    // The terminal echoed the enter
+    if (std::io::stdin().is_terminal()) {
+        screen.rows += 1;
+    }
+
+    if (unicase::eq_ascii(input, "q")) {
+        return false;
+    }
+
+    if let Ok(n) = input.parse::<usize>() {
+        if (state.main.engine.choices().map(|x| n >= 1 && n <= x.len()).unwrap_or(false)) {
+            state.choose(MAIN_INSTANCE, n - 1);
+        }
+    }
+    else if (!state.main.engine.resume_custom_command()) {
+        state.advance(MAIN_INSTANCE);
+    }
+
+    true
+}
+
+fn play(filename : &str, section : &str, fast : bool, custom_commands : CustomCommands) -> Result<(), String> {
+    let path = std::path::Path::new(filename);
+    if (!path.exists()) {
+        return Err(format!("{}: no such file", filename));
+    }
+
+    let mut state = GlobalState {
+        path : path.parent().map(|x| x.to_string_lossy().into_owned()).filter(|x| !x.is_empty()).map(|x| x + "/").unwrap_or_default(),
+        ..Default::default()
+    };
+    // Before queueing, which parses the file
+    state.cache.custom_commands = custom_commands;
+    let name = path.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    state.queue(QueueParams {
+        filename : &name,
+        section,
+        ..Default::default()
+    });
+
+    let input = spawn_input();
+    let mut screen = Screen {
+        fast,
+        plain : !std::io::stdout().is_terminal(),
+        ..Default::default()
+    };
+
+    loop {
+        let started = Instant::now();
+
+        let mut waiting_on_player = state.main.engine.choices().is_some() || state.main.engine.blocking_command().is_some();
+        if (fast && waiting_on_player && !screen.before_tick.is_empty()) {
+            draw_state(&mut screen, &state);
+            screen.before_tick.clear();
+        }
+
+        // Fast mode only reads input when there's no way past, so it can be piped in
+        while (!fast || waiting_on_player) {
+            let received = if (fast) {
+                input.recv().map_err(|_| TryRecvError::Disconnected)
+            }
+            else {
+                input.try_recv()
+            };
+
+            match received {
+                Ok(line) => {
+                    if (!handle_input(&mut state, &mut screen, &line)) {
+                        return Ok(());
+                    }
+                    // Choosing can clear, which should see the text from before
+                    screen.pump_events(&mut state);
+                    waiting_on_player = false;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if (waiting_on_player) {
+                        return Ok(());
+                    }
+                    break;
+                },
+            }
+        }
+
+        // A single tick at most clears once, so the capture has everything that was shown
+        if (fast) {
+            state.complete_line(MAIN_INSTANCE);
+            screen.pump_events(&mut state);
+            screen.before_tick = capture(&state);
+        }
+        state.tick(1.0);
+        screen.pump_events(&mut state);
+
+        if (state.main.engine.current_filename().is_none()) {
+            return Ok(());
+        }
+
+        if (!fast) {
+            draw_state(&mut screen, &state);
+            std::thread::sleep(FRAME.saturating_sub(started.elapsed()));
+        }
+    }
+}
+
+fn run(args : &[&str]) -> Result<(), String> {
+    let mut fast = false;
+    let mut custom_commands = CustomCommands::default();
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--fast" => fast = true,
+            "--commands" => {
+                let list = args.next().ok_or("--commands needs a file")?;
+                let contents = std::fs::read_to_string(list).map_err(|e| format!("{}: {}", list, e))?;
+                custom_commands = CustomCommands::parse_list(&contents);
+            },
+            arg => rest.push(arg),
+        }
+    }
+
+    match &rest[..] {
+        [filename, section] => play(filename, section, fast, custom_commands),
+        _ => Err("usage: adlib-play [--fast] [--commands <file>] <file.adlib> <section>".to_owned()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|x| &x[..]).collect::<Vec<_>>();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
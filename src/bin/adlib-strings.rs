@@ -0,0 +1,111 @@
+// Exports the text of an .adlib file as a CSV or XLIFF table for translators, and turns a
+// translated table back into a locale file next to it, eg intro.adlib + fr -> intro.fr.adlib.
+//
+// adlib-strings [--commands <file>] export <file.adlib> <table.csv|table.xlf> [locale]
+// adlib-strings [--commands <file>] import <file.adlib> <table.csv|table.xlf> <locale>
+// adlib-strings [--commands <file>] assign-ids <file.adlib>...
+//
+// --commands lists the custom commands the game handles, the same as for adlib-lint.
+
+#![allow(unused_parens)]
+
+use std::process::ExitCode;
+
+use ad_libber::custom_commands::CustomCommands;
+use ad_libber::string_table;
+
+const USAGE : &str = "usage: adlib-strings [--commands <file>] export <file.adlib> <table.csv|table.xlf> [locale]
+       adlib-strings [--commands <file>] import <file.adlib> <table.csv|table.xlf> <locale>
+       adlib-strings [--commands <file>] assign-ids <file.adlib>...";
+
+fn is_xliff(table : &str) -> bool {
+    table.ends_with(".xlf") || table.ends_with(".xliff")
+}
+
+fn read(filename : &str) -> Result<String, String> {
+    std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))
+}
+
+fn export(filename : &str, table : &str, locale : Option<&str>, custom_commands : &CustomCommands) -> Result<(), String> {
+    let contents = read(filename)?;
+    let entries = string_table::extract(filename, &contents, custom_commands)
+        .map_err(|errors| errors.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n"))?;
+
+    let out = if (is_xliff(table)) {
+        string_table::to_xliff(&entries, "en", locale)
+    }
+    else {
+        string_table::to_csv(&entries)
+    };
+
+    std::fs::write(table, out).map_err(|e| format!("{}: {}", table, e))?;
+    eprintln!("{}: {} lines", table, entries.len());
+    Ok(())
+}
+
+fn import(filename : &str, table : &str, locale : &str, custom_commands : &CustomCommands) -> Result<(), String> {
+    let contents = read(filename)?;
+    let table_contents = read(table)?;
+
+    let translations = if (is_xliff(table)) {
+        string_table::read_xliff(&table_contents)
+    }
+    else {
+        string_table::read_csv(&table_contents)
+    }.map_err(|e| format!("{}: {}", table, e))?;
+
+    let translated = string_table::apply(filename, &contents, &translations, custom_commands)
+        .map_err(|errors| errors.iter().map(|x| format!("{}: {}", table, x)).collect::<Vec<_>>().join("\n"))?;
+
+    let base = filename.strip_suffix(".adlib").unwrap_or(filename);
+    let out = format!("{}.{}.adlib", base, locale);
+    std::fs::write(&out, &translated.contents).map_err(|e| format!("{}: {}", out, e))?;
+
+    for id in &translated.untranslated {
+        eprintln!("{}: untranslated {}", out, id);
+    }
+    Ok(())
+}
+
+// Tags untagged lines in place, run before exporting so table ids survive edits.
+fn assign_ids(filenames : &[&str], custom_commands : &CustomCommands) -> Result<(), String> {
+    for filename in filenames {
+        let contents = read(filename)?;
+        let assigned = string_table::assign_line_ids(filename, &contents, custom_commands)
+            .map_err(|errors| errors.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n"))?;
+
+        if (assigned != contents) {
+            std::fs::write(filename, &assigned).map_err(|e| format!("{}: {}", filename, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn run(args : &[&str]) -> Result<(), String> {
+    let (custom_commands, args) = match args {
+        ["--commands", list, args @ ..] => (CustomCommands::parse_list(&read(list)?), args),
+        _ => (CustomCommands::default(), args),
+    };
+
+    match args {
+        ["export", filename, table] => export(filename, table, None, &custom_commands),
+        ["export", filename, table, locale] => export(filename, table, Some(locale), &custom_commands),
+        ["import", filename, table, locale] => import(filename, table, locale, &custom_commands),
+        ["assign-ids", filenames @ ..] if !filenames.is_empty() => assign_ids(filenames, &custom_commands),
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|x| &x[..]).collect::<Vec<_>>();
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -0,0 +1,113 @@
+// Plays a section headlessly and prints its transcript, or checks it against a golden file.
+//
+// adlib-transcript [options] <file.adlib> <section>
+//
+// --script <file>     input to give, see transcript::Script
+// --commands <file>   the game's custom commands, the same as for adlib-lint
+// --dt <frames>       per tick, 1 by default
+// --seed <n>          for random blocks
+// --blips             include blip events
+// --wait-for-input    hold at the end of every line until advance
+// --golden <file>     compare with a stored transcript, exits non-zero and prints the
+//                     differences if they don't match
+// --update            write the golden file instead of comparing
+
+#![allow(unused_parens)]
+
+use std::process::ExitCode;
+
+use ad_libber::custom_commands::CustomCommands;
+use ad_libber::interop::global_state::GlobalState;
+use ad_libber::transcript::{self, Script, TranscriptOptions};
+
+const USAGE : &str = "usage: adlib-transcript [--script <file>] [--commands <file>] [--dt <frames>] [--seed <n>] [--blips] [--wait-for-input] [--golden <file> [--update]] <file.adlib> <section>";
+
+fn read(filename : &str) -> Result<String, String> {
+    std::fs::read_to_string(filename).map_err(|e| format!("{}: {}", filename, e))
+}
+
+// Ok(false) when the golden file didn't match.
+fn run(args : &[&str]) -> Result<bool, String> {
+    let mut options = TranscriptOptions::default();
+    let mut script = Script::default();
+    let mut custom_commands = CustomCommands::default();
+    let mut wait_for_input = false;
+    let mut golden = None;
+    let mut update = false;
+    let mut positional = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().copied().ok_or(format!("{} needs a value", arg));
+        match *arg {
+            "--script" => {
+                let filename = value()?;
+                script = Script::parse(&read(filename)?).map_err(|e| format!("{}:{}", filename, e))?;
+            },
+            "--commands" => custom_commands = CustomCommands::parse_list(&read(value()?)?),
+            "--dt" => options.dt = value()?.parse().map_err(|_| "--dt needs a number".to_owned())?,
+            "--seed" => options.seed = value()?.parse().map_err(|_| "--seed needs a number".to_owned())?,
+            "--golden" => golden = Some(value()?),
+            "--blips" => options.blips = true,
+            "--wait-for-input" => wait_for_input = true,
+            "--update" => update = true,
+            _ => positional.push(*arg),
+        }
+    }
+
+    let (filename, section) = match positional[..] {
+        [filename, section] => (filename, section),
+        _ => return Err(USAGE.to_owned()),
+    };
+
+    let path = std::path::Path::new(filename);
+    if (!path.exists()) {
+        return Err(format!("{}: no such file", filename));
+    }
+
+    let mut state = GlobalState {
+        path : path.parent().map(|x| x.to_string_lossy().into_owned()).filter(|x| !x.is_empty()).map(|x| x + "/").unwrap_or_default(),
+        ..Default::default()
+    };
+    state.main.engine.options.wait_for_input = wait_for_input;
+    // Before record queues the section, which parses the file
+    state.cache.custom_commands = custom_commands;
+    let name = path.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let actual = transcript::record(&mut state, &name, section, &script, &options);
+
+    match golden {
+        None => {
+            print!("{}", actual);
+            Ok(true)
+        },
+        Some(golden) if update => {
+            std::fs::write(golden, &actual).map_err(|e| format!("{}: {}", golden, e))?;
+            Ok(true)
+        },
+        Some(golden) => {
+            match transcript::diff(&read(golden)?, &actual) {
+                Some(diff) => {
+                    println!("{} differs:", golden);
+                    print!("{}", diff);
+                    Ok(false)
+                },
+                None => Ok(true),
+            }
+        },
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|x| &x[..]).collect::<Vec<_>>();
+
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(2)
+        },
+    }
+}
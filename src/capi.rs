@@ -0,0 +1,802 @@
+// C API for engines other than GameMaker, declared in include/ad_libber.h. The header is kept
+// in step by hand, see test_header_matches. Each engine is an opaque handle so hosts can have
+// as many as they like. Nothing here panics across the boundary: bad arguments come back as
+// error codes, and a panic inside is caught, reported as ADLIB_ERR_PANIC and leaves the
+// handle refusing further calls except destroy.
+//
+// Strings handed out are owned by the handle and stay valid until the next call that hands
+// out the same kind of string, or until destroy.
+
+// Pointers from C are checked for null and trusted otherwise, so every function taking one
+// is unsafe to call from Rust. A live engine is one from adlib_engine_create that hasn't been
+// destroyed.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::dialogue::Annotation;
+use crate::interop::global_state::{GlobalState, MAIN_INSTANCE};
+use crate::interop::queue_params::QueueParams;
+use crate::parse_error::ParseErrorKind;
+use crate::variables::Value;
+
+pub const ADLIB_OK : c_int = 0;
+pub const ADLIB_ERR_NULL : c_int = -1;
+pub const ADLIB_ERR_UTF8 : c_int = -2;
+pub const ADLIB_ERR_NOT_FOUND : c_int = -3;
+pub const ADLIB_ERR_PARSE : c_int = -4;
+pub const ADLIB_ERR_OUT_OF_RANGE : c_int = -5;
+pub const ADLIB_ERR_PANIC : c_int = -6;
+
+pub const ADLIB_JIGGLY : u32 = 1;
+pub const ADLIB_WIDE : u32 = 2;
+
+pub struct AdlibEngine
+{
+    state : GlobalState,
+    // Captured by adlib_spans_reset
+    spans : Vec<(CString, u32)>,
+    event_name : CString,
+    event_arg : CString,
+    choice_text : CString,
+    talker : CString,
+    variable : CString,
+    seen : CString,
+    last_error : CString,
+    poisoned : bool,
+}
+
+struct Error
+{
+    code : c_int,
+    message : String,
+}
+
+impl Error {
+    fn new(code : c_int, message : &str) -> Self {
+        Self {
+            code,
+            message : message.to_owned(),
+        }
+    }
+}
+
+fn to_c_string(s : &str) -> CString {
+    // Interior nuls can't cross, drop them rather than the whole string
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
+unsafe fn str_arg<'a>(p : *const c_char, name : &str) -> Result<&'a str, Error> {
+    if (p.is_null()) {
+        return Err(Error::new(ADLIB_ERR_NULL, &format!("{} is null", name)));
+    }
+    CStr::from_ptr(p).to_str().map_err(|_| Error::new(ADLIB_ERR_UTF8, &format!("{} is not utf-8", name)))
+}
+
+unsafe fn out_arg<'a, T>(p : *mut T, name : &str) -> Result<&'a mut T, Error> {
+    p.as_mut().ok_or_else(|| Error::new(ADLIB_ERR_NULL, &format!("{} is null", name)))
+}
+
+// Runs f on the engine behind handle, turning errors and panics into codes. handle is null
+// or a live engine.
+unsafe fn guard(handle : *mut AdlibEngine, f : impl FnOnce(&mut AdlibEngine) -> Result<(), Error>) -> c_int {
+    let engine = match handle.as_mut() {
+        Some(engine) => engine,
+        None => return ADLIB_ERR_NULL,
+    };
+
+    if (engine.poisoned) {
+        return ADLIB_ERR_PANIC;
+    }
+
+    match catch_unwind(AssertUnwindSafe(|| f(engine))) {
+        Ok(Ok(())) => ADLIB_OK,
+        Ok(Err(err)) => {
+            engine.last_error = to_c_string(&err.message);
+            err.code
+        },
+        Err(_) => {
+            engine.poisoned = true;
+            engine.last_error = to_c_string("panicked, the engine can only be destroyed");
+            ADLIB_ERR_PANIC
+        },
+    }
+}
+
+// NULL if the engine couldn't be made.
+#[no_mangle]
+pub extern "C" fn adlib_engine_create() -> *mut AdlibEngine {
+    let engine = catch_unwind(|| AdlibEngine {
+        state : GlobalState::default(),
+        spans : vec![],
+        event_name : CString::default(),
+        event_arg : CString::default(),
+        choice_text : CString::default(),
+        talker : CString::default(),
+        variable : CString::default(),
+        seen : CString::default(),
+        last_error : CString::default(),
+        poisoned : false,
+    });
+
+    match engine {
+        Ok(engine) => Box::into_raw(Box::new(engine)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Null is ignored. The handle and every string it handed out are gone afterwards.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine that isn't used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_engine_destroy(handle : *mut AdlibEngine) {
+    if (!handle.is_null()) {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(unsafe { Box::from_raw(handle) })));
+    }
+}
+
+/// Why the last call that failed did, empty if none has.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_last_error(handle : *const AdlibEngine) -> *const c_char {
+    match unsafe { handle.as_ref() } {
+        Some(engine) => engine.last_error.as_ptr(),
+        None => c"".as_ptr(),
+    }
+}
+
+/// Prepended to filenames, eg "dialogue/" to load dialogue/intro.adlib as "intro".
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `path` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_set_base_path(handle : *mut AdlibEngine, path : *const c_char) -> c_int {
+    guard(handle, |engine| {
+        engine.state.path = unsafe { str_arg(path, "path") }?.to_owned();
+        Ok(())
+    })
+}
+
+/// Eg "fr" to read intro.fr.adlib where it exists, null or empty for the base files.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `locale` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_set_locale(handle : *mut AdlibEngine, locale : *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let locale = if (locale.is_null()) { None } else { Some(unsafe { str_arg(locale, "locale") }?) };
+        engine.state.cache.set_locale(locale);
+        Ok(())
+    })
+}
+
+/// Lets scripts use (name ...) for the host to handle, as a custom_command event. With blocking
+/// non-zero the text waits until adlib_resume_custom_command. Register before loading, files
+/// already loaded keep what they were parsed with.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `name` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_register_custom_command(handle : *mut AdlibEngine, name : *const c_char, blocking : c_int) -> c_int {
+    guard(handle, |engine| {
+        let name = unsafe { str_arg(name, "name") }?;
+        engine.state.cache.custom_commands.register(name, blocking != 0);
+        Ok(())
+    })
+}
+
+/// With pass_through non-zero any unknown command reaches the host instead of being a parse
+/// error. Set before loading like adlib_register_custom_command.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_set_custom_command_pass_through(handle : *mut AdlibEngine, pass_through : c_int) -> c_int {
+    guard(handle, |engine| {
+        engine.state.cache.custom_commands.set_pass_through(pass_through != 0);
+        Ok(())
+    })
+}
+
+/// Lets the text carry on after a blocking custom command. resumed is set to whether one was
+/// waiting, and may be null.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `resumed` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_resume_custom_command(handle : *mut AdlibEngine, resumed : *mut c_int) -> c_int {
+    guard(handle, |engine| {
+        let result = engine.state.main.engine.resume_custom_command();
+        if let Some(resumed) = unsafe { resumed.as_mut() } {
+            *resumed = result as c_int;
+        }
+        Ok(())
+    })
+}
+
+fn load(engine : &mut AdlibEngine, filename : &str) -> Result<(), Error> {
+    engine.state.preload(filename);
+
+    let full_filename = engine.state.full_filename(filename);
+    match engine.state.cache.get_errors(&full_filename) {
+        Some(errors) if errors.iter().all(|x| matches!(x.kind, ParseErrorKind::Io(_))) => {
+            Err(Error::new(ADLIB_ERR_NOT_FOUND, &errors.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n")))
+        },
+        Some(errors) => {
+            Err(Error::new(ADLIB_ERR_PARSE, &errors.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n")))
+        },
+        None => Ok(()),
+    }
+}
+
+/// Parses filename ahead of time. ADLIB_ERR_NOT_FOUND if it can't be read, ADLIB_ERR_PARSE
+/// with the errors in adlib_last_error if it's broken.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `filename` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_load(handle : *mut AdlibEngine, filename : *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let filename = unsafe { str_arg(filename, "filename") }?;
+        load(engine, filename)
+    })
+}
+
+/// Starts filename's section unless it's already playing. With oneshot non-zero it's skipped
+/// once the section has been seen. ADLIB_ERR_NOT_FOUND if there's no such section, and nothing plays.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `filename` and `section` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_queue(handle : *mut AdlibEngine, filename : *const c_char, section : *const c_char, oneshot : c_int) -> c_int {
+    guard(handle, |engine| {
+        let filename = unsafe { str_arg(filename, "filename") }?;
+        let section = unsafe { str_arg(section, "section") }?;
+        load(engine, filename)?;
+
+        let full_filename = engine.state.full_filename(filename);
+        if (engine.state.cache.get(&full_filename).and_then(|x| x.get(section)).is_none()) {
+            return Err(Error::new(ADLIB_ERR_NOT_FOUND, &format!("{}: no section {}", full_filename, section)));
+        }
+
+        engine.state.queue(QueueParams {
+            filename,
+            section,
+            oneshot : oneshot != 0,
+            ..Default::default()
+        });
+        Ok(())
+    })
+}
+
+/// dt is in 60ths of a second.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_tick(handle : *mut AdlibEngine, dt : f32) -> c_int {
+    guard(handle, |engine| {
+        if (!dt.is_finite() || dt < 0.0) {
+            return Err(Error::new(ADLIB_ERR_OUT_OF_RANGE, "dt must be a positive number"));
+        }
+        engine.state.tick(dt);
+        Ok(())
+    })
+}
+
+/// Finishes revealing the line, or moves past a finished one. advanced is set to whether
+/// anything happened, and may be null.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `advanced` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_advance(handle : *mut AdlibEngine, advanced : *mut c_int) -> c_int {
+    guard(handle, |engine| {
+        let result = engine.state.advance(MAIN_INSTANCE);
+        if let Some(advanced) = unsafe { advanced.as_mut() } {
+            *advanced = result as c_int;
+        }
+        Ok(())
+    })
+}
+
+/// Whether anything is playing, including text lingering after the end.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `playing` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_is_playing(handle : *mut AdlibEngine, playing : *mut c_int) -> c_int {
+    guard(handle, |engine| {
+        *out_arg(playing, "playing")? = engine.state.main.engine.current_filename().is_some() as c_int;
+        Ok(())
+    })
+}
+
+/// Captures the text on screen as spans and sets count to how many there are, read them with
+/// adlib_span. The spans don't change until the next reset.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `count` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_spans_reset(handle : *mut AdlibEngine, count : *mut usize) -> c_int {
+    guard(handle, |engine| {
+        let count = out_arg(count, "count")?;
+
+        engine.spans.clear();
+        let mut iter = engine.state.main.engine.current_string_iter();
+        while let Some((text, span)) = iter.next() {
+            let mut flags = 0;
+            for annotation in &span.annotations {
+                flags |= match annotation {
+                    Annotation::Jiggly => ADLIB_JIGGLY,
+                    Annotation::Wide => ADLIB_WIDE,
+                };
+            }
+            engine.spans.push((to_c_string(text), flags));
+        }
+
+        *count = engine.spans.len();
+        Ok(())
+    })
+}
+
+/// Text of a captured span, # marks the end of a line. flags are ADLIB_JIGGLY and ADLIB_WIDE
+/// or'd together, and may be null.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `text` and `flags` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_span(handle : *mut AdlibEngine, index : usize, text : *mut *const c_char, flags : *mut u32) -> c_int {
+    guard(handle, |engine| {
+        let text = out_arg(text, "text")?;
+        let (span_text, span_flags) = engine.spans.get(index)
+            .ok_or_else(|| Error::new(ADLIB_ERR_OUT_OF_RANGE, &format!("no span {}", index)))?;
+
+        *text = span_text.as_ptr();
+        if let Some(flags) = unsafe { flags.as_mut() } {
+            *flags = *span_flags;
+        }
+        Ok(())
+    })
+}
+
+/// Pops the oldest event. has_event is set to 0 when there are none, otherwise name and arg
+/// are set as described for DialogueEvent, until the next call.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `has_event`, `name` and `arg` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_next_event(handle : *mut AdlibEngine, has_event : *mut c_int, name : *mut *const c_char, arg : *mut *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let has_event = out_arg(has_event, "has_event")?;
+        let name = out_arg(name, "name")?;
+        let arg = out_arg(arg, "arg")?;
+
+        match engine.state.main.engine.pop_event() {
+            Some(event) => {
+                engine.event_name = to_c_string(event.name());
+                engine.event_arg = to_c_string(&event.arg());
+                *has_event = 1;
+            },
+            None => {
+                engine.event_name = CString::default();
+                engine.event_arg = CString::default();
+                *has_event = 0;
+            },
+        }
+
+        *name = engine.event_name.as_ptr();
+        *arg = engine.event_arg.as_ptr();
+        Ok(())
+    })
+}
+
+/// Empty when nobody is speaking.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `name` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_current_talker(handle : *mut AdlibEngine, name : *mut *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let name = out_arg(name, "name")?;
+        engine.talker = to_c_string(engine.state.main.engine.current_talker().map(|x| &x.name[..]).unwrap_or_default());
+        *name = engine.talker.as_ptr();
+        Ok(())
+    })
+}
+
+/// 0 unless the dialogue is waiting on a choice.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `count` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_choice_count(handle : *mut AdlibEngine, count : *mut usize) -> c_int {
+    guard(handle, |engine| {
+        *out_arg(count, "count")? = engine.state.main.engine.choices().map(|x| x.len()).unwrap_or(0);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `handle` is null or a live engine and `text` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_choice_text(handle : *mut AdlibEngine, index : usize, text : *mut *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let text = out_arg(text, "text")?;
+        let choice = engine.state.main.engine.choices().and_then(|x| x.get(index))
+            .ok_or_else(|| Error::new(ADLIB_ERR_OUT_OF_RANGE, &format!("no choice {}", index)))?;
+
+        engine.choice_text = to_c_string(&choice.text);
+        *text = engine.choice_text.as_ptr();
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `handle` is null or a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_choose(handle : *mut AdlibEngine, index : usize) -> c_int {
+    guard(handle, |engine| {
+        if (index >= engine.state.main.engine.choices().map(|x| x.len()).unwrap_or(0)) {
+            return Err(Error::new(ADLIB_ERR_OUT_OF_RANGE, &format!("no choice {}", index)));
+        }
+        engine.state.choose(MAIN_INSTANCE, index);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `handle` is null or a live engine and `name` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_set_variable_number(handle : *mut AdlibEngine, name : *const c_char, value : f64) -> c_int {
+    guard(handle, |engine| {
+        let name = unsafe { str_arg(name, "name") }?;
+        engine.state.variables.set(name, Value::Number(value));
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `handle` is null or a live engine and `name` and `value` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_set_variable_string(handle : *mut AdlibEngine, name : *const c_char, value : *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let name = unsafe { str_arg(name, "name") }?;
+        let value = unsafe { str_arg(value, "value") }?;
+        engine.state.variables.set(name, Value::Text(value.to_owned()));
+        Ok(())
+    })
+}
+
+/// Unset variables are 0.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine, `name` null or nul-terminated, and `value` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_get_variable_number(handle : *mut AdlibEngine, name : *const c_char, value : *mut f64) -> c_int {
+    guard(handle, |engine| {
+        let name = unsafe { str_arg(name, "name") }?;
+        *out_arg(value, "value")? = engine.state.variables.get_number(name);
+        Ok(())
+    })
+}
+
+/// Numbers come back formatted, unset variables empty.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine, `name` null or nul-terminated, and `value` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_get_variable_string(handle : *mut AdlibEngine, name : *const c_char, value : *mut *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let name = unsafe { str_arg(name, "name") }?;
+        let value = out_arg(value, "value")?;
+        engine.variable = to_c_string(&engine.state.variables.get(name).map(|x| x.to_string()).unwrap_or_default());
+        *value = engine.variable.as_ptr();
+        Ok(())
+    })
+}
+
+/// Times a section has started, or with line non-null how many times that line has. Lines are
+/// their #id: tag or their 1-based number in the section.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine, `filename`, `section` and `line` null or nul-terminated, and `count` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_seen_count(handle : *mut AdlibEngine, filename : *const c_char, section : *const c_char, line : *const c_char, count : *mut u32) -> c_int {
+    guard(handle, |engine| {
+        let filename = unsafe { str_arg(filename, "filename") }?;
+        let section = unsafe { str_arg(section, "section") }?;
+        let line = if (line.is_null()) { None } else { Some(unsafe { str_arg(line, "line") }?) };
+        *out_arg(count, "count")? = engine.state.seen_count(filename, section, line);
+        Ok(())
+    })
+}
+
+/// Lines of a section never shown yet, 0 if there's no such section.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine, `filename` and `section` null or nul-terminated, and `count` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_unseen_line_count(handle : *mut AdlibEngine, filename : *const c_char, section : *const c_char, count : *mut usize) -> c_int {
+    guard(handle, |engine| {
+        let filename = unsafe { str_arg(filename, "filename") }?;
+        let section = unsafe { str_arg(section, "section") }?;
+        *out_arg(count, "count")? = engine.state.unseen_lines(filename, section).len();
+        Ok(())
+    })
+}
+
+/// Seen counts as text for the host to store, eg alongside its saves.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `text` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_export_seen(handle : *mut AdlibEngine, text : *mut *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let text = out_arg(text, "text")?;
+        engine.seen = to_c_string(&engine.state.export_seen());
+        *text = engine.seen.as_ptr();
+        Ok(())
+    })
+}
+
+/// Replaces the seen counts with ones from adlib_export_seen. ADLIB_ERR_PARSE leaves them as
+/// they were.
+///
+/// # Safety
+///
+/// `handle` is null or a live engine and `text` null or nul-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn adlib_import_seen(handle : *mut AdlibEngine, text : *const c_char) -> c_int {
+    guard(handle, |engine| {
+        let text = unsafe { str_arg(text, "text") }?;
+        engine.state.import_seen(text).map_err(|err| Error::new(ADLIB_ERR_PARSE, &err.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(s : &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn string(p : *const c_char) -> String {
+        unsafe { CStr::from_ptr(p) }.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_play()
+    {
+        // Engines stay live until destroyed at the end and the CStrings outlive each call
+        unsafe {
+            let dir = std::env::temp_dir().join("ad_libber_test_capi");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("intro.adlib"), "[talker goose]\n[a]\ngoose | hi (j) yo (/j)\n> ok").unwrap();
+            std::fs::write(dir.join("broken.adlib"), "[a]\n(nope").unwrap();
+
+            let engine = adlib_engine_create();
+            assert_eq!(adlib_set_base_path(engine, c(&(dir.to_string_lossy().into_owned() + "/")).as_ptr()), ADLIB_OK);
+
+            assert_eq!(adlib_load(engine, c("missing").as_ptr()), ADLIB_ERR_NOT_FOUND);
+            assert_eq!(adlib_load(engine, c("broken").as_ptr()), ADLIB_ERR_PARSE);
+            assert!(string(adlib_last_error(engine)).contains("broken.adlib:2:1"));
+            assert_eq!(adlib_queue(engine, c("intro").as_ptr(), c("b").as_ptr(), 0), ADLIB_ERR_NOT_FOUND);
+            assert_eq!(adlib_queue(engine, c("intro").as_ptr(), c("a").as_ptr(), 0), ADLIB_OK);
+
+            for _ in 0..100 {
+                assert_eq!(adlib_tick(engine, 1.0), ADLIB_OK);
+            }
+
+            let mut count = 0;
+            assert_eq!(adlib_spans_reset(engine, &mut count), ADLIB_OK);
+            let spans = (0..count).map(|i| {
+                let mut text = std::ptr::null();
+                let mut flags = 0;
+                assert_eq!(adlib_span(engine, i, &mut text, &mut flags), ADLIB_OK);
+                (string(text), flags)
+            }).collect::<Vec<_>>();
+            assert_eq!(spans, vec![("hi".to_owned(), 0), ("yo".to_owned(), ADLIB_JIGGLY), ("#".to_owned(), 0)]);
+            assert_eq!(adlib_span(engine, count, &mut std::ptr::null(), std::ptr::null_mut()), ADLIB_ERR_OUT_OF_RANGE);
+
+            let mut events = vec![];
+            loop {
+                let (mut has_event, mut name, mut arg) = (0, std::ptr::null(), std::ptr::null());
+                assert_eq!(adlib_next_event(engine, &mut has_event, &mut name, &mut arg), ADLIB_OK);
+                if (has_event == 0) {
+                    break;
+                }
+                events.push(format!("{} {}", string(name), string(arg)));
+            }
+            assert_eq!(events.first().map(|x| &x[..]), Some("speaker_changed goose"));
+
+            let mut talker = std::ptr::null();
+            assert_eq!(adlib_current_talker(engine, &mut talker), ADLIB_OK);
+            assert_eq!(string(talker), "goose");
+
+            let mut choices = 0;
+            assert_eq!(adlib_choice_count(engine, &mut choices), ADLIB_OK);
+            assert_eq!(choices, 1);
+            assert_eq!(adlib_choose(engine, 1), ADLIB_ERR_OUT_OF_RANGE);
+            assert_eq!(adlib_choose(engine, 0), ADLIB_OK);
+
+            let (mut seen, mut unseen, mut exported) = (0, 0, std::ptr::null());
+            assert_eq!(adlib_seen_count(engine, c("intro").as_ptr(), c("a").as_ptr(), std::ptr::null(), &mut seen), ADLIB_OK);
+            assert_eq!(seen, 1);
+            assert_eq!(adlib_unseen_line_count(engine, c("intro").as_ptr(), c("a").as_ptr(), &mut unseen), ADLIB_OK);
+            assert_eq!(unseen, 0);
+            assert_eq!(adlib_export_seen(engine, &mut exported), ADLIB_OK);
+            let exported = c(&string(exported));
+            assert_eq!(adlib_import_seen(engine, c("nope").as_ptr()), ADLIB_ERR_PARSE);
+            assert_eq!(adlib_import_seen(engine, exported.as_ptr()), ADLIB_OK);
+            assert_eq!(adlib_seen_count(engine, c("intro").as_ptr(), c("a").as_ptr(), c("1").as_ptr(), &mut seen), ADLIB_OK);
+            assert_eq!(seen, 1);
+
+            // Bad arguments are errors, not crashes
+            assert_eq!(adlib_tick(std::ptr::null_mut(), 1.0), ADLIB_ERR_NULL);
+            assert_eq!(adlib_tick(engine, f32::NAN), ADLIB_ERR_OUT_OF_RANGE);
+            assert_eq!(adlib_queue(engine, std::ptr::null(), c("a").as_ptr(), 0), ADLIB_ERR_NULL);
+            assert_eq!(adlib_spans_reset(engine, std::ptr::null_mut()), ADLIB_ERR_NULL);
+            assert_eq!(adlib_set_base_path(engine, c"\xff".as_ptr()), ADLIB_ERR_UTF8);
+            assert_eq!(string(adlib_last_error(engine)), "path is not utf-8");
+
+            adlib_engine_destroy(engine);
+            adlib_engine_destroy(std::ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_custom_commands()
+    {
+        unsafe {
+            let dir = std::env::temp_dir().join("ad_libber_test_capi_commands");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("shake.adlib"), "[a]\nhi (shake 3) there").unwrap();
+            std::fs::write(dir.join("camera.adlib"), "[a]\n(camera pan)\nhi").unwrap();
+            std::fs::write(dir.join("camera_again.adlib"), "[a]\n(camera pan)\nhi").unwrap();
+
+            let engine = adlib_engine_create();
+            assert_eq!(adlib_set_base_path(engine, c(&(dir.to_string_lossy().into_owned() + "/")).as_ptr()), ADLIB_OK);
+            assert_eq!(adlib_register_custom_command(engine, c("shake").as_ptr(), 1), ADLIB_OK);
+            assert_eq!(adlib_register_custom_command(engine, std::ptr::null(), 1), ADLIB_ERR_NULL);
+            assert_eq!(adlib_queue(engine, c("shake").as_ptr(), c("a").as_ptr(), 0), ADLIB_OK);
+
+            // Held on the shake until the host resumes
+            let mut resumed = 0;
+            for _ in 0..100 {
+                assert_eq!(adlib_tick(engine, 1.0), ADLIB_OK);
+            }
+            assert_eq!(adlib_resume_custom_command(engine, &mut resumed), ADLIB_OK);
+            assert_eq!(resumed, 1);
+            assert_eq!(adlib_resume_custom_command(engine, &mut resumed), ADLIB_OK);
+            assert_eq!(resumed, 0);
+            assert_eq!(adlib_resume_custom_command(engine, std::ptr::null_mut()), ADLIB_OK);
+
+            // Unregistered commands are errors unless passed through
+            assert_eq!(adlib_load(engine, c("camera").as_ptr()), ADLIB_ERR_PARSE);
+            assert_eq!(adlib_set_custom_command_pass_through(engine, 1), ADLIB_OK);
+            assert_eq!(adlib_load(engine, c("camera_again").as_ptr()), ADLIB_OK);
+
+            adlib_engine_destroy(engine);
+        }
+    }
+
+    #[test]
+    fn test_panic_poisons()
+    {
+        unsafe {
+            let engine = adlib_engine_create();
+            assert_eq!(guard(engine, |_| panic!("oh no")), ADLIB_ERR_PANIC);
+            assert_eq!(adlib_tick(engine, 1.0), ADLIB_ERR_PANIC);
+            adlib_engine_destroy(engine);
+        }
+    }
+
+    // A C type from the header as it's spelled in Rust, eg "const char **" as "*mut *const c_char".
+    fn rust_type(c_type : &str) -> String {
+        let depth = c_type.matches('*').count();
+        let base = c_type.trim_end_matches(['*', ' ']);
+        let (is_const, base) = match base.strip_prefix("const ") {
+            Some(base) => (true, base),
+            None => (false, base),
+        };
+
+        let mut rust = match base {
+            "void" => "",
+            "char" => "c_char",
+            "int" => "c_int",
+            "size_t" => "usize",
+            "uint32_t" => "u32",
+            "float" => "f32",
+            "double" => "f64",
+            "AdlibEngine" => "AdlibEngine",
+            _ => panic!("no Rust type for {}", c_type),
+        }.to_owned();
+
+        for i in 0..depth {
+            let qualifier = if (i == 0 && is_const) { "*const " } else { "*mut " };
+            rust = qualifier.to_owned() + &rust;
+        }
+        rust
+    }
+
+    // Name to return and parameter types of every function the header declares.
+    fn header_signatures(header : &str) -> Vec<(String, String, Vec<String>)> {
+        header.lines().filter_map(|line| {
+            let (head, params) = line.strip_suffix(");")?.split_once('(')?;
+            let name_start = head.find("adlib_")?;
+            let params = params.split(", ")
+                .filter(|x| *x != "void")
+                .map(|param| rust_type(&param[..param.rfind([' ', '*']).unwrap() + 1]))
+                .collect();
+            Some((head[name_start..].to_owned(), rust_type(&head[..name_start]), params))
+        }).collect()
+    }
+
+    // Same from the exported functions here, which have to be no_mangle to be found by name.
+    // Unsafe or not doesn't matter to C.
+    fn exported_signatures(source : &str) -> Vec<(String, String, Vec<String>)> {
+        source.split("extern \"C\" fn ").skip(1).map(|x| {
+            let (name, rest) = x.split_once('(').unwrap();
+            let (params, rest) = rest.split_once(')').unwrap();
+            let ret = rest.split('{').next().unwrap().trim().trim_start_matches("-> ").to_owned();
+            let params = params.split(", ").filter(|x| !x.is_empty()).map(|x| x.split_once(" : ").unwrap().1.to_owned()).collect();
+            (name.to_owned(), ret, params)
+        }).collect()
+    }
+
+    #[test]
+    fn test_header_matches()
+    {
+        let header = include_str!("../include/ad_libber.h");
+        let source = include_str!("capi.rs");
+
+        let mut declared = header_signatures(header);
+        let mut exported = exported_signatures(source);
+        declared.sort();
+        exported.sort();
+        assert!(exported.len() > 10);
+        assert_eq!(declared, exported);
+        let no_mangle = source.matches("#[no_mangle]\npub extern \"C\" fn ").count() + source.matches("#[no_mangle]\npub unsafe extern \"C\" fn ").count();
+        assert_eq!(no_mangle, exported.len());
+
+        for (name, value) in [("ADLIB_OK", ADLIB_OK), ("ADLIB_ERR_NULL", ADLIB_ERR_NULL), ("ADLIB_ERR_UTF8", ADLIB_ERR_UTF8),
+            ("ADLIB_ERR_NOT_FOUND", ADLIB_ERR_NOT_FOUND), ("ADLIB_ERR_PARSE", ADLIB_ERR_PARSE),
+            ("ADLIB_ERR_OUT_OF_RANGE", ADLIB_ERR_OUT_OF_RANGE), ("ADLIB_ERR_PANIC", ADLIB_ERR_PANIC)] {
+            assert!(header.contains(&format!("#define {} ({})", name, value)), "{} doesn't match ad_libber.h", name);
+        }
+        assert!(header.contains(&format!("#define ADLIB_JIGGLY {}u", ADLIB_JIGGLY)));
+        assert!(header.contains(&format!("#define ADLIB_WIDE {}u", ADLIB_WIDE)));
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+// Names the game handles itself, passed through as Command::Custom. Only registered names
+// are accepted so typos are still caught when parsing, unless the game opts into passing
+// every unknown command through.
+#[derive(Default, Clone, Debug)]
+pub struct CustomCommands
+{
+    // Lowercased name to whether the cursor waits for the host before continuing
+    names : HashMap<String, bool>,
+    pass_through : bool,
+}
+
+impl CustomCommands {
+    // Any well formed unknown command is accepted as a non-blocking custom command.
+    pub fn pass_through() -> Self {
+        Self {
+            names : HashMap::new(),
+            pass_through : true,
+        }
+    }
+
+    // The --commands lists the tools take, one name per line, followed by "blocking" for
+    // commands the host resumes. Blank lines and lines starting with # are skipped.
+    pub fn parse_list(contents : &str) -> Self {
+        let mut custom_commands = Self::default();
+        for line in contents.lines().map(|x| x.trim()).filter(|x| !x.is_empty() && !x.starts_with('#')) {
+            match line.split_once(char::is_whitespace) {
+                Some((name, flag)) if unicase::eq_ascii(flag.trim(), "blocking") => custom_commands.register(name, true),
+                _ => custom_commands.register(line, false),
+            }
+        }
+        custom_commands
+    }
+
+    pub fn set_pass_through(&mut self, pass_through : bool) {
+        self.pass_through = pass_through;
+    }
+
+    pub fn register(&mut self, name : &str, blocking : bool) {
+        self.names.insert(name.to_ascii_lowercase(), blocking);
+    }
+
+    pub fn clear(&mut self) {
+        self.names.clear();
+    }
+
+    pub fn is_strict(&self) -> bool {
+        !self.pass_through
+    }
+
+    // None if the name isn't allowed, otherwise whether it blocks. Registered names keep
+    // their blocking flag when passing through.
+    pub fn lookup(&self, name : &str) -> Option<bool> {
+        if (!is_valid_name(name)) {
+            None
+        }
+        else if let Some(blocking) = self.names.get(&name.to_ascii_lowercase()) {
+            Some(*blocking)
+        }
+        else if (self.pass_through) {
+            Some(false)
+        }
+        else {
+            None
+        }
+    }
+}
+
+fn is_valid_name(name : &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() => chars.all(|c| c.is_alphanumeric() || c == '_' || c == '-'),
+        _ => false,
+    }
+}
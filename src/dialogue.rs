@@ -1,7 +1,64 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::custom_commands::CustomCommands;
+use crate::event::DialogueEvent;
+use crate::parse_error::{ParseError, ParseErrorKind};
+use crate::random::{VariantKey, VariantMode, Variants};
+use crate::seen::SeenKey;
 use crate::talker::Talker;
+use crate::variables::{Assignment, Condition, Value, Variables};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionTarget
+{
+    // Name of another .adlib file next to the current one, without the extension.
+    pub file : Option<String>,
+    pub section : String,
+}
+
+impl SectionTarget {
+    pub fn parse(s : &str) -> Option<Self> {
+        if let Some((file, section)) = s.split_once(':') {
+            let file = file.trim();
+            let section = section.trim();
+            if (file.is_empty() || section.is_empty()) {
+                return None;
+            }
+
+            Some(Self {
+                file : Some(file.to_owned()),
+                section : section.to_owned(),
+            })
+        }
+        else {
+            let section = s.trim();
+            if (section.is_empty()) {
+                return None;
+            }
+
+            Some(Self {
+                file : None,
+                section : section.to_owned(),
+            })
+        }
+    }
+
+    // Full filename of the target, given the full filename of the file referencing it.
+    pub fn resolve_filename(&self, current_filename : &str) -> String {
+        match &self.file {
+            Some(file) => {
+                std::path::Path::new(current_filename)
+                    .with_file_name(file.to_owned() + ".adlib")
+                    .to_string_lossy()
+                    .into_owned()
+            },
+            None => current_filename.to_owned(),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum Command {
@@ -10,6 +67,28 @@ pub enum Command {
     Speaker(String),
     Wait(u32),
     Clear,
+    Goto(SectionTarget),
+    Call(SectionTarget),
+    Return,
+    Set(Assignment),
+    If(Condition),
+    Else,
+    EndIf,
+    Random(VariantMode),
+    // Starts the next alternative in a random block, with its weight
+    Variant(u32),
+    EndRandom,
+    // Voice over clip for the host to play
+    Vo(String),
+    // Anything else, handled by the game. Blocking ones wait for the host to resume.
+    Custom { name : String, args : Vec<String>, blocking : bool },
+}
+
+// Commands that nest like brackets, with a separator between branches.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Block {
+    If,
+    Random,
 }
 
 fn is_command(input : &str, name : &str) -> bool {
@@ -28,12 +107,43 @@ impl Command {
         }
     }
 
-    pub fn parse(s : &str) -> Option<Self> {
+    fn opens_block(&self) -> Option<Block> {
+        match self {
+            Command::If(_) => Some(Block::If),
+            Command::Random(_) => Some(Block::Random),
+            _ => None,
+        }
+    }
+
+    fn separates_block(&self) -> Option<Block> {
+        match self {
+            Command::Else => Some(Block::If),
+            Command::Variant(_) => Some(Block::Random),
+            _ => None,
+        }
+    }
+
+    fn closes_block(&self) -> Option<Block> {
+        match self {
+            Command::EndIf => Some(Block::If),
+            Command::EndRandom => Some(Block::Random),
+            _ => None,
+        }
+    }
+
+    pub fn section_target(&self) -> Option<&SectionTarget> {
+        match self {
+            Command::Goto(target) | Command::Call(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn parse(s : &str, custom_commands : &CustomCommands) -> Result<Self, ParseErrorKind> {
         let mut splits = s.split_ascii_whitespace();
-        let command = splits.next()?;
+        let command = splits.next().ok_or(ParseErrorKind::UnknownCommand)?;
 
         if (is_command(command, "clear")) {
-            Some(Self::Clear)
+            Ok(Self::Clear)
         }
         else if (is_command(command, "wait")) {
 
@@ -49,32 +159,83 @@ impl Command {
                     mult = 60.0;
                 }
 
-                let f = mult * f32::from_str(parse_t).expect(&format!("Failed to parse duration from wait command {}", parse_t));
+                let f = mult * f32::from_str(parse_t).map_err(|_| ParseErrorKind::BadDuration)?;
+                if (!f.is_finite() || f < 0.0) {
+                    return Err(ParseErrorKind::BadDuration);
+                }
+
                 f.round() as u32
             }
             else {
                 60
             };
 
-            Some(Self::Wait(dur))
+            Ok(Self::Wait(dur))
+        }
+        else if (is_command(command, "goto")) {
+            let target = splits.next().ok_or(ParseErrorKind::MissingArgument)?;
+            Ok(Self::Goto(SectionTarget::parse(target).ok_or(ParseErrorKind::MissingArgument)?))
+        }
+        else if (unicase::eq_ascii(command, "call")) {
+            // No single letter form, 'c' is clear
+            let target = splits.next().ok_or(ParseErrorKind::MissingArgument)?;
+            Ok(Self::Call(SectionTarget::parse(target).ok_or(ParseErrorKind::MissingArgument)?))
+        }
+        else if (is_command(command, "return")) {
+            Ok(Self::Return)
+        }
+        else if (unicase::eq_ascii(command, "set")) {
+            Ok(Self::Set(Assignment::parse(&splits.collect::<Vec<_>>())?))
+        }
+        else if (unicase::eq_ascii(command, "if")) {
+            Ok(Self::If(Condition::parse(&splits.collect::<Vec<_>>())?))
+        }
+        else if (unicase::eq_ascii(command, "else")) {
+            Ok(Self::Else)
+        }
+        else if (unicase::eq_ascii(command, "endif")) {
+            Ok(Self::EndIf)
+        }
+        else if (unicase::eq_ascii(command, "random")) {
+            Ok(Self::Random(VariantMode::parse(splits.next())?))
+        }
+        else if (unicase::eq_ascii(command, "variant")) {
+            let weight = match splits.next() {
+                Some(x) => x.parse::<u32>().ok().filter(|x| *x > 0).ok_or(ParseErrorKind::BadExpression)?,
+                None => 1,
+            };
+            Ok(Self::Variant(weight))
+        }
+        else if (unicase::eq_ascii(command, "endrandom")) {
+            Ok(Self::EndRandom)
+        }
+        else if (unicase::eq_ascii(command, "vo")) {
+            Ok(Self::Vo(splits.next().ok_or(ParseErrorKind::MissingArgument)?.to_owned()))
         }
         else if (is_command(command, "speaker")) {
-            Some(Self::Speaker(splits.next().expect("Could not parse speaker").to_owned()))
+            Ok(Self::Speaker(splits.next().ok_or(ParseErrorKind::MissingArgument)?.to_owned()))
         }
         else if (is_command(command, "jiggle")) {
-            Some(Self::AnnotationStart(Annotation::Jiggly))
+            Ok(Self::AnnotationStart(Annotation::Jiggly))
         }
         else if (is_end_command(command, "jiggle")) {
-            Some(Self::AnnotationEnd(Annotation::Jiggly))
+            Ok(Self::AnnotationEnd(Annotation::Jiggly))
         }
         else if (is_command(command, "wide")) {
-            Some(Self::AnnotationStart(Annotation::Wide))
+            Ok(Self::AnnotationStart(Annotation::Wide))
         }
         else if (is_end_command(command, "wide")) {
-            Some(Self::AnnotationEnd(Annotation::Wide))
+            Ok(Self::AnnotationEnd(Annotation::Wide))
+        }
+        else if let Some(blocking) = custom_commands.lookup(command) {
+            Ok(Self::Custom {
+                name : command.to_owned(),
+                args : splits.map(|x| x.to_owned()).collect(),
+                blocking,
+            })
         }
         else {
-            None
+            Err(ParseErrorKind::UnknownCommand)
         }
     }
 }
@@ -82,8 +243,111 @@ impl Command {
 #[derive(Clone, Debug)]
 pub struct TextChunk {
     text: String,
-    #[allow(unused)]
-    talker_id : Option<u32>,
+    // Index into Dialogue::talkers
+    pub talker_id : Option<u32>,
+    // From a trailing #id: tag, shared by every chunk of the line
+    pub line_id : Option<String>,
+}
+
+impl TextChunk {
+    // Length in grapheme clusters, which is what gets revealed one per tick.
+    pub fn grapheme_count(&self) -> usize {
+        grapheme_len(&self.text)
+    }
+
+    // The first n grapheme clusters.
+    pub fn revealed(&self, n : usize) -> &str {
+        &self.text[..grapheme_byte_offset(&self.text, n)]
+    }
+}
+
+pub(crate) fn fnv1a(bytes : &[u8]) -> u64 {
+    let mut hash : u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// The chunks written out field by field. Debug output would change whenever a type gained a
+// field or was renamed, which would restart every saved conversation.
+fn canonical_chunks(chunks : &[Chunk]) -> String {
+    let optional = |x : Option<String>| x.unwrap_or_else(|| "-".to_owned());
+    let target = |x : &SectionTarget| match &x.file {
+        Some(file) => format!("{}:{}", file, x.section),
+        None => x.section.clone(),
+    };
+    let value = |x : &Value| match x {
+        Value::Number(x) => format!("n{}", x),
+        Value::Text(x) => format!("s{}", x),
+    };
+
+    let mut out = String::new();
+    for chunk in chunks {
+        let fields = match chunk {
+            Chunk::Text(text) => vec!["text".to_owned(), text.text.clone(), optional(text.talker_id.map(|x| x.to_string())), optional(text.line_id.clone())],
+            Chunk::Newline => vec!["newline".to_owned()],
+            Chunk::Choice(options) => {
+                let mut fields = vec!["choice".to_owned()];
+                for option in options {
+                    fields.push(option.text.clone());
+                    fields.push(optional(option.target.as_ref().map(target)));
+                }
+                fields
+            },
+            Chunk::Command(command) => match command {
+                Command::AnnotationStart(annotation) => vec!["start".to_owned(), annotation.name().to_owned()],
+                Command::AnnotationEnd(annotation) => vec!["end".to_owned(), annotation.name().to_owned()],
+                Command::Speaker(name) => vec!["speaker".to_owned(), name.clone()],
+                Command::Wait(frames) => vec!["wait".to_owned(), frames.to_string()],
+                Command::Clear => vec!["clear".to_owned()],
+                Command::Goto(x) => vec!["goto".to_owned(), target(x)],
+                Command::Call(x) => vec!["call".to_owned(), target(x)],
+                Command::Return => vec!["return".to_owned()],
+                Command::Set(assignment) => vec!["set".to_owned(), assignment.name.clone(), assignment.op.symbol().to_owned(), value(&assignment.value)],
+                Command::If(condition) => vec![
+                    "if".to_owned(),
+                    condition.name.clone(),
+                    condition.negate.to_string(),
+                    optional(condition.seen.as_ref().map(|x| format!("{}#{}", target(&x.target), optional(x.line.clone())))),
+                    optional(condition.compare.as_ref().map(|(op, x)| format!("{} {}", op.symbol(), value(x)))),
+                ],
+                Command::Else => vec!["else".to_owned()],
+                Command::EndIf => vec!["endif".to_owned()],
+                Command::Random(mode) => vec!["random".to_owned(), mode.name().to_owned()],
+                Command::Variant(weight) => vec!["variant".to_owned(), weight.to_string()],
+                Command::EndRandom => vec!["endrandom".to_owned()],
+                Command::Vo(clip) => vec!["vo".to_owned(), clip.clone()],
+                Command::Custom { name, args, blocking } => {
+                    let mut fields = vec!["custom".to_owned(), name.clone(), blocking.to_string()];
+                    fields.extend(args.iter().cloned());
+                    fields
+                },
+            },
+        };
+
+        // Unit and record separators, which source text doesn't have
+        out.push_str(&fields.join("\x1f"));
+        out.push('\x1e');
+    }
+    out
+}
+
+fn grapheme_len(s : &str) -> usize {
+    s.graphemes(true).count()
+}
+
+// Byte offset of the nth grapheme cluster, or the length if there are fewer.
+fn grapheme_byte_offset(s : &str, n : usize) -> usize {
+    s.grapheme_indices(true).nth(n).map(|(i, _)| i).unwrap_or(s.len())
+}
+
+#[derive(Clone, Debug)]
+pub struct ChoiceOption {
+    pub text : String,
+    // None continues after the choice.
+    pub target : Option<SectionTarget>,
 }
 
 #[derive(Clone, Debug)]
@@ -91,14 +355,16 @@ pub enum Chunk {
     Text(TextChunk),
     Newline,
     Command(Command),
+    Choice(Vec<ChoiceOption>),
 }
 
 impl Chunk {
     pub fn tick_len(&self) -> u32 {
         match self {
-            Chunk::Text(s) => s.text.len() as u32,
+            Chunk::Text(s) => s.grapheme_count() as u32,
             Chunk::Newline => 1,
             Chunk::Command(c) => c.tick_len(),
+            Chunk::Choice(_) => 0,
         }
     }
 }
@@ -108,21 +374,86 @@ pub struct Dialogue
 {
     pub name : String,
     pub filename : String,
+    // What seen counts file it under. The file it translates for a translation, so changing
+    // language keeps what has been seen, otherwise filename. Set by DialogueCache::preload.
+    pub base_filename : String,
     pub chunks : Vec<Chunk>,
+    // Talkers declared in the file before this section
+    pub talkers : Vec<Talker>,
 }
 
 impl Dialogue {
+    pub fn talker(&self, talker_id : u32) -> Option<&Talker> {
+        self.talkers.get(talker_id as usize)
+    }
+
+    pub fn talker_by_name(&self, name : &str) -> Option<&Talker> {
+        self.talkers.iter().find(|x| unicase::eq_ascii(&x.name[..], name))
+    }
+
+    // Same section of the same file.
+    pub fn name_eq(&self, other : &Dialogue) -> bool {
+        unicase::eq_ascii(&self.name, &other.name) && unicase::eq_ascii(&self.filename, &other.filename)
+    }
+
+    // What the line starting at chunk i is counted as in SeenLines, its #id: tag or else its
+    // 1-based number in the section.
+    fn line_key(&self, i : usize) -> String {
+        match &self.chunks[i] {
+            Chunk::Text(TextChunk { line_id : Some(id), .. }) => id.clone(),
+            _ => (self.chunks[..i].iter().filter(|x| matches!(x, Chunk::Newline)).count() + 1).to_string(),
+        }
+    }
+
+    // Every line of the section as line_key has it, in order.
+    pub fn line_keys(&self) -> Vec<String> {
+        let mut keys = vec![];
+        let mut in_line = false;
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            match chunk {
+                Chunk::Text(_) if !in_line => {
+                    in_line = true;
+                    keys.push(self.line_key(i));
+                },
+                Chunk::Newline => in_line = false,
+                _ => {},
+            }
+        }
+        keys
+    }
+
+    // FNV-1a of the parsed chunks, changes whenever the section's contents do. Saves keep it,
+    // so it mustn't change for anything else, see canonical_chunks.
+    pub fn fingerprint(&self) -> u64 {
+        fnv1a(canonical_chunks(&self.chunks).as_bytes())
+    }
+
     pub fn from_error(err : &str) -> Self {
         Self {
             name : "error".to_owned(),
             filename : "error".to_owned(),
+            base_filename : "error".to_owned(),
             chunks : vec![
-                Chunk::Text(TextChunk{ text: err.to_owned(), talker_id: None}),
+                Chunk::Text(TextChunk{ text: err.to_owned(), talker_id: None, line_id: None}),
             ],
+            talkers : vec![],
         }
     }
-    fn name_eq(&self, other: &Self) -> bool {
-        unicase::eq_ascii(&self.name, &other.name) && unicase::eq_ascii(&self.filename, &other.filename)
+
+    pub fn from_parse_errors(errors : &[ParseError]) -> Self {
+        let mut chunks = vec![];
+        for error in errors {
+            chunks.push(Chunk::Text(TextChunk{ text: error.to_string(), talker_id: None, line_id: None}));
+            chunks.push(Chunk::Newline);
+        }
+
+        Self {
+            name : "error".to_owned(),
+            filename : "error".to_owned(),
+            base_filename : "error".to_owned(),
+            chunks,
+            talkers : vec![],
+        }
     }
 
     /*
@@ -145,10 +476,136 @@ pub struct DialogueFile
 {
     pub talkers : Vec<Talker>,
     pub sections : Vec<Dialogue>,
+    // References into other files, checked by DialogueCache when loading.
+    pub external_refs : Vec<SectionRef>,
+}
+
+// Reference to a section by name from a goto, call or choice.
+#[derive(Clone, Debug)]
+pub struct SectionRef
+{
+    pub filename : String,
+    pub line : usize,
+    pub column : usize,
+    pub target : SectionTarget,
+}
+
+impl SectionRef {
+    fn unknown_section_error(&self) -> ParseError {
+        let text = match &self.target.file {
+            Some(file) => format!("{}:{}", file, self.target.section),
+            None => self.target.section.clone(),
+        };
+
+        ParseError::new(&self.filename, self.line, self.column, &text, ParseErrorKind::UnknownSection)
+    }
+}
+
+pub(crate) fn column_of(line : &str, sub : &str) -> usize {
+    // sub must be a slice of line
+    sub.as_ptr() as usize - line.as_ptr() as usize + 1
+}
+
+// State shared by everything parsing one file.
+struct ParseContext<'c>
+{
+    filename : &'c str,
+    custom_commands : &'c CustomCommands,
+    errors : Vec<ParseError>,
+    section_refs : Vec<SectionRef>,
+    line_ids : HashSet<String>,
+}
+
+impl<'c> ParseContext<'c> {
+    fn error(&mut self, line_number : usize, column : usize, text : &str, kind : ParseErrorKind) {
+        self.errors.push(ParseError::new(self.filename, line_number, column, text, kind));
+    }
+
+    // The id in a #id: tag, None if it's empty or already used in this file.
+    fn line_id(&mut self, line_number : usize, column : usize, tag : &str) -> Option<String> {
+        let id = &tag[LINE_ID_PREFIX.len()..];
+        if (id.is_empty()) {
+            self.error(line_number, column, tag, ParseErrorKind::MissingArgument);
+            None
+        }
+        else if (!self.line_ids.insert(id.to_owned())) {
+            self.error(line_number, column, tag, ParseErrorKind::DuplicateLineId);
+            None
+        }
+        else {
+            Some(id.to_owned())
+        }
+    }
+
+    fn section_ref(&mut self, line_number : usize, column : usize, target : &SectionTarget) {
+        self.section_refs.push(SectionRef {
+            filename : self.filename.to_owned(),
+            line : line_number,
+            column,
+            target : target.clone(),
+        });
+    }
+}
+
+// Splits the text of a line into words and whole "(command args)" tokens.
+pub(crate) fn tokenize_line(line : &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let len = if (rest.starts_with('(')) {
+            // Up to and including the close, or the whole rest if unterminated
+            rest.find(')').map(|x| x + 1).unwrap_or(rest.len())
+        }
+        else {
+            rest.find(|c : char| c.is_ascii_whitespace()).unwrap_or(rest.len())
+        };
+
+        tokens.push(&rest[..len]);
+        rest = rest[len..].trim_start();
+    }
+
+    tokens
+}
+
+// A line that is only a command, as opposed to text with commands in it.
+// TODO this is a hack, collapse this case
+pub(crate) fn is_command_line(line : &str) -> bool {
+    line.starts_with("(") && line.ends_with(")") && !line.contains("/")
+}
+
+// "talker | text" into the talker name and the text.
+pub(crate) fn split_talker(line : &str) -> (Option<&str>, &str) {
+    match line.split_once("|") {
+        Some((talker_name, rest)) => (Some(talker_name.trim()), rest.trim()),
+        None => (None, line),
+    }
+}
+
+pub(crate) const LINE_ID_PREFIX : &str = "#id:";
+
+// "text #id:xxxx" into the text and the tag, the tag has to be the last thing on the line.
+pub(crate) fn split_line_id(line : &str) -> (&str, Option<&str>) {
+    let line = line.trim_end();
+    let tag_start = line.rfind(|c : char| c.is_ascii_whitespace()).map(|x| x + 1).unwrap_or(0);
+    if (line[tag_start..].starts_with(LINE_ID_PREFIX)) {
+        (line[..tag_start].trim_end(), Some(&line[tag_start..]))
+    }
+    else {
+        (line, None)
+    }
+}
+
+// "> text -> target" or "> text" into the option text and the target if there is one.
+pub(crate) fn split_choice_option(line : &str) -> (&str, Option<&str>) {
+    let body = line[1..].trim();
+    match body.rsplit_once("->") {
+        Some((text, target)) => (text.trim(), Some(target.trim())),
+        None => (body, None),
+    }
 }
 
 impl<'a> DialogueFile {
-    fn parse_talker(name : &str, lines : &[&'a str], i : &mut usize) -> Talker {
+    fn parse_talker(ctx : &mut ParseContext, name : &str, lines : &[&'a str], i : &mut usize) -> Talker {
         let mut talker = Talker {
             name : name.to_owned(),
             ..Default::default()
@@ -165,15 +622,20 @@ impl<'a> DialogueFile {
                 break;
             }
 
-            if let Some((field, value)) = line.split_once('=') {
+            if let Some((field_raw, value_raw)) = line.split_once('=') {
+                let field = field_raw.trim();
+                let value = value_raw.trim();
                 if unicase::eq_ascii(field, "sprite") {
-                    talker.sprite = value.trim().to_owned();
+                    talker.sprite = value.to_owned();
                 }
                 else if unicase::eq_ascii(field, "sound") {
-                    talker.sound = value.trim().to_owned();
+                    talker.sound = value.to_owned();
                 }
                 else if unicase::eq_ascii(field, "rate") {
-                    talker.rate = Some(value.parse::<f32>().expect("Could not parse rate"));
+                    match value.parse::<f32>() {
+                        Ok(rate) => talker.rate = Some(rate),
+                        Err(_) => ctx.error(*i + 1, column_of(line, value), value, ParseErrorKind::BadRate),
+                    }
                 }
             }
 
@@ -183,8 +645,91 @@ impl<'a> DialogueFile {
         talker
     }
 
-    fn parse_section(talkers : &[Talker], filename : &str, name : &str, lines : &[&'a str], i : &mut usize) -> Dialogue {
-        let mut section = Dialogue { name : name.to_owned(), filename : filename.to_owned(), chunks: Default::default() };
+    fn unbalanced_error_kind(command : &Command) -> ParseErrorKind {
+        match command {
+            Command::Random(_) | Command::Variant(_) | Command::EndRandom => ParseErrorKind::UnbalancedRandom,
+            _ => ParseErrorKind::UnbalancedIf,
+        }
+    }
+
+    // Parses the command in token, which includes the brackets, and checks its targets and nesting.
+    fn parse_command(ctx : &mut ParseContext, token : &str, line : &str, line_number : usize, open_blocks : &mut Vec<(Block, usize, bool)>) -> Option<Command> {
+        let column = column_of(line, token);
+
+        if (token.len() < 2 || !token.ends_with(")")) {
+            ctx.error(line_number, column, token, ParseErrorKind::UnterminatedCommand);
+            return None;
+        }
+
+        let command = match Command::parse(&token[1..(token.len() - 1)], ctx.custom_commands) {
+            Ok(command) => command,
+            Err(kind) => {
+                ctx.error(line_number, column, token, kind);
+                return None;
+            },
+        };
+
+        if let Some(target) = command.section_target() {
+            ctx.section_ref(line_number, column, target);
+        }
+
+        let mut balanced = true;
+        if let Some(block) = command.opens_block() {
+            open_blocks.push((block, line_number, false));
+        }
+        else if let Some(block) = command.separates_block() {
+            balanced = match open_blocks.last_mut() {
+                // Only one else per if, any number of variants
+                Some((open, _, seen_separator)) if *open == block && !(block == Block::If && *seen_separator) => {
+                    *seen_separator = true;
+                    true
+                },
+                _ => false,
+            };
+        }
+        else if let Some(block) = command.closes_block() {
+            balanced = match open_blocks.last() {
+                Some((open, _, _)) if *open == block => {
+                    open_blocks.pop();
+                    true
+                },
+                _ => false,
+            };
+        }
+
+        if (!balanced) {
+            ctx.error(line_number, column, token, Self::unbalanced_error_kind(&command));
+        }
+
+        Some(command)
+    }
+
+    fn parse_choice_option(ctx : &mut ParseContext, line : &'a str, line_number : usize) -> ChoiceOption {
+        let (text, target_raw) = split_choice_option(line);
+        let target = target_raw.and_then(|target_raw| {
+            let target = SectionTarget::parse(target_raw);
+            match &target {
+                Some(target) => {
+                    ctx.section_ref(line_number, column_of(line, target_raw), target);
+                },
+                None => {
+                    ctx.error(line_number, column_of(line, target_raw), target_raw, ParseErrorKind::MissingArgument);
+                },
+            }
+            target
+        });
+
+        ChoiceOption {
+            text : text.to_owned(),
+            target,
+        }
+    }
+
+    fn parse_section(ctx : &mut ParseContext, talkers : &[Talker], name : &str, lines : &[&'a str], i : &mut usize) -> Dialogue {
+        let mut section = Dialogue { name : name.to_owned(), filename : ctx.filename.to_owned(), base_filename : ctx.filename.to_owned(), chunks: Default::default(), talkers : talkers.to_vec() };
+
+        // Line of each open if or random block and whether we have seen an else
+        let mut open_blocks : Vec<(Block, usize, bool)> = vec![];
 
         while *i < lines.len() {
             let line = lines[*i];
@@ -196,44 +741,52 @@ impl<'a> DialogueFile {
             if (line.starts_with("[")) {
                 break;
             }
-            // TODO this line is a hack, collapse this case
-            else if (line.starts_with("(") && line.ends_with(")") && !line.contains("/")) {
-                let command = Command::parse(&line[1..(line.len() - 1)]).expect(&format!("Could not parse command {}", line));
-                eprintln!("parsed command: {:?}", command);
-                section.chunks.push(Chunk::Command(command));
+            else if (line.starts_with(">")) {
+                let option = Self::parse_choice_option(ctx, line, *i + 1);
+                if let Some(Chunk::Choice(options)) = section.chunks.last_mut() {
+                    options.push(option);
+                }
+                else {
+                    section.chunks.push(Chunk::Choice(vec![option]));
+                }
             }
-            else {
-                let mut line_to_parse = line;
-                let mut talker_id : Option<u32> = None;
-
-                if let Some((talker_name_raw, rest)) = line.split_once("|") {
-                    let talker_name = talker_name_raw.trim();
-                    talker_id = talkers.iter().enumerate().filter(|(_, x)| unicase::eq_ascii(&x.name[..], talker_name)).map(|(i, _)| i as u32).next();
-                    line_to_parse = rest.trim();
+            else if (is_command_line(line)) {
+                if let Some(command) = Self::parse_command(ctx, line, line, *i + 1, &mut open_blocks) {
+                    section.chunks.push(Chunk::Command(command));
                 }
+            }
+            else {
+                let (talker_name, line_to_parse) = split_talker(line);
+                let (line_to_parse, line_id_tag) = split_line_id(line_to_parse);
+                let line_id = line_id_tag.and_then(|tag| ctx.line_id(*i + 1, column_of(line, tag), tag));
+                let talker_id = talker_name.and_then(|talker_name| {
+                    talkers.iter().enumerate().filter(|(_, x)| unicase::eq_ascii(&x.name[..], talker_name)).map(|(i, _)| i as u32).next()
+                });
 
-                let mut splits = line_to_parse.split_ascii_whitespace();
                 let mut cur_str = String::new();
-                while let Some(split) = splits.next() {
-                    if (split.starts_with("(")) {
-                        let command = Command::parse(&split[1..(split.len() - 1)]).expect(&format!("Could not parse command in line '{}' '{}'", line, split));
-                        section.chunks.push(Chunk::Text(TextChunk {
-                            text: cur_str,
-                            talker_id,
-                        }));
-                        section.chunks.push(Chunk::Command(command));
-                        cur_str = String::new();
+                for token in tokenize_line(line_to_parse) {
+                    if (token.starts_with("(")) {
+                        if let Some(command) = Self::parse_command(ctx, token, line, *i + 1, &mut open_blocks) {
+                            section.chunks.push(Chunk::Text(TextChunk {
+                                text: cur_str,
+                                talker_id,
+                                line_id : line_id.clone(),
+                            }));
+                            section.chunks.push(Chunk::Command(command));
+                            cur_str = String::new();
+                        }
                     }
                     else {
-                        if (cur_str.len() > 0) {
+                        if (!cur_str.is_empty()) {
                             cur_str.push(' ');
                         }
-                        cur_str.push_str(split);
+                        cur_str.push_str(token);
                     }
                 }
                 section.chunks.push(Chunk::Text(TextChunk {
                     text: cur_str.to_owned(),
                     talker_id,
+                    line_id,
                 }));
                 section.chunks.push(Chunk::Newline);
             }
@@ -241,6 +794,14 @@ impl<'a> DialogueFile {
             *i += 1;
         }
 
+        for (block, line_number, _) in open_blocks {
+            let kind = match block {
+                Block::If => ParseErrorKind::UnbalancedIf,
+                Block::Random => ParseErrorKind::UnbalancedRandom,
+            };
+            ctx.error(line_number, 1, lines[line_number - 1], kind);
+        }
+
         section
     }
 }
@@ -248,15 +809,29 @@ impl<'a> DialogueFile {
 impl DialogueFile
 {
 
-    pub fn parse(p : &str) -> std::io::Result<Self> {
-        let contents = std::fs::read_to_string(p)?;
-        Ok(Self::parse_contents(p, &contents))
+    pub fn parse(p : &str) -> Result<Self, Vec<ParseError>> {
+        Self::parse_with(p, &CustomCommands::default())
+    }
+
+    pub fn parse_with(p : &str, custom_commands : &CustomCommands) -> Result<Self, Vec<ParseError>> {
+        let contents = std::fs::read_to_string(p).map_err(|e| vec![ParseError::io(p, &e)])?;
+        Self::parse_contents_with(p, &contents, custom_commands)
+    }
+
+    pub fn parse_contents(filename : &str, contents : &str) -> Result<Self, Vec<ParseError>> {
+        Self::parse_contents_with(filename, contents, &CustomCommands::default())
     }
 
-    pub fn parse_contents(filename : &str, contents : &str) -> Self {
-        eprintln!("Parsing: {}", filename);
+    pub fn parse_contents_with(filename : &str, contents : &str, custom_commands : &CustomCommands) -> Result<Self, Vec<ParseError>> {
         let mut sections = vec![];
         let mut talkers = vec![];
+        let mut ctx = ParseContext {
+            filename,
+            custom_commands,
+            errors : vec![],
+            section_refs : vec![],
+            line_ids : HashSet::new(),
+        };
         let lines = contents.lines().collect::<Vec<_>>();
 
         let mut i = 0;
@@ -267,41 +842,55 @@ impl DialogueFile
                 continue;
             }
 
-            if (line.starts_with("[")) {
-                let section_name = &line[1..line.len()-1];
+            if let Some(header) = line.strip_prefix('[') {
+                if (!line.ends_with("]")) {
+                    ctx.error(i + 1, 1, line, ParseErrorKind::UnterminatedHeader);
+                }
+
+                let section_name = header.trim_end_matches(']');
 
                 i += 1;
 
                 if let Some((keyword, name)) = section_name.split_once(" ") {
                     if (unicase::eq_ascii(keyword, "talker")) {
-                        eprintln!("Read talker: {}", name);
-                        talkers.push(Self::parse_talker(name, &lines, &mut i));
-                        eprintln!("{:?}", talkers.last());
+                        talkers.push(Self::parse_talker(&mut ctx, name, &lines, &mut i));
                         continue;
                     }
                 }
 
-                eprintln!("Read Section: {}", section_name);
-                let section = Self::parse_section(&talkers, filename, section_name, &lines, &mut i);
-                eprintln!("{:?}", section);
-                sections.push(section);
+                sections.push(Self::parse_section(&mut ctx, &talkers, section_name, &lines, &mut i));
+            }
+            else {
+                // Text before the first section header, nothing to attach it to.
+                i += 1;
             }
         }
 
-        Self {
-            talkers,
-            sections,
+        let mut errors = ctx.errors;
+        let mut external_refs = vec![];
+        for section_ref in ctx.section_refs {
+            if (section_ref.target.file.is_some()) {
+                external_refs.push(section_ref);
+            }
+            else if (!sections.iter().any(|x : &Dialogue| unicase::eq_ascii(&x.name, &section_ref.target.section))) {
+                errors.push(section_ref.unknown_section_error());
+            }
         }
-    }
 
-    pub fn get(&self, section_name : &str) -> Option<&Dialogue> {
-        for section in &self.sections {
-            if (unicase::eq_ascii(section_name, &section.name)) {
-                return Some(section);
-            }
+        if (errors.is_empty()) {
+            Ok(Self {
+                talkers,
+                sections,
+                external_refs,
+            })
+        }
+        else {
+            Err(errors)
         }
+    }
 
-        return None;
+    pub fn get(&self, section_name : &str) -> Option<&Dialogue> {
+        self.sections.iter().find(|x| unicase::eq_ascii(section_name, &x.name))
     }
 }
 
@@ -309,23 +898,239 @@ impl DialogueFile
 pub struct DialogueCache
 {
     cache : HashMap<String, DialogueFile>,
+    errors : HashMap<String, Vec<ParseError>>,
+    // Checked when files are parsed, register before preloading.
+    pub custom_commands : CustomCommands,
+    // Translations sit next to the base file, eg intro.fr.adlib for intro.adlib
+    locale : Option<String>,
+    // Translation filename to the base filename it was found from
+    base_filenames : HashMap<String, String>,
+    // How each file looked on disk when it was loaded, for reload_changed
+    stamps : HashMap<String, FileStamp>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FileStamp
+{
+    modified : Option<std::time::SystemTime>,
+    // None if the file couldn't be read
+    hash : Option<u64>,
+}
+
+impl FileStamp {
+    fn read(filename : &str) -> Self {
+        Self {
+            modified : std::fs::metadata(filename).and_then(|x| x.modified()).ok(),
+            hash : std::fs::read(filename).ok().map(|x| fnv1a(&x)),
+        }
+    }
 }
 
 impl DialogueCache {
+    // Takes effect on the next lookup, cached files for other locales stay loaded.
+    pub fn set_locale(&mut self, locale : Option<&str>) {
+        self.locale = locale.filter(|x| !x.is_empty()).map(|x| x.to_owned());
+    }
+
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    // The current locale's translation of a base filename, whether or not it exists.
+    pub fn localized_filename(&self, filename : &str) -> Option<String> {
+        let locale = self.locale.as_ref()?;
+        let base = filename.strip_suffix(".adlib").unwrap_or(filename);
+        Some(format!("{}.{}.adlib", base, locale))
+    }
+
+    // Undoes localized_filename for translations that have been looked up.
+    pub fn base_filename<'a>(&'a self, filename : &'a str) -> &'a str {
+        self.base_filenames.get(filename).map(|x| &x[..]).unwrap_or(filename)
+    }
+
+    // The file to read a section from. That's the current locale's translation of filename
+    // if it has the section, or is broken so its errors get shown, otherwise filename.
+    pub fn resolve_section_file(&mut self, filename : &str, section : &str) -> String {
+        let filename = self.base_filename(filename).to_owned();
+        let localized = match self.localized_filename(&filename) {
+            Some(localized) => localized,
+            None => return filename,
+        };
+
+        if (!self.base_filenames.contains_key(&localized)) {
+            self.base_filenames.insert(localized.clone(), filename.clone());
+
+            // Untranslated files are normal, don't report them as read errors
+            if (std::path::Path::new(&localized).exists()) {
+                let missing = self.missing_translations(&filename);
+                if (!missing.is_empty()) {
+                    eprintln!("{}: no translation of {}", localized, missing.join(", "));
+                }
+            }
+        }
+
+        if let Some(file) = self.get(&localized) {
+            if (file.get(section).is_some()) {
+                return localized;
+            }
+        }
+        else if (self.get_errors(&localized).unwrap_or_default().iter().any(|x| !matches!(x.kind, ParseErrorKind::Io(_)))) {
+            return localized;
+        }
+
+        filename
+    }
+
+    // Sections of a base file that the current locale's translation doesn't have.
+    // Empty if there is no locale or no translation file.
+    pub fn missing_translations(&mut self, filename : &str) -> Vec<String> {
+        let filename = self.base_filename(filename).to_owned();
+        let localized = match self.localized_filename(&filename) {
+            Some(localized) => localized,
+            None => return vec![],
+        };
+
+        self.preload(&filename);
+        if (std::path::Path::new(&localized).exists()) {
+            self.preload(&localized);
+        }
+
+        match (self.get(&filename), self.get(&localized)) {
+            (Some(base), Some(translation)) => {
+                base.sections.iter()
+                    .filter(|x| translation.get(&x.name).is_none())
+                    .map(|x| x.name.clone())
+                    .collect()
+            },
+            _ => vec![],
+        }
+    }
+
     pub fn preload(&mut self, filename : &str) {
-        if (self.cache.contains_key(filename))
+        if (self.cache.contains_key(filename) || self.errors.contains_key(filename))
         {
             // Already loaded.
         }
         else {
-            let dialogue = DialogueFile::parse(filename).expect("Could not parse file in DialogueCache");
-            self.cache.insert(filename.to_owned(), dialogue);
+            self.stamps.insert(filename.to_owned(), FileStamp::read(filename));
+
+            match DialogueFile::parse_with(filename, &self.custom_commands) {
+                Ok(mut dialogue) => {
+                    // Known as a translation once resolve_section_file has looked it up
+                    if let Some(base) = self.base_filenames.get(filename) {
+                        for section in dialogue.sections.iter_mut() {
+                            section.base_filename = base.clone();
+                        }
+                    }
+                    let external_refs = dialogue.external_refs.clone();
+
+                    // Insert before following references so files referencing each other terminate.
+                    self.cache.insert(filename.to_owned(), dialogue);
+
+                    let errors = self.check_external_refs(filename, &external_refs);
+                    if (!errors.is_empty()) {
+                        self.cache.remove(filename);
+                        self.insert_errors(filename, errors);
+                    }
+                },
+                Err(errors) => {
+                    self.insert_errors(filename, errors);
+                },
+            }
+        }
+    }
+
+    // Reparses files that changed on disk since they were loaded, returning their names.
+    // Saving without changes only touches the mtime, the contents hash tells those apart.
+    // Files with errors and files referring to a changed one are checked again too, as
+    // their references may have been broken or fixed. Dialogue already handed out is a
+    // copy and keeps playing the old text.
+    pub fn reload_changed(&mut self) -> Vec<String> {
+        let mut changed = vec![];
+        for (filename, stamp) in self.stamps.iter_mut() {
+            let modified = std::fs::metadata(filename).and_then(|x| x.modified()).ok();
+            if (modified.is_some() && modified == stamp.modified) {
+                continue;
+            }
+
+            let current = FileStamp::read(filename);
+            if (current.hash != stamp.hash) {
+                changed.push(filename.clone());
+            }
+            *stamp = current;
+        }
+
+        if (changed.is_empty()) {
+            return changed;
+        }
+        changed.sort();
+
+        // Missing files would only repeat their read error, they show up in changed once they exist
+        let mut stale = self.errors.iter()
+            .filter(|(_, errors)| !errors.iter().all(|x| matches!(x.kind, ParseErrorKind::Io(_))))
+            .map(|(filename, _)| filename.clone())
+            .collect::<HashSet<_>>();
+        for (filename, file) in &self.cache {
+            let refers_to_changed = file.external_refs.iter().any(|x| {
+                let target = x.target.resolve_filename(self.base_filename(filename));
+                let localized = self.localized_filename(&target);
+                changed.contains(&target) || localized.map(|x| changed.contains(&x)).unwrap_or(false)
+            });
+
+            if (refers_to_changed) {
+                stale.insert(filename.clone());
+            }
+        }
+        stale.extend(changed.iter().cloned());
+
+        let mut stale = stale.into_iter().collect::<Vec<_>>();
+        stale.sort();
+        for filename in &stale {
+            self.cache.remove(filename);
+            self.errors.remove(filename);
+        }
+        for filename in &stale {
+            self.preload(filename);
+        }
+
+        changed
+    }
+
+    fn check_external_refs(&mut self, filename : &str, external_refs : &[SectionRef]) -> Vec<ParseError> {
+        let mut errors = vec![];
+        for section_ref in external_refs {
+            let target_filename = section_ref.target.resolve_filename(self.base_filename(filename));
+            let target_filename = self.resolve_section_file(&target_filename, &section_ref.target.section);
+            self.preload(&target_filename);
+
+            if let Some(target_file) = self.get(&target_filename) {
+                if (target_file.get(&section_ref.target.section).is_none()) {
+                    errors.push(section_ref.unknown_section_error());
+                }
+            }
+            else if (self.get_errors(&target_filename).unwrap_or_default().iter().all(|x| matches!(x.kind, ParseErrorKind::Io(_)))) {
+                // Missing file, broken files report their own errors when queued.
+                errors.push(section_ref.unknown_section_error());
+            }
+        }
+
+        errors
+    }
+
+    fn insert_errors(&mut self, filename : &str, errors : Vec<ParseError>) {
+        for error in &errors {
+            eprintln!("{}", error);
         }
+        self.errors.insert(filename.to_owned(), errors);
     }
 
     pub fn get(&self, filename : &str) -> Option<&DialogueFile> {
         self.cache.get(filename)
     }
+
+    pub fn get_errors(&self, filename : &str) -> Option<&[ParseError]> {
+        self.errors.get(filename).map(|x| &x[..])
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -334,9 +1139,19 @@ pub enum Annotation {
     Wide,
 }
 
+impl Annotation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Annotation::Jiggly => "jiggly",
+            Annotation::Wide => "wide",
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SpanAnnotation
 {
+    // In grapheme clusters, like the reveal
     start : usize,
     end : usize,
     pub annotations : Vec<Annotation>,
@@ -359,6 +1174,12 @@ impl<'a> AnnotatedString {
 }
 
 impl AnnotatedString {
+    fn substring(&self, span : &SpanAnnotation) -> &str {
+        let start = grapheme_byte_offset(&self.string, span.start);
+        let end = grapheme_byte_offset(&self.string, span.end);
+        &self.string[start..end]
+    }
+
     pub fn owned_iter(self) -> OwnedAnnotatedStringIterator {
         OwnedAnnotatedStringIterator {
             annotated : self,
@@ -373,11 +1194,13 @@ pub struct AnnotatedStringIterator<'a> {
 }
 
 impl<'a> AnnotatedStringIterator<'a> {
+    // Items borrow the iterator, which Iterator can't express
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<(&str, &SpanAnnotation)> {
         if self.i < self.annotated.annotations.len() {
             let x = &self.annotated.annotations[self.i];
 
-            let substring = &self.annotated.string[x.start..x.end];
+            let substring = self.annotated.substring(x);
             let annotations = &self.annotated.annotations[self.i];
 
             self.i += 1;
@@ -397,11 +1220,13 @@ pub struct OwnedAnnotatedStringIterator {
 }
 
 impl OwnedAnnotatedStringIterator {
+    // Items borrow the iterator, which Iterator can't express
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<(&str, &SpanAnnotation)> {
         if self.i < self.annotated.annotations.len() {
             let x = &self.annotated.annotations[self.i];
 
-            let substring = &self.annotated.string[x.start..x.end];
+            let substring = self.annotated.substring(x);
             let annotations = &self.annotated.annotations[self.i];
 
             self.i += 1;
@@ -414,10 +1239,34 @@ impl OwnedAnnotatedStringIterator {
     }
 }
 
+// Deepest the call stack goes before the cursor gives up on the conversation.
+pub const MAX_CALL_DEPTH : usize = 64;
+
 #[derive(Clone, Debug)]
 pub struct DialogueCursor
 {
+    // The dialogue that was queued, dialogue changes when following a goto or call.
+    root_name : String,
+    root_filename : String,
     dialogue : Dialogue,
+    // Dialogues to return to and the index of the call chunk in each.
+    call_stack : Vec<(Dialogue, usize)>,
+    // Chunks in branches of an if that weren't taken.
+    hidden : Vec<std::ops::Range<usize>>,
+    // Set by talker prefixed lines and the speaker command, carries across jumps.
+    talker : Option<Talker>,
+    // First character of the grapheme cluster revealed by the last incr, if any
+    just_revealed : Option<char>,
+    // Whether a line has started and not hit its newline yet
+    in_line : bool,
+    // Id of the last line entered, if it had one
+    line_id : Option<String>,
+    // Collected as chunks are entered, taken by the engine
+    events : Vec<DialogueEvent>,
+    // Sections entered and lines started, recorded into Variables::seen on the next incr
+    seen : Vec<SeenKey>,
+    // Stopped on a blocking custom command until the host resumes
+    blocked : bool,
     start : usize,
     end : usize,
     line_i : usize,
@@ -426,17 +1275,136 @@ pub struct DialogueCursor
 
 impl DialogueCursor {
     pub fn new(dialogue : &Dialogue) -> Self {
-        Self {
+        Self::with_speaker(dialogue, None)
+    }
+
+    // Starts as if the section began with (speaker name).
+    pub fn with_speaker(dialogue : &Dialogue, speaker : Option<&str>) -> Self {
+        let mut cursor = Self {
+            root_name : dialogue.name.clone(),
+            root_filename : dialogue.filename.clone(),
             dialogue : dialogue.clone(),
+            call_stack : vec![],
+            hidden : vec![],
+            talker : None,
+            just_revealed : None,
+            in_line : false,
+            line_id : None,
+            events : vec![],
+            seen : vec![SeenKey::new(&dialogue.base_filename, &dialogue.name, None)],
+            blocked : false,
             start : 0,
             end : 0,
             line_i : 0,
-            exhausted: false,
+            exhausted: dialogue.chunks.is_empty(),
+        };
+
+        if let Some(name) = speaker {
+            cursor.set_talker(Some(cursor.speaker(name)));
+        }
+        cursor.enter_chunk();
+        cursor
+    }
+
+    pub fn talker(&self) -> Option<&Talker> {
+        self.talker.as_ref()
+    }
+
+    pub fn line_id(&self) -> Option<&str> {
+        self.line_id.as_deref()
+    }
+
+    pub fn just_revealed(&self) -> Option<char> {
+        self.just_revealed
+    }
+
+    pub fn take_events(&mut self) -> Vec<DialogueEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn set_talker(&mut self, talker : Option<Talker>) {
+        let old_name = self.talker.as_ref().map(|x| &x.name);
+        let new_name = talker.as_ref().map(|x| &x.name);
+        if let Some(name) = new_name {
+            if (old_name.map(|x| !unicase::eq_ascii(&x[..], &name[..])).unwrap_or(true)) {
+                self.events.push(DialogueEvent::SpeakerChanged { name : name.clone() });
+            }
         }
+
+        self.talker = talker;
+    }
+
+    // Speakers don't have to be declared, they just won't have a sprite or sound.
+    fn speaker(&self, name : &str) -> Talker {
+        self.dialogue.talker_by_name(name).cloned().unwrap_or_else(|| Talker {
+            name : name.to_owned(),
+            ..Default::default()
+        })
+    }
+
+    // Called whenever end moves on to a new chunk.
+    fn enter_chunk(&mut self) {
+        let chunk = match self.dialogue.chunks.get(self.end) {
+            Some(chunk) => chunk.clone(),
+            None => return,
+        };
+
+        match chunk {
+            Chunk::Text(text) => {
+                if let Some(talker_id) = text.talker_id {
+                    self.set_talker(self.dialogue.talker(talker_id).cloned());
+                }
+
+                if (!self.in_line) {
+                    self.in_line = true;
+                    self.line_id = text.line_id.clone();
+                    self.seen.push(SeenKey::new(&self.dialogue.base_filename, &self.dialogue.name, Some(&self.dialogue.line_key(self.end))));
+                    self.events.push(DialogueEvent::LineStarted { talker : self.talker.as_ref().map(|x| x.name.clone()) });
+                }
+            },
+            Chunk::Newline => {
+                self.in_line = false;
+                self.events.push(DialogueEvent::LineFinished);
+            },
+            Chunk::Command(Command::Speaker(name)) => {
+                self.set_talker(Some(self.speaker(&name)));
+            },
+            Chunk::Command(Command::Clear) => {
+                self.events.push(DialogueEvent::Clear);
+            },
+            Chunk::Command(Command::Wait(frames)) => {
+                self.events.push(DialogueEvent::WaitStarted { frames });
+            },
+            Chunk::Command(Command::AnnotationStart(annotation)) => {
+                self.events.push(DialogueEvent::AnnotationOpened(annotation));
+            },
+            Chunk::Command(Command::AnnotationEnd(annotation)) => {
+                self.events.push(DialogueEvent::AnnotationClosed(annotation));
+            },
+            Chunk::Command(Command::Vo(clip)) => {
+                self.events.push(DialogueEvent::VoStarted { clip });
+            },
+            Chunk::Command(Command::Custom { name, args, blocking }) => {
+                self.blocked = blocking;
+                self.events.push(DialogueEvent::Custom { name, args, blocking });
+            },
+            _ => {},
+        }
+    }
+
+    // Called whenever end is about to move off a chunk.
+    fn leave_chunk(&mut self) {
+        if let Some(Chunk::Command(Command::Wait(_))) = self.dialogue.chunks.get(self.end) {
+            self.events.push(DialogueEvent::WaitEnded);
+        }
+    }
+
+    pub fn root_name(&self) -> &str {
+        &self.root_name
     }
 
     pub fn dialogue_name_eq(&self, other: &Dialogue) -> bool {
-        self.dialogue.name_eq(other)
+        unicase::eq_ascii(&self.root_name, &other.name) && unicase::eq_ascii(&self.root_filename, &other.filename)
     }
 
     pub fn get(&self) -> AnnotatedString {
@@ -444,7 +1412,11 @@ impl DialogueCursor {
         let mut span_annotations = vec![];
         let mut annotations : Vec<Annotation> = vec![];
         let mut start = 0;
-        for i in self.start..=self.end {
+        for i in self.start..=self.end.min(self.dialogue.chunks.len().saturating_sub(1)) {
+            if (self.hidden.iter().any(|x| x.contains(&i))) {
+                continue;
+            }
+
             match &self.dialogue.chunks[i] {
                 Chunk::Newline => {
                     s.push('#');
@@ -454,13 +1426,16 @@ impl DialogueCursor {
                         s.push_str(&text.text);
                     }
                     else {
-                        s.push_str(&text.text[0..self.line_i.min(text.text.len())]);
+                        s.push_str(text.revealed(self.line_i));
                     }
                 },
+                Chunk::Choice(_) => {
+                    // Options are drawn by the host, see DialogueEngine::choices
+                },
                 Chunk::Command(command) => {
                     span_annotations.push(SpanAnnotation {
                         start,
-                        end : s.len(),
+                        end : grapheme_len(&s),
                         annotations: annotations.clone(),
                     });
 
@@ -469,23 +1444,23 @@ impl DialogueCursor {
                             annotations.push(*an)
                         },
                         Command::AnnotationEnd(an) => {
-                            annotations = annotations.into_iter().filter(|x| *x != *an).collect();
+                            annotations.retain(|x| *x != *an);
                         },
                         _ => {},
                     }
 
-                    if (s.len() > 0) {
+                    if (!s.is_empty()) {
                         s.push(' ');
                     }
 
-                    start = s.len();
+                    start = grapheme_len(&s);
                 }
             }
         }
 
         span_annotations.push(SpanAnnotation {
             start, 
-            end : s.len(),
+            end : grapheme_len(&s),
             annotations: annotations.clone(),
         });
 
@@ -495,28 +1470,271 @@ impl DialogueCursor {
         }
     }
 
-    pub fn incr(&mut self) -> bool {
+    pub fn current_choice(&self) -> Option<&[ChoiceOption]> {
+        if (self.exhausted) {
+            return None;
+        }
+
+        if let Some(Chunk::Choice(options)) = self.dialogue.chunks.get(self.end) {
+            Some(options)
+        }
+        else {
+            None
+        }
+    }
+
+    // Set when the cursor is stopped on a goto or call, resolved with jump.
+    pub fn pending_jump(&self) -> Option<&SectionTarget> {
         if (self.exhausted) {
+            return None;
+        }
+
+        if let Some(Chunk::Command(command)) = self.dialogue.chunks.get(self.end) {
+            command.section_target()
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.dialogue.filename
+    }
+
+    // Incrs left before reaching the newline ending the current line. Every chunk takes one
+    // more than its tick_len, the last one moves on to the next chunk.
+    pub fn ticks_to_line_end(&self) -> u32 {
+        let mut ticks = self.dialogue.chunks.get(self.end).map(|x| x.tick_len().saturating_sub(self.line_i as u32) + 1).unwrap_or(0);
+        for i in (self.end + 1)..self.dialogue.chunks.len() {
+            match &self.dialogue.chunks[i] {
+                Chunk::Newline | Chunk::Choice(_) => break,
+                chunk => ticks += chunk.tick_len() + 1,
+            }
+        }
+
+        ticks
+    }
+
+    // On the newline ending a line, not yet moved past it.
+    pub fn at_line_end(&self) -> bool {
+        !self.exhausted && matches!(self.dialogue.chunks.get(self.end), Some(Chunk::Newline))
+    }
+
+    // Name of the blocking custom command the cursor is waiting on, if any.
+    pub fn blocking_command(&self) -> Option<&str> {
+        match self.dialogue.chunks.get(self.end) {
+            Some(Chunk::Command(Command::Custom { name, .. })) if self.blocked => Some(name),
+            _ => None,
+        }
+    }
+
+    // The host finished whatever the blocking custom command started.
+    pub fn resume_custom_command(&mut self) -> bool {
+        std::mem::replace(&mut self.blocked, false)
+    }
+
+    // Continue from the start of another dialogue, remembering where we were if the
+    // cursor is on a call.
+    pub fn jump(&mut self, dialogue : &Dialogue) {
+        let on_call = matches!(self.dialogue.chunks.get(self.end), Some(Chunk::Command(Command::Call(_))));
+        if (on_call && self.call_stack.len() >= MAX_CALL_DEPTH) {
+            // Almost certainly sections calling each other forever, show that and stop
+            let message = format!("Calls nested more than {} deep at {}", MAX_CALL_DEPTH, dialogue.name);
+            self.call_stack.clear();
+            self.dialogue = Dialogue::from_error(&message);
+        }
+        else if (on_call) {
+            let caller = std::mem::replace(&mut self.dialogue, dialogue.clone());
+            self.call_stack.push((caller, self.end));
+            self.seen.push(SeenKey::new(&dialogue.base_filename, &dialogue.name, None));
+        }
+        else {
+            self.dialogue = dialogue.clone();
+            self.seen.push(SeenKey::new(&dialogue.base_filename, &dialogue.name, None));
+        }
+
+        // Jumping starts a fresh screen, like a clear.
+        self.blocked = false;
+        self.hidden.clear();
+        self.in_line = false;
+        self.events.push(DialogueEvent::Clear);
+        self.start = 0;
+        self.end = 0;
+        self.line_i = 0;
+
+        match self.dialogue.chunks.first() {
+            None | Some(Chunk::Command(Command::Return)) => {
+                self.return_to_caller();
+            },
+            _ => {
+                self.enter_chunk();
+            },
+        }
+    }
+
+    fn pop_call_stack(&mut self) -> bool {
+        if let Some((dialogue, call_i)) = self.call_stack.pop() {
+            self.dialogue = dialogue;
+            self.hidden.clear();
+            self.in_line = false;
+            self.events.push(DialogueEvent::Clear);
+            self.start = call_i;
+            self.end = call_i;
+            self.line_i = 0;
+            true
+        }
+        else {
             false
         }
+    }
+
+    fn return_to_caller(&mut self) -> bool {
+        if (self.pop_call_stack()) {
+            self.next_chunk()
+        }
         else {
-            //println!("incr {} {}", self.end, self.line_i);
-            if (self.line_i >= self.dialogue.chunks[self.end].tick_len() as usize) {
+            self.exhausted = true;
+            false
+        }
+    }
 
-                if (self.end + 1 >= self.dialogue.chunks.len()) {
-                    self.exhausted = true;
-                    return false;
+    fn next_chunk(&mut self) -> bool {
+        self.leave_chunk();
+
+        if (self.end + 1 >= self.dialogue.chunks.len()) {
+            // Falling off the end of a called section returns.
+            return self.return_to_caller();
+        }
+
+        self.end += 1;
+        self.line_i = 0;
+
+        match self.dialogue.chunks[self.end] {
+            Chunk::Command(Command::Clear) => {
+                self.start = self.end;
+            },
+            Chunk::Command(Command::Return) => {
+                return self.return_to_caller();
+            },
+            _ => {},
+        }
+
+        self.enter_chunk();
+        true
+    }
+
+    // Move past a choice whose option has no target.
+    pub fn resume(&mut self) -> bool {
+        if (self.exhausted) {
+            false
+        }
+        else {
+            self.next_chunk()
+        }
+    }
+
+    // Separators at the same depth as the block containing from, followed by the index closing it.
+    fn scan_block(&self, from : usize, block : Block) -> Vec<usize> {
+        let mut found = vec![];
+        let mut depth = 0;
+        for j in (from + 1)..self.dialogue.chunks.len() {
+            if let Chunk::Command(command) = &self.dialogue.chunks[j] {
+                if (command.opens_block() == Some(block)) {
+                    depth += 1;
+                }
+                else if (depth == 0 && command.separates_block() == Some(block)) {
+                    found.push(j);
+                }
+                else if (command.closes_block() == Some(block)) {
+                    if (depth == 0) {
+                        found.push(j);
+                        return found;
+                    }
+                    depth -= 1;
                 }
+            }
+        }
+
+        // Unterminated, parsing should have caught this
+        found.push(self.dialogue.chunks.len() - 1);
+        found
+    }
 
-                self.end += 1;
-                self.line_i = 0;
+    // Move end forward to j, hiding everything between.
+    fn skip_to(&mut self, j : usize) {
+        self.hidden.push((self.end + 1)..j);
+        self.end = j;
+    }
 
-                if let Chunk::Command(Command::Clear) = self.dialogue.chunks[self.end] {
-                    self.start = self.end;
+    fn pick_variant(&mut self, mode : VariantMode, variants : &mut Variants) {
+        let boundaries = self.scan_block(self.end, Block::Random);
+
+        // Each variant starts after the random command or a variant separator
+        let mut starts = vec![self.end];
+        let mut weights = vec![1];
+        for j in &boundaries[..boundaries.len() - 1] {
+            if let Chunk::Command(Command::Variant(weight)) = self.dialogue.chunks[*j] {
+                if (*j == self.end + 1) {
+                    // Nothing before the first separator, so it replaces the implicit first variant
+                    starts.clear();
+                    weights.clear();
                 }
+                starts.push(*j);
+                weights.push(weight);
+            }
+        }
+
+        let key = VariantKey::new(&self.dialogue.base_filename, &self.dialogue.name, self.end);
+        let picked = variants.pick(key, mode, &weights);
+        self.skip_to(starts[picked]);
+    }
+
+    pub fn incr(&mut self, variables : &mut Variables, variants : &mut Variants) -> bool {
+        self.just_revealed = None;
+        for key in self.seen.drain(..) {
+            variables.seen.record(key);
+        }
+
+        if (self.exhausted) {
+            false
+        }
+        else if (self.current_choice().is_some() || self.pending_jump().is_some() || self.blocked) {
+            // Stalled until resumed or jumped
+            true
+        }
+        else {
+            match &self.dialogue.chunks[self.end] {
+                Chunk::Command(Command::Set(assignment)) => {
+                    assignment.apply(variables);
+                },
+                Chunk::Command(Command::If(condition)) if !condition.evaluate(variables, &self.dialogue.base_filename) => {
+                    // To the else or endif
+                    let j = self.scan_block(self.end, Block::If)[0];
+                    self.skip_to(j);
+                },
+                Chunk::Command(Command::Random(mode)) => {
+                    let mode = *mode;
+                    self.pick_variant(mode, variants);
+                },
+                Chunk::Command(command) if command.separates_block().is_some() => {
+                    // Reached the end of a taken branch, on to the endif or endrandom
+                    let block = command.separates_block().unwrap();
+                    let j = *self.scan_block(self.end, block).last().unwrap();
+                    self.skip_to(j);
+                },
+                _ => {},
+            }
+
+            //println!("incr {} {}", self.end, self.line_i);
+            if (self.line_i >= self.dialogue.chunks[self.end].tick_len() as usize) {
+                return self.next_chunk();
             }
             else {
                 self.line_i += 1;
+
+                if let Chunk::Text(text) = &self.dialogue.chunks[self.end] {
+                    self.just_revealed = text.revealed(self.line_i).graphemes(true).next_back().and_then(|x| x.chars().next());
+                }
             }
 
             true
@@ -524,6 +1742,105 @@ impl DialogueCursor {
     }
 }
 
+// A section by name, with the fingerprint it had when saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DialogueRef
+{
+    pub filename : String,
+    pub section : String,
+    pub fingerprint : u64,
+}
+
+impl DialogueRef {
+    fn new(dialogue : &Dialogue) -> Self {
+        Self {
+            filename : dialogue.filename.clone(),
+            section : dialogue.name.clone(),
+            fingerprint : dialogue.fingerprint(),
+        }
+    }
+
+    // None if the section is gone or has changed since.
+    fn resolve(&self, lookup : &dyn Fn(&str, &str) -> Option<Dialogue>) -> Option<Dialogue> {
+        lookup(&self.filename, &self.section).filter(|x| x.fingerprint() == self.fingerprint)
+    }
+}
+
+// Position of a cursor with dialogues by name, see DialogueCursor::snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CursorSnapshot
+{
+    pub root_name : String,
+    pub root_filename : String,
+    pub dialogue : DialogueRef,
+    // Callers and the index of the call chunk in each, innermost last
+    pub call_stack : Vec<(DialogueRef, usize)>,
+    pub hidden : Vec<(usize, usize)>,
+    pub talker : Option<Talker>,
+    pub in_line : bool,
+    pub line_id : Option<String>,
+    pub blocked : bool,
+    pub start : usize,
+    pub end : usize,
+    pub line_i : usize,
+    pub exhausted : bool,
+}
+
+impl DialogueCursor {
+    pub fn snapshot(&self) -> CursorSnapshot {
+        CursorSnapshot {
+            root_name : self.root_name.clone(),
+            root_filename : self.root_filename.clone(),
+            dialogue : DialogueRef::new(&self.dialogue),
+            call_stack : self.call_stack.iter().map(|(dialogue, call_i)| (DialogueRef::new(dialogue), *call_i)).collect(),
+            hidden : self.hidden.iter().map(|x| (x.start, x.end)).collect(),
+            talker : self.talker.clone(),
+            in_line : self.in_line,
+            line_id : self.line_id.clone(),
+            blocked : self.blocked,
+            start : self.start,
+            end : self.end,
+            line_i : self.line_i,
+            exhausted : self.exhausted,
+        }
+    }
+
+    // Looks every dialogue up again by file and section. None if any of them is missing or
+    // changed, since positions in a changed section don't mean anything.
+    pub fn restore(snapshot : &CursorSnapshot, lookup : &dyn Fn(&str, &str) -> Option<Dialogue>) -> Option<Self> {
+        let dialogue = snapshot.dialogue.resolve(lookup)?;
+        let mut call_stack = vec![];
+        for (dialogue_ref, call_i) in &snapshot.call_stack {
+            call_stack.push((dialogue_ref.resolve(lookup)?, *call_i));
+        }
+
+        // Fingerprints match so indices are in range unless the save was edited
+        let in_range = snapshot.end < dialogue.chunks.len().max(1) && snapshot.start <= snapshot.end;
+        if (!in_range || call_stack.iter().any(|(dialogue, call_i)| *call_i >= dialogue.chunks.len())) {
+            return None;
+        }
+
+        Some(Self {
+            root_name : snapshot.root_name.clone(),
+            root_filename : snapshot.root_filename.clone(),
+            dialogue,
+            call_stack,
+            hidden : snapshot.hidden.iter().map(|(start, end)| *start..*end).collect(),
+            talker : snapshot.talker.clone(),
+            just_revealed : None,
+            in_line : snapshot.in_line,
+            line_id : snapshot.line_id.clone(),
+            events : vec![],
+            seen : vec![],
+            blocked : snapshot.blocked,
+            start : snapshot.start,
+            end : snapshot.end,
+            line_i : snapshot.line_i,
+            exhausted : snapshot.exhausted,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests
 {
@@ -541,9 +1858,316 @@ sound = snd_goose
 [intro]
 goose | hello there
 (wait 100ms)
-goose | (j) toad (/j)");
+goose | (j) toad (/j)").unwrap();
+
+        assert_eq!(parsed.talkers, vec![Talker {
+            name : "goose".to_owned(),
+            sprite : "spr_goose".to_owned(),
+            sound : "snd_goose".to_owned(),
+            rate : None,
+        }]);
+        assert_eq!(parsed.sections.len(), 1);
+
+        let intro = parsed.get("INTRO").unwrap();
+        assert_eq!(intro.talkers, parsed.talkers);
+        let summary = intro.chunks.iter().map(|x| match x {
+            Chunk::Text(text) => format!("{:?} {:?}", text.text, text.talker_id),
+            Chunk::Newline => "newline".to_owned(),
+            Chunk::Command(command) => format!("{:?}", command),
+            Chunk::Choice(options) => format!("choice {}", options.len()),
+        }).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            "\"hello there\" Some(0)",
+            "newline",
+            // 100ms at 60 frames a second
+            "Wait(6)",
+            "\"\" Some(0)",
+            "AnnotationStart(Jiggly)",
+            "\"toad\" Some(0)",
+            "AnnotationEnd(Jiggly)",
+            "\"\" Some(0)",
+            "newline",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_errors()
+    {
+        // Once anything is registered, other names are typos
+        let mut custom_commands = CustomCommands::default();
+        custom_commands.register("shake", false);
+
+        let errors = DialogueFile::parse_contents_with("test", "[talker goose]
+rate = fast
+
+[intro]
+goose | hello (shout) there
+(wait soon)
+(speaker)
+[outro
+goose | bye (j", &custom_commands).unwrap_err();
+
+        let summary = errors.iter().map(|x| (x.line, x.column, x.kind.clone())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (2, 8, ParseErrorKind::BadRate),
+            (5, 15, ParseErrorKind::UnknownCommand),
+            (6, 1, ParseErrorKind::BadDuration),
+            (7, 1, ParseErrorKind::MissingArgument),
+            (8, 1, ParseErrorKind::UnterminatedHeader),
+            (9, 13, ParseErrorKind::UnterminatedCommand),
+        ]);
+        assert_eq!(errors[1].text, "(shout)");
+        assert_eq!(errors[1].to_string(), "test:5:15: unknown command '(shout)'");
+    }
+
+    #[test]
+    fn test_parse_choice()
+    {
+        let parsed = DialogueFile::parse_contents("test", "[intro]
+want a toad?
+> yes -> accept
+> no
+
+[accept]
+here you go").unwrap();
+
+        let intro = parsed.get("intro").unwrap();
+        let options = match &intro.chunks[2] {
+            Chunk::Choice(options) => options,
+            x => panic!("expected choice, got {:?}", x),
+        };
+        assert_eq!(options.len(), 2);
+        assert_eq!(options[0].text, "yes");
+        assert_eq!(options[0].target, SectionTarget::parse("accept"));
+        assert_eq!(options[1].text, "no");
+        assert_eq!(options[1].target, None);
+
+        let errors = DialogueFile::parse_contents("test", "[intro]
+> yes -> nowhere").unwrap_err();
+        assert_eq!(errors[0].kind, ParseErrorKind::UnknownSection);
+        assert_eq!((errors[0].line, errors[0].column), (2, 10));
+    }
 
-        println!("{:#?}", parsed);
-        assert!(false);
+    #[test]
+    fn test_external_refs_checked_on_load()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_external_refs");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("shared.adlib"), "[lines]\nhello").unwrap();
+        std::fs::write(dir.join("good.adlib"), "[a]\n(goto shared:lines)").unwrap();
+        std::fs::write(dir.join("bad.adlib"), "[a]\n(call shared:nope)\n(goto missing:lines)").unwrap();
+
+        let good = dir.join("good.adlib").to_string_lossy().into_owned();
+        let bad = dir.join("bad.adlib").to_string_lossy().into_owned();
+
+        let mut cache = DialogueCache::default();
+        cache.preload(&good);
+        cache.preload(&bad);
+
+        assert!(cache.get(&good).is_some());
+        assert!(cache.get(&bad).is_none());
+
+        let errors = cache.get_errors(&bad).unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].text, "shared:nope");
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[1].text, "missing:lines");
+        assert_eq!(errors[1].kind, ParseErrorKind::UnknownSection);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_localized_fallback()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_localized");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("intro.adlib"), "[a]\nhello\n[b]\n(goto a)").unwrap();
+        std::fs::write(dir.join("intro.fr.adlib"), "[a]\nbonjour").unwrap();
+
+        let base = dir.join("intro.adlib").to_string_lossy().into_owned();
+        let fr = dir.join("intro.fr.adlib").to_string_lossy().into_owned();
+
+        let mut cache = DialogueCache::default();
+        assert_eq!(cache.resolve_section_file(&base, "a"), base);
+
+        // Switching locale applies to files that are already cached
+        cache.set_locale(Some("fr"));
+        assert_eq!(cache.resolve_section_file(&base, "a"), fr);
+        assert_eq!(cache.resolve_section_file(&base, "b"), base);
+        assert_eq!(cache.missing_translations(&base), vec!["b"]);
+
+        // Jumps from inside the translation still find the base file, and it's seen as it
+        assert_eq!(cache.base_filename(&fr), base);
+        assert_eq!(cache.get(&fr).unwrap().get("a").unwrap().base_filename, base);
+        assert_eq!(cache.resolve_section_file(&fr, "b"), base);
+
+        cache.set_locale(Some("de"));
+        assert_eq!(cache.resolve_section_file(&base, "a"), base);
+        assert!(cache.missing_translations(&base).is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint()
+    {
+        let source = "[talker goose]\n[a]\ngoose | hi (j) there (/j) #id:hi\n(set mood += 2)\n(if seen b#1 >= 1)\n(call other:b)\n(endif)\n(random cycle)\none\n(variant 2)\ntwo\n(endrandom)\n> ok -> b\n> no\n[b]\nbye";
+        let fingerprint = |source : &str| DialogueFile::parse_contents_with("test", source, &CustomCommands::pass_through()).unwrap().get("a").unwrap().fingerprint();
+
+        // Pinned, a change here restarts every saved conversation
+        assert_eq!(fingerprint(source), 0x18cb42ab7b50742d);
+        assert_eq!(fingerprint(&source.replace("[b]\nbye", "[b]\nbye now")), fingerprint(source));
+        assert_ne!(fingerprint(&source.replace("(variant 2)", "(variant 3)")), fingerprint(source));
+        assert_ne!(fingerprint(&source.replace("seen b#1", "seen b#2")), fingerprint(source));
+    }
+
+    #[test]
+    fn test_reload_changed()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("shared.adlib"), "[lines]\nhello").unwrap();
+        std::fs::write(dir.join("main.adlib"), "[a]\n(goto shared:later)").unwrap();
+
+        let shared = dir.join("shared.adlib").to_string_lossy().into_owned();
+        let main = dir.join("main.adlib").to_string_lossy().into_owned();
+
+        // Pushes the mtime forward so the change shows on filesystems with coarse times
+        let write = |filename : &str, contents : &str| {
+            std::fs::write(filename, contents).unwrap();
+            let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+            std::fs::File::options().write(true).open(filename).unwrap().set_modified(later).unwrap();
+        };
+
+        let mut cache = DialogueCache::default();
+        cache.preload(&main);
+        assert!(cache.get(&main).is_none());
+        assert!(cache.reload_changed().is_empty());
+
+        // Same contents, only the mtime moves
+        write(&shared, "[lines]\nhello");
+        assert!(cache.reload_changed().is_empty());
+
+        // Fixing the target fixes the file referring to it
+        let old = cache.get(&shared).unwrap().get("lines").unwrap().clone();
+        write(&shared, "[lines]\nhello again\n[later]\nbye");
+        assert_eq!(cache.reload_changed(), vec![shared.clone()]);
+        assert!(cache.get(&main).is_some());
+        assert_ne!(cache.get(&shared).unwrap().get("lines").unwrap().fingerprint(), old.fingerprint());
+
+        write(&shared, "[lines]\nhello");
+        assert_eq!(cache.reload_changed(), vec![shared.clone()]);
+        assert!(cache.get(&main).is_none());
+    }
+
+    #[test]
+    fn test_line_ids()
+    {
+        let parsed = DialogueFile::parse_contents("test", "[intro]
+goose | hello (j) there (/j) #id:intro_hello
+no id here").unwrap();
+
+        let intro = parsed.get("intro").unwrap();
+        let ids = intro.chunks.iter().filter_map(|x| match x {
+            Chunk::Text(text) => Some((text.text.clone(), text.line_id.clone())),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(ids, vec![
+            ("hello".to_owned(), Some("intro_hello".to_owned())),
+            ("there".to_owned(), Some("intro_hello".to_owned())),
+            ("".to_owned(), Some("intro_hello".to_owned())),
+            ("no id here".to_owned(), None),
+        ]);
+
+        let mut cursor = DialogueCursor::new(intro);
+        assert_eq!(cursor.line_id(), Some("intro_hello"));
+        while (cursor.line_id().is_some()) {
+            cursor.incr(&mut Variables::default(), &mut Variants::default());
+        }
+        // Cleared once the next line starts
+        assert!(cursor.get().string.contains("#"));
+
+        let errors = DialogueFile::parse_contents("test", "[intro]
+one #id:a
+two #id:a
+three #id:").unwrap_err();
+        let summary = errors.iter().map(|x| (x.line, x.column, x.kind.clone())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (3, 5, ParseErrorKind::DuplicateLineId),
+            (4, 7, ParseErrorKind::MissingArgument),
+        ]);
+    }
+
+    #[test]
+    fn test_unbalanced_if()
+    {
+        let errors = DialogueFile::parse_contents("test", "[intro]
+(if a)
+(else)
+(else)
+(endif)
+(endif)
+(if b)
+(if c ~ 3)").unwrap_err();
+
+        let summary = errors.iter().map(|x| (x.line, x.kind.clone())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (4, ParseErrorKind::UnbalancedIf),
+            (6, ParseErrorKind::UnbalancedIf),
+            (8, ParseErrorKind::BadExpression),
+            (7, ParseErrorKind::UnbalancedIf),
+        ]);
+    }
+
+    // Every string shown while revealing the section, and the characters revealed.
+    fn reveal(contents : &str) -> (Vec<String>, String) {
+        let file = DialogueFile::parse_contents("test", contents).unwrap();
+        let mut cursor = DialogueCursor::new(file.get("intro").unwrap());
+        let mut variables = Variables::default();
+        let mut variants = Variants::default();
+
+        let mut shown : Vec<String> = vec![];
+        let mut revealed = String::new();
+        while cursor.incr(&mut variables, &mut variants) {
+            revealed.extend(cursor.just_revealed());
+            let s = cursor.get().string;
+            if (shown.last() != Some(&s)) {
+                shown.push(s);
+            }
+        }
+
+        (shown, revealed)
+    }
+
+    #[test]
+    fn test_reveal_graphemes()
+    {
+        let (shown, revealed) = reveal("[intro]
+日本語");
+        assert_eq!(shown, vec!["日", "日本", "日本語", "日本語#"]);
+        assert_eq!(revealed, "日本語");
+
+        // e followed by a combining acute accent is revealed in one go
+        let (shown, _) = reveal("[intro]
+cafe\u{301}!");
+        assert_eq!(shown, vec!["c", "ca", "caf", "cafe\u{301}", "cafe\u{301}!", "cafe\u{301}!#"]);
+
+        // Family emoji is four people joined with zero width joiners
+        let (shown, revealed) = reveal("[intro]
+hi \u{1F469}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466} (j) ok (/j)");
+        assert_eq!(shown[3], "hi \u{1F469}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{200D}\u{1F466}");
+        assert_eq!(revealed.chars().nth(3), Some('\u{1F469}'));
+
+        // Span offsets are in graphemes too
+        let file = DialogueFile::parse_contents("test", "[intro]
+\u{1F469}\u{200D}\u{1F467} (j) 日本 (/j) e\u{301}").unwrap();
+        let mut cursor = DialogueCursor::new(file.get("intro").unwrap());
+        while cursor.incr(&mut Variables::default(), &mut Variants::default()) {}
+        let annotated = cursor.get();
+        let mut iter = annotated.iter();
+        let mut spans = vec![];
+        while let Some((substring, span)) = iter.next() {
+            spans.push((substring.to_owned(), span.start, span.end));
+        }
+        assert_eq!(spans[0], ("\u{1F469}\u{200D}\u{1F467}".to_owned(), 0, 1));
+        assert_eq!(spans[1], ("日本".to_owned(), 2, 4));
+        assert_eq!(spans[2].0, "e\u{301}#");
+    }
+}
@@ -1,4 +1,10 @@
+use std::collections::{HashMap, VecDeque};
+
 use crate::dialogue::*;
+use crate::event::{DialogueEvent, DialogueObserver};
+use crate::random::{Variants, VariantsSnapshot};
+use crate::talker::Talker;
+use crate::variables::Variables;
 
 //const CLEAR_T_MAX : f32 = 35.0;
 //const CLEAR_T_SUCK_MIN : f32 = 31.0;
@@ -8,6 +14,13 @@ pub struct DialogueEngineOptions
 {
     pub line_linger_time : f32,
     pub text_rate : f32,
+    // Blip on every nth revealed character, 0 for no blips
+    pub blip_interval : u32,
+    // Hold at the end of every line until advance, and never clear on a timer
+    pub wait_for_input : bool,
+    // After a vo command with a known duration, reveal the rest of the line over the clip
+    // instead of at text_rate
+    pub vo_paced_reveal : bool,
 }
 
 impl Default for DialogueEngineOptions
@@ -16,6 +29,9 @@ impl Default for DialogueEngineOptions
         Self {
             line_linger_time : 240.0,
             text_rate : 0.75,
+            blip_interval : 2,
+            wait_for_input : false,
+            vo_paced_reveal : false,
         }
     }
 }
@@ -24,41 +40,602 @@ impl Default for DialogueEngineOptions
 pub struct DialogueEngine
 {
     pub options : DialogueEngineOptions,
+    pub variants : Variants,
     cursor : Option<DialogueCursor>,
     annotated_string : AnnotatedString,
 
     t : f32,
 
     line_linger_t : f32,
+
+    events : VecDeque<DialogueEvent>,
+    blip_counter : u32,
+
+    // A line finished in wait for input mode and advance hasn't been called yet
+    awaiting_input : bool,
+
+    // Clip name to length in frames, see set_vo_duration
+    vo_durations : HashMap<String, f32>,
+    // Ticks per frame while a clip paces the current line
+    vo_rate : Option<f32>,
+
+    // Of what's playing
+    playback : QueueOptions,
+    // Most important first, in the order they came otherwise
+    queued : VecDeque<Queued>,
+}
+
+// Everything about playback worth saving. Options and vo durations are left out since the
+// host sets those up again on load.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct EngineSnapshot
+{
+    pub cursor : Option<CursorSnapshot>,
+    pub t : f32,
+    pub line_linger_t : f32,
+    pub blip_counter : u32,
+    pub awaiting_input : bool,
+    pub vo_rate : Option<f32>,
+    pub variants : VariantsSnapshot,
+    // Of what's playing
+    pub playback : QueueOptions,
+    pub queued : Vec<QueuedSnapshot>,
+}
+
+// Dialogue waiting to play. It hasn't started so it's found again by name alone and plays
+// whatever the section says by then.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedSnapshot
+{
+    pub filename : String,
+    pub section : String,
+    pub options : QueueOptions,
+}
+
+// What restore managed to do with the saved dialogue.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    // Nothing was playing
+    Idle,
+    // Carrying on exactly where it was
+    Resumed,
+    // A section it was in has changed or gone, so the queued section starts again
+    Restarted,
+    // The queued section is gone too, nothing plays
+    Dropped,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn parse(s : &str) -> Option<Self> {
+        match s {
+            x if unicase::eq_ascii(x, "low") => Some(Priority::Low),
+            x if unicase::eq_ascii(x, "normal") => Some(Priority::Normal),
+            x if unicase::eq_ascii(x, "high") => Some(Priority::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+// What queue does when something is already playing. Dialogue of a higher priority than
+// what's playing always interrupts it, these decide the rest.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // Replace what's playing, or wait for it if it's more important
+    #[default]
+    Interrupt,
+    // Play after it and anything else waiting that's as important
+    Enqueue,
+    // Don't play at all
+    DropIfBusy,
+}
+
+impl QueuePolicy {
+    pub fn parse(s : &str) -> Option<Self> {
+        match s {
+            x if unicase::eq_ascii(x, "interrupt") => Some(QueuePolicy::Interrupt),
+            x if unicase::eq_ascii(x, "enqueue") => Some(QueuePolicy::Enqueue),
+            x if unicase::eq_ascii(x, "drop_if_busy") => Some(QueuePolicy::DropIfBusy),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QueuePolicy::Interrupt => "interrupt",
+            QueuePolicy::Enqueue => "enqueue",
+            QueuePolicy::DropIfBusy => "drop_if_busy",
+        }
+    }
+}
+
+// How to play one queued dialogue. The overrides only last until it finishes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueOptions
+{
+    pub policy : QueuePolicy,
+    pub priority : Priority,
+    // Instead of options.text_rate
+    pub text_rate : Option<f32>,
+    // Instead of options.line_linger_time
+    pub line_linger_time : Option<f32>,
+    // Speaks until a line names someone else
+    pub talker : Option<String>,
+    // Reseeds random blocks when it starts
+    pub seed : Option<u64>,
+}
+
+// Waiting for what's playing to finish
+#[derive(Clone, Debug)]
+struct Queued
+{
+    dialogue : Dialogue,
+    options : QueueOptions,
 }
 
+// Result of moving the cursor on by one tick.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Running,
+    LineFinished,
+    // Waiting on a choice, jump or blocking custom command
+    Stalled,
+    Finished,
+}
+
+// Oldest events are dropped past this so a host that never drains doesn't grow forever.
+const MAX_QUEUED_EVENTS : usize = 1024;
+
 impl DialogueEngine {
+    // Same as queue_with at normal priority, replacing whatever is playing.
     pub fn queue(&mut self, dialogue : &Dialogue) {
-        if let Some(c) = self.cursor.as_ref() {
-            if c.dialogue_name_eq(dialogue) {
-                // Already queued
-                // Reduce clear time
-                self.line_linger_t /= 2.0;
+        self.queue_with(dialogue, &QueueOptions::default());
+    }
+
+    // Does nothing if the dialogue is already playing or waiting, use advance to hurry it
+    // along. Interrupted dialogue is dropped rather than picked up again later.
+    pub fn queue_with(&mut self, dialogue : &Dialogue, options : &QueueOptions) {
+        let current = match self.cursor.as_ref() {
+            Some(cursor) => cursor,
+            None => {
+                self.play_with(dialogue, options);
                 return;
+            },
+        };
+
+        if (current.dialogue_name_eq(dialogue) || self.queued.iter().any(|x| x.dialogue.name_eq(dialogue))) {
+            return;
+        }
+
+        let priority = options.priority;
+        let interrupt = match options.policy {
+            _ if priority > self.playback.priority => true,
+            QueuePolicy::Interrupt => priority == self.playback.priority,
+            QueuePolicy::Enqueue => false,
+            QueuePolicy::DropIfBusy => return,
+        };
+
+        if (interrupt) {
+            self.play_with(dialogue, options);
+        }
+        else {
+            let i = self.queued.iter().position(|x| x.options.priority < priority).unwrap_or(self.queued.len());
+            self.queued.insert(i, Queued { dialogue : dialogue.clone(), options : options.clone() });
+        }
+    }
+
+    // Unlike queue, always restarts even if the dialogue is already playing. Keeps the
+    // priority and rates of what was playing.
+    pub fn play(&mut self, dialogue : &Dialogue) {
+        let playback = QueueOptions {
+            talker : None,
+            seed : None,
+            ..self.playback.clone()
+        };
+        self.play_with(dialogue, &playback);
+    }
+
+    fn play_with(&mut self, dialogue : &Dialogue, options : &QueueOptions) {
+        self.stop();
+        self.playback = options.clone();
+        if let Some(seed) = options.seed {
+            self.variants.seed(seed);
+        }
+        self.cursor = Some(DialogueCursor::with_speaker(dialogue, options.talker.as_deref()));
+        self.collect_cursor_events();
+    }
+
+    fn text_rate(&self) -> f32 {
+        self.playback.text_rate.unwrap_or(self.options.text_rate)
+    }
+
+    fn line_linger_time(&self) -> f32 {
+        self.playback.line_linger_time.unwrap_or(self.options.line_linger_time)
+    }
+
+    pub fn choices(&self) -> Option<&[ChoiceOption]> {
+        self.cursor.as_ref()?.current_choice()
+    }
+
+    // Picks an option at the current choice point.
+    // Options without a target resume the current dialogue, otherwise the caller is
+    // responsible for looking up the target section and calling jump.
+    pub fn choose(&mut self, index : usize) -> Option<ChoiceOption> {
+        let option = self.choices()?.get(index)?.clone();
+
+        if (option.target.is_none()) {
+            if (!self.cursor.as_mut().unwrap().resume()) {
+                self.finish();
             }
+            self.collect_cursor_events();
         }
 
-        self.clear();
-        self.cursor = Some(DialogueCursor::new(dialogue));
+        Some(option)
+    }
+
+    pub fn current_talker(&self) -> Option<&Talker> {
+        self.cursor.as_ref()?.talker()
+    }
+
+    // Id from the #id: tag of the current line, eg to look up its voice over.
+    pub fn current_line_id(&self) -> Option<&str> {
+        self.cursor.as_ref()?.line_id()
+    }
+
+    pub fn current_filename(&self) -> Option<&str> {
+        Some(self.cursor.as_ref()?.filename())
+    }
+
+    // Goto or call the cursor is stopped on, the caller looks it up and passes it to jump.
+    pub fn pending_jump(&self) -> Option<&SectionTarget> {
+        self.cursor.as_ref()?.pending_jump()
+    }
+
+    // Blocking custom command the cursor is stopped on, see resume_custom_command.
+    pub fn blocking_command(&self) -> Option<&str> {
+        self.cursor.as_ref()?.blocking_command()
+    }
+
+    // Lets the text continue after a blocking custom command, returns false if nothing was waiting.
+    pub fn resume_custom_command(&mut self) -> bool {
+        self.cursor.as_mut().map(|x| x.resume_custom_command()).unwrap_or(false)
     }
 
+    pub fn jump(&mut self, dialogue : &Dialogue) {
+        if let Some(cursor) = self.cursor.as_mut() {
+            cursor.jump(dialogue);
+            self.annotated_string = cursor.get();
+            self.collect_cursor_events();
+        }
+    }
+
+    // Stops playback and forgets anything waiting to play.
     pub fn clear(&mut self) {
+        self.stop();
+        self.queued.clear();
+    }
+
+    fn stop(&mut self) {
         self.cursor = None;
+        self.playback = QueueOptions::default();
         self.annotated_string = Default::default();
         self.t = 0.0;
         self.line_linger_t = 0.0;
+        self.blip_counter = 0;
+        self.awaiting_input = false;
+        self.vo_rate = None;
+    }
+
+    pub fn snapshot(&self) -> EngineSnapshot {
+        EngineSnapshot {
+            cursor : self.cursor.as_ref().map(|x| x.snapshot()),
+            t : self.t,
+            line_linger_t : self.line_linger_t,
+            blip_counter : self.blip_counter,
+            awaiting_input : self.awaiting_input,
+            vo_rate : self.vo_rate,
+            variants : self.variants.snapshot(),
+            playback : self.playback.clone(),
+            queued : self.queued.iter().map(|x| QueuedSnapshot {
+                filename : x.dialogue.filename.clone(),
+                section : x.dialogue.name.clone(),
+                options : x.options.clone(),
+            }).collect(),
+        }
+    }
+
+    // Replaces whatever is playing. lookup finds a section by filename and name, eg from a
+    // DialogueCache. Queued events are dropped, they were for the old playback. Queued
+    // dialogue that's gone is dropped, if what was playing is gone the next one starts.
+    pub fn restore(&mut self, snapshot : &EngineSnapshot, lookup : &dyn Fn(&str, &str) -> Option<Dialogue>) -> RestoreOutcome {
+        self.clear();
+        self.events.clear();
+        self.variants = Variants::restore(&snapshot.variants);
+
+        let queued = snapshot.queued.iter().filter_map(|x| {
+            Some(Queued { dialogue : lookup(&x.filename, &x.section)?, options : x.options.clone() })
+        });
+
+        let cursor_snapshot = match &snapshot.cursor {
+            Some(cursor_snapshot) => cursor_snapshot,
+            None => return RestoreOutcome::Idle,
+        };
+
+        if let Some(cursor) = DialogueCursor::restore(cursor_snapshot, lookup) {
+            self.queued.extend(queued);
+            self.annotated_string = cursor.get();
+            self.cursor = Some(cursor);
+            self.playback = snapshot.playback.clone();
+            self.t = snapshot.t;
+            self.line_linger_t = snapshot.line_linger_t;
+            self.blip_counter = snapshot.blip_counter;
+            self.awaiting_input = snapshot.awaiting_input;
+            self.vo_rate = snapshot.vo_rate;
+            RestoreOutcome::Resumed
+        }
+        else if let Some(root) = lookup(&cursor_snapshot.root_filename, &cursor_snapshot.root_name) {
+            self.play_with(&root, &snapshot.playback);
+            self.queued.extend(queued);
+            RestoreOutcome::Restarted
+        }
+        else {
+            self.queued.extend(queued);
+            if let Some(next) = self.queued.pop_front() {
+                self.play_with(&next.dialogue, &next.options);
+            }
+            RestoreOutcome::Dropped
+        }
+    }
+
+    // Length of a clip in frames, used by vo_paced_reveal.
+    pub fn set_vo_duration(&mut self, clip : &str, frames : f32) {
+        self.vo_durations.insert(clip.to_ascii_lowercase(), frames);
+    }
+
+    // Clear because the dialogue is over, as opposed to clearing to start another.
+    fn dismiss(&mut self) {
+        self.stop();
+        self.push_event(DialogueEvent::Clear);
+
+        if let Some(next) = self.queued.pop_front() {
+            self.play_with(&next.dialogue, &next.options);
+        }
+    }
+
+    // Whether the engine is holding for advance, either at the end of a line or on
+    // the last line in wait for input mode.
+    pub fn awaiting_input(&self) -> bool {
+        self.awaiting_input || (self.options.wait_for_input && self.line_linger_t > 0.0)
+    }
+
+    fn stalled(&self) -> bool {
+        self.choices().is_some() || self.pending_jump().is_some() || self.blocking_command().is_some()
+    }
+
+    // One press of the continue button. Finishes revealing the current line, or if it's
+    // already shown moves on to the next, or clears if the dialogue is over.
+    // Returns false if there was nothing to do, eg the engine is waiting on a choice.
+    pub fn advance(&mut self, variables : &mut Variables) -> bool {
+        if (self.cursor.is_none()) {
+            false
+        }
+        else if (self.line_linger_t > 0.0) {
+            self.dismiss();
+            true
+        }
+        else if (self.awaiting_input) {
+            self.awaiting_input = false;
+
+            // Step off the newline now so advancing past the last line clears straight away
+            while (self.cursor.as_ref().unwrap().at_line_end()) {
+                match self.step(variables, false) {
+                    Step::Finished => {
+                        self.dismiss();
+                        return true;
+                    },
+                    Step::Stalled => break,
+                    _ => {},
+                }
+            }
+
+            self.annotated_string = self.cursor.as_ref().unwrap().get();
+            true
+        }
+        else {
+            self.complete_line(variables)
+        }
+    }
+
+    // Reveals the rest of the current line immediately, skipping waits but still running
+    // commands. Stops early at choices, jumps and blocking custom commands.
+    pub fn complete_line(&mut self, variables : &mut Variables) -> bool {
+        if (self.cursor.is_none() || self.line_linger_t > 0.0 || self.awaiting_input || self.stalled()) {
+            return false;
+        }
+
+        loop {
+            match self.step(variables, false) {
+                Step::Running => {},
+                Step::LineFinished => {
+                    self.awaiting_input = self.options.wait_for_input;
+                    break;
+                },
+                Step::Stalled | Step::Finished => break,
+            }
+        }
+
+        self.t = 0.0;
+        if let Some(cursor) = self.cursor.as_ref() {
+            self.annotated_string = cursor.get();
+        }
+        true
+    }
+
+    // Runs the rest of the current dialogue without showing it, then clears.
+    // Commands still run so variables end up as if it had played, but it stops at choices,
+    // jumps and blocking custom commands since those need the host.
+    pub fn skip_section(&mut self, variables : &mut Variables) -> bool {
+        if (self.cursor.is_none()) {
+            return false;
+        }
+
+        if (self.line_linger_t > 0.0) {
+            self.dismiss();
+            return true;
+        }
+
+        self.awaiting_input = false;
+        loop {
+            match self.step(variables, false) {
+                Step::Running | Step::LineFinished => {},
+                Step::Stalled => break,
+                Step::Finished => {
+                    self.dismiss();
+                    return true;
+                },
+            }
+        }
+
+        self.t = 0.0;
+        self.annotated_string = self.cursor.as_ref().unwrap().get();
+        true
+    }
+
+    // Move the cursor on one tick, with blips if the text is being revealed normally.
+    fn step(&mut self, variables : &mut Variables, blips : bool) -> Step {
+        if (self.stalled()) {
+            return Step::Stalled;
+        }
+
+        let cursor = self.cursor.as_mut().unwrap();
+        let running = cursor.incr(variables, &mut self.variants);
+        let revealed = cursor.just_revealed();
+        let line_finished = self.collect_cursor_events();
+
+        if (!running) {
+            self.finish();
+            return Step::Finished;
+        }
+
+        if let (true, Some(character)) = (blips, revealed) {
+            self.on_revealed(character);
+        }
+
+        if (line_finished) {
+            Step::LineFinished
+        }
+        else {
+            Step::Running
+        }
+    }
+
+    // Events since the last drain, oldest first. Call once per frame.
+    pub fn drain_events(&mut self) -> Vec<DialogueEvent> {
+        self.events.drain(..).collect()
+    }
+
+    pub fn pop_event(&mut self) -> Option<DialogueEvent> {
+        self.events.pop_front()
+    }
+
+    // Hands every queued event to the observer, oldest first, and empties the queue.
+    pub fn dispatch_events(&mut self, observer : &mut dyn DialogueObserver) {
+        while let Some(event) = self.events.pop_front() {
+            observer.on_event(&event);
+        }
+    }
+
+    // Returns whether a line finished.
+    fn collect_cursor_events(&mut self) -> bool {
+        let mut line_finished = false;
+        let events = match self.cursor.as_mut() {
+            Some(cursor) => cursor.take_events(),
+            None => return false,
+        };
+
+        for event in events {
+            match &event {
+                DialogueEvent::LineFinished | DialogueEvent::Clear => {
+                    line_finished |= event == DialogueEvent::LineFinished;
+                    self.vo_rate = None;
+                },
+                DialogueEvent::VoStarted { clip } => {
+                    self.vo_rate = self.vo_paced_rate(clip);
+                },
+                _ => {},
+            }
+            self.push_event(event);
+        }
+
+        line_finished
+    }
+
+    fn vo_paced_rate(&self, clip : &str) -> Option<f32> {
+        if (!self.options.vo_paced_reveal) {
+            return None;
+        }
+
+        let frames = *self.vo_durations.get(&clip.to_ascii_lowercase())?;
+        let ticks = self.cursor.as_ref()?.ticks_to_line_end();
+        if (frames > 0.0 && ticks > 0) {
+            Some(ticks as f32 / frames)
+        }
+        else {
+            None
+        }
+    }
+
+    // Dialogue ran out, linger on the last line before clearing.
+    fn finish(&mut self) {
+        // Bit hacky set to one second
+        self.line_linger_t = 1.0;
+
+        if let Some(cursor) = self.cursor.as_ref() {
+            let name = cursor.root_name().to_owned();
+            self.push_event(DialogueEvent::SectionFinished { name });
+        }
+    }
+
+    fn push_event(&mut self, event : DialogueEvent) {
+        if (self.events.len() >= MAX_QUEUED_EVENTS) {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn on_revealed(&mut self, character : char) {
+        if (self.options.blip_interval == 0 || character.is_whitespace() || character.is_ascii_punctuation()) {
+            return;
+        }
+
+        if (self.blip_counter.is_multiple_of(self.options.blip_interval)) {
+            let sound = self.current_talker().map(|x| x.sound.clone()).unwrap_or_default();
+            self.push_event(DialogueEvent::Blip { sound, character });
+        }
+
+        self.blip_counter += 1;
     }
 
     pub fn current_string_iter(&self) -> OwnedAnnotatedStringIterator {
         self.annotated_string.clone().owned_iter()
     }
 
-    pub fn tick(&mut self, dt_norm : f32) {
+    pub fn tick(&mut self, dt_norm : f32, variables : &mut Variables) {
         if (self.cursor.is_none()) {
             return;
         }
@@ -79,24 +656,591 @@ impl DialogueEngine {
             }
             */
 
-            if (self.line_linger_t > self.options.line_linger_time) {
-                self.clear();
+            if (!self.options.wait_for_input && self.line_linger_t > self.line_linger_time()) {
+                self.dismiss();
             }
 
             return;
         }
 
-        self.t += dt_norm * self.options.text_rate;
+        if (self.awaiting_input || self.stalled()) {
+            // Waiting on the host to call advance, choose, jump or resume_custom_command
+            self.annotated_string = self.cursor.as_ref().unwrap().get();
+            return;
+        }
+
+        let rate = match self.vo_rate {
+            Some(rate) => rate,
+            None => self.text_rate() * self.current_talker().and_then(|x| x.rate).unwrap_or(1.0),
+        };
+        self.t += dt_norm * rate;
 
         while (self.t > 1.0) {
             self.t -= 1.0;
-            if (!self.cursor.as_mut().unwrap().incr()) {
-                // Bit hacky set to one second
-                self.line_linger_t = 1.0;
-                break;
+            match self.step(variables, true) {
+                Step::Running => {},
+                Step::LineFinished => {
+                    if (self.options.wait_for_input) {
+                        self.awaiting_input = true;
+                        self.t = 0.0;
+                        break;
+                    }
+                },
+                Step::Stalled | Step::Finished => break,
             }
         }
 
         self.annotated_string = self.cursor.as_ref().unwrap().get();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::variables::Value;
+    use crate::custom_commands::CustomCommands;
+    use crate::dialogue::MAX_CALL_DEPTH;
+
+    #[test]
+    fn test_choice_stalls_until_chosen()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+hi
+> stay
+bye").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.queue(file.get("intro").unwrap());
+
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+        }
+
+        assert_eq!(engine.choices().unwrap().len(), 1);
+        assert_eq!(engine.current_string_iter().next().unwrap().0, "hi#");
+
+        let option = engine.choose(0).unwrap();
+        assert_eq!(option.text, "stay");
+        assert!(engine.choices().is_none());
+
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+        }
+
+        assert_eq!(engine.current_string_iter().next().unwrap().0, "hi#bye#");
+
+        // Choosing at the very end leaves nothing to pick while the text lingers
+        let file = DialogueFile::parse_contents("test", "[end]\nhi\n> ok").unwrap();
+        engine.queue(file.get("end").unwrap());
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+        }
+        engine.choose(0).unwrap();
+        assert!(engine.choices().is_none());
+    }
+
+    fn current_string(engine : &DialogueEngine) -> String {
+        let mut iter = engine.current_string_iter();
+        let mut s = String::new();
+        while let Some((x, _)) = iter.next() {
+            s.push_str(x);
+        }
+        s
+    }
+
+    // Ticks until the dialogue is done, returning every string shown along the way.
+    fn run(engine : &mut DialogueEngine, file : &DialogueFile, variables : &mut Variables) -> Vec<String> {
+        let mut shown : Vec<String> = vec![];
+        for _ in 0..200 {
+            engine.tick(1.0, variables);
+            if let Some(target) = engine.pending_jump().cloned() {
+                engine.jump(file.get(&target.section).unwrap());
+            }
+
+            let s = current_string(engine);
+            if (shown.last() != Some(&s)) {
+                shown.push(s);
+            }
+        }
+
+        shown
+    }
+
+    #[test]
+    fn test_goto_and_call()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+hi
+(call shared)
+back
+(goto outro)
+never
+
+[shared]
+shared line
+(return)
+never
+
+[outro]
+bye").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        engine.options.line_linger_time = 1000.0;
+        engine.queue(file.get("intro").unwrap());
+
+        let shown = run(&mut engine, &file, &mut Variables::default());
+        assert!(shown.contains(&"hi#".to_owned()));
+        assert!(shown.contains(&"shared line#".to_owned()));
+        assert!(shown.contains(&"back#".to_owned()));
+        assert_eq!(shown.last().unwrap(), "bye#");
+        assert!(!shown.iter().any(|x| x.contains("never")));
+
+        // Requeueing the original dialogue after following a goto doesn't restart it
+        engine.queue(file.get("intro").unwrap());
+        assert_eq!(current_string(&engine), "bye#");
+    }
+
+    #[test]
+    fn test_recursive_call()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+(call again)
+never
+
+[again]
+(call intro)").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        engine.options.line_linger_time = 1000.0;
+        engine.queue(file.get("intro").unwrap());
+
+        let shown = run(&mut engine, &file, &mut Variables::default());
+        assert_eq!(shown.last().unwrap(), &format!("Calls nested more than {} deep at again", MAX_CALL_DEPTH));
+        assert!(!shown.iter().any(|x| x.contains("never")));
+    }
+
+    #[test]
+    fn test_conditions()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+(if met_goose)
+hello again
+(else)
+(set met_goose)
+nice to meet you
+(endif)
+(set coins += 2)
+(if coins >= 3)
+(if !poor)
+rich
+(endif)
+(else)
+poor
+(endif)
+bye").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        variables.set("coins", Value::Number(2.0));
+
+        engine.options.line_linger_time = 1000.0;
+        engine.queue(file.get("intro").unwrap());
+        let shown = run(&mut engine, &file, &mut variables);
+        let last = shown.last().unwrap();
+        assert!(last.contains("nice to meet you"));
+        assert!(last.contains("rich"));
+        assert!(!last.contains("hello again"));
+        assert!(!last.contains("poor"));
+        assert_eq!(variables.get_number("coins"), 4.0);
+        assert!(variables.get("MET_GOOSE").unwrap().truthy());
+
+        engine.play(file.get("intro").unwrap());
+        let shown = run(&mut engine, &file, &mut variables);
+        let last = shown.last().unwrap();
+        assert!(last.contains("hello again"));
+        assert!(!last.contains("nice to meet you"));
+    }
+
+    #[test]
+    fn test_random_variants()
+    {
+        let file = DialogueFile::parse_contents("test", "[bark]
+(random cycle)
+beep
+(variant 2)
+boop
+(variant)
+(if loud)
+BOOP
+(endif)
+(endrandom)
+done").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        variables.set("loud", Value::Number(1.0));
+
+        let mut lasts = vec![];
+        for _ in 0..4 {
+            engine.play(file.get("bark").unwrap());
+            lasts.push(run(&mut engine, &file, &mut variables).into_iter().rev().find(|x| !x.is_empty()).unwrap());
+        }
+
+        assert_eq!(lasts, vec!["beep#done#", "boop#done#", "BOOP#done#", "beep#done#"]);
+    }
+
+    #[test]
+    fn test_talkers()
+    {
+        let file = DialogueFile::parse_contents("test", "[talker goose]
+sprite = spr_goose
+sound = snd_goose
+rate = 2
+
+[intro]
+goose | honk
+still the goose
+(speaker toad)
+ribbit").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.options.text_rate = 0.5;
+        engine.queue(file.get("intro").unwrap());
+
+        // At double rate the goose reveals a character every frame, after the first
+        for _ in 0..4 {
+            engine.tick(1.0, &mut variables);
+        }
+        let goose = engine.current_talker().unwrap();
+        assert_eq!((&goose.name[..], &goose.sprite[..], &goose.sound[..]), ("goose", "spr_goose", "snd_goose"));
+        assert_eq!(current_string(&engine), "hon");
+
+        let mut talkers = vec![];
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+            let name = engine.current_talker().map(|x| x.name.clone());
+            if (talkers.last() != Some(&name)) {
+                talkers.push(name);
+            }
+        }
+        assert_eq!(talkers, vec![Some("goose".to_owned()), Some("toad".to_owned())]);
+    }
+
+    #[test]
+    fn test_blips()
+    {
+        let file = DialogueFile::parse_contents("test", "[talker goose]
+sound = snd_goose
+
+[intro]
+goose | honk, honk!").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.queue(file.get("intro").unwrap());
+
+        let mut blips = vec![];
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+            for event in engine.drain_events() {
+                if let DialogueEvent::Blip { sound, character } = event {
+                    assert_eq!(sound, "snd_goose");
+                    blips.push(character);
+                }
+            }
+        }
+
+        // Every other letter, skipping the comma, space and exclamation mark
+        assert_eq!(blips.into_iter().collect::<String>(), "hnhn");
+    }
+
+    #[test]
+    fn test_events()
+    {
+        let file = DialogueFile::parse_contents("test", "[talker goose]
+
+[intro]
+goose | (j) hi (/j)
+(wait 2)
+(speaker toad)
+(clear)
+ok").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.options.blip_interval = 0;
+        engine.options.line_linger_time = 10.0;
+        engine.queue(file.get("intro").unwrap());
+
+        let mut names = vec![];
+        let mut observer = |event : &DialogueEvent| names.push(format!("{}:{}", event.name(), event.arg()));
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+            engine.dispatch_events(&mut observer);
+        }
+
+        assert_eq!(names, vec![
+            "speaker_changed:goose",
+            "line_started:goose",
+            "annotation_opened:jiggly",
+            "annotation_closed:jiggly",
+            "line_finished:",
+            "wait_started:2",
+            "wait_ended:",
+            "speaker_changed:toad",
+            "clear:",
+            "line_started:toad",
+            "line_finished:",
+            "section_finished:intro",
+            "clear:",
+        ]);
+    }
+
+    #[test]
+    fn test_custom_commands()
+    {
+        let mut custom_commands = CustomCommands::default();
+        custom_commands.register("shake", false);
+        custom_commands.register("give_item", true);
+
+        let file = DialogueFile::parse_contents_with("test", "[intro]
+look (shake 3 hard) out
+(give_item toad)
+thanks", &custom_commands).unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.queue(file.get("intro").unwrap());
+
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+        }
+
+        // Stalled on the give_item until the host is done with it
+        assert_eq!(engine.blocking_command(), Some("give_item"));
+        let customs = engine.drain_events().into_iter().filter(|x| x.name() == "custom").map(|x| x.arg()).collect::<Vec<_>>();
+        assert_eq!(customs, vec!["shake 3 hard", "give_item toad"]);
+        assert_eq!(current_string(&engine), "lookout#");
+
+        assert!(engine.resume_custom_command());
+        assert!(!engine.resume_custom_command());
+        run(&mut engine, &file, &mut variables);
+        assert!(engine.blocking_command().is_none());
+        assert!(engine.drain_events().iter().any(|x| matches!(x, DialogueEvent::SectionFinished { .. })));
+
+        // Typos are errors unless the game asks for everything to pass through
+        assert!(DialogueFile::parse_contents("test", "[intro]\n(wiat 3)").is_err());
+        let file = DialogueFile::parse_contents_with("test", "[intro]\n(wiat 3)", &CustomCommands::pass_through()).unwrap();
+        assert!(matches!(&file.get("intro").unwrap().chunks[0], Chunk::Command(Command::Custom { blocking : false, .. })));
+    }
+
+    #[test]
+    fn test_advance()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+hello there
+(wait 5s)
+(set heard)
+general").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.options.wait_for_input = true;
+        engine.queue(file.get("intro").unwrap());
+
+        engine.tick(2.0, &mut variables);
+        assert_eq!(current_string(&engine), "h");
+
+        // First press shows the whole line and holds there
+        assert!(engine.advance(&mut variables));
+        assert_eq!(current_string(&engine), "hello there#");
+        assert!(engine.awaiting_input());
+        for _ in 0..100 {
+            engine.tick(1.0, &mut variables);
+        }
+        assert_eq!(current_string(&engine), "hello there#");
+
+        // Second press moves on, the next line skips the wait but still runs the set
+        assert!(engine.advance(&mut variables));
+        assert!(!engine.awaiting_input());
+        assert!(engine.advance(&mut variables));
+        assert_eq!(current_string(&engine), "hello there#general#");
+        assert_eq!(variables.get_number("heard"), 1.0);
+
+        // Advancing off the last line clears straight away, without a linger
+        assert!(engine.advance(&mut variables));
+        assert_eq!(current_string(&engine), "");
+        assert!(!engine.advance(&mut variables));
+        let events = engine.drain_events();
+        assert!(events.iter().any(|x| matches!(x, DialogueEvent::SectionFinished { .. })));
+        assert_eq!(events.last(), Some(&DialogueEvent::Clear));
+    }
+
+    #[test]
+    fn test_skip_section()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+a very long line
+(set count += 1)
+> stop here
+another line").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.queue(file.get("intro").unwrap());
+
+        // Stops at the choice since the player has to answer it
+        assert!(engine.skip_section(&mut variables));
+        assert!(engine.choices().is_some());
+        assert_eq!(variables.get_number("count"), 1.0);
+        assert!(engine.drain_events().iter().all(|x| !matches!(x, DialogueEvent::Blip { .. })));
+
+        engine.choose(0);
+        assert!(engine.skip_section(&mut variables));
+        assert_eq!(current_string(&engine), "");
+        assert!(!engine.skip_section(&mut variables));
+    }
+
+    #[test]
+    fn test_queue_policies()
+    {
+        let file = DialogueFile::parse_contents("test", "[story]
+once upon a time
+[honk]
+honk
+[quack]
+quack
+[ending]
+the end").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.options.text_rate = 100.0;
+        let with = |policy, priority| QueueOptions { policy, priority, ..Default::default() };
+        let shown = |engine : &mut DialogueEngine| {
+            engine.tick(1.0, &mut Variables::default());
+            current_string(engine)
+        };
+
+        // Barks don't cut off the story but it can cut off barks
+        engine.queue_with(file.get("honk").unwrap(), &with(QueuePolicy::Interrupt, Priority::Low));
+        engine.queue_with(file.get("story").unwrap(), &with(QueuePolicy::DropIfBusy, Priority::Normal));
+        assert_eq!(shown(&mut engine), "once upon a time#");
+        engine.queue_with(file.get("quack").unwrap(), &with(QueuePolicy::DropIfBusy, Priority::Low));
+        engine.queue_with(file.get("honk").unwrap(), &with(QueuePolicy::Interrupt, Priority::Low));
+        engine.queue_with(file.get("ending").unwrap(), &with(QueuePolicy::Enqueue, Priority::Normal));
+        engine.queue_with(file.get("ending").unwrap(), &with(QueuePolicy::Enqueue, Priority::Normal));
+        assert_eq!(shown(&mut engine), "once upon a time#");
+
+        // Waiting ones play most important first, each once
+        engine.skip_section(&mut variables);
+        assert_eq!(shown(&mut engine), "the end#");
+        engine.skip_section(&mut variables);
+        assert_eq!(shown(&mut engine), "honk#");
+        engine.skip_section(&mut variables);
+        assert_eq!(shown(&mut engine), "");
+
+        // Same priority interrupts by default, clear forgets what's waiting
+        engine.queue(file.get("story").unwrap());
+        engine.queue_with(file.get("honk").unwrap(), &with(QueuePolicy::Enqueue, Priority::Normal));
+        engine.queue(file.get("ending").unwrap());
+        assert_eq!(shown(&mut engine), "the end#");
+        engine.clear();
+        engine.skip_section(&mut variables);
+        assert_eq!(shown(&mut engine), "");
+    }
+
+    #[test]
+    fn test_queue_overrides()
+    {
+        let file = DialogueFile::parse_contents("test", "[talker goose]
+sound = snd_goose
+
+[bark]
+honk
+toad | ribbit
+[story]
+once").unwrap();
+
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        engine.options.text_rate = 1.0;
+        engine.options.line_linger_time = 100.0;
+
+        // Ticks until the text is out and the engine has cleared
+        let mut frames_to_finish = |engine : &mut DialogueEngine| {
+            let mut frames = 0;
+            while (engine.current_filename().is_some()) {
+                engine.tick(1.0, &mut variables);
+                frames += 1;
+            }
+            frames
+        };
+
+        engine.queue_with(file.get("bark").unwrap(), &QueueOptions {
+            text_rate : Some(10.0),
+            line_linger_time : Some(5.0),
+            talker : Some("goose".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(engine.current_talker().map(|x| &x.sound[..]), Some("snd_goose"));
+        let events = engine.drain_events();
+        assert_eq!(events[0], DialogueEvent::SpeakerChanged { name : "goose".to_owned() });
+        assert_eq!(events[1], DialogueEvent::LineStarted { talker : Some("goose".to_owned()) });
+        assert!(frames_to_finish(&mut engine) < 10);
+
+        // Back to the engine's own options
+        engine.queue(file.get("story").unwrap());
+        assert!(engine.current_talker().is_none());
+        assert!(frames_to_finish(&mut engine) > 100);
+    }
+
+    #[test]
+    fn test_vo_paced_reveal()
+    {
+        let file = DialogueFile::parse_contents("test", "[intro]
+(vo goose_hello) twenty characters!!
+ten more..").unwrap();
+
+        let reveal_frames = |paced : bool| {
+            let mut engine = DialogueEngine::default();
+            let mut variables = Variables::default();
+            engine.options.vo_paced_reveal = paced;
+            engine.options.blip_interval = 0;
+            engine.set_vo_duration("goose_hello", 100.0);
+            engine.queue(file.get("intro").unwrap());
+
+            let mut vo = vec![];
+            let mut frames : u32 = 0;
+            while (!current_string(&engine).contains("twenty characters!!#")) {
+                engine.tick(1.0, &mut variables);
+                vo.extend(engine.drain_events().into_iter().filter(|x| x.name() == "vo_started").map(|x| x.arg()));
+
+                // Count from when the clip starts
+                if (!vo.is_empty()) {
+                    frames += 1;
+                }
+            }
+            assert_eq!(vo, vec!["goose_hello"]);
+
+            // Back to text_rate after the line
+            let start = frames;
+            while (!current_string(&engine).contains("ten more..#")) {
+                engine.tick(1.0, &mut variables);
+                frames += 1;
+            }
+            (start, frames - start)
+        };
+
+        let (line, next) = reveal_frames(false);
+        assert!(line < 40, "{}", line);
+
+        // Text ends with the clip, give or take a frame
+        let (paced_line, paced_next) = reveal_frames(true);
+        assert!((99..=102).contains(&paced_line), "{}", paced_line);
+        assert!(paced_next.abs_diff(next) <= 1, "{} {}", paced_next, next);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::dialogue::Annotation;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DialogueEvent {
+    // A character was revealed that should make a typing sound, sound comes from the current talker.
+    Blip { sound : String, character : char },
+    LineStarted { talker : Option<String> },
+    LineFinished,
+    // The queued dialogue ran out, name is the section that was queued.
+    SectionFinished { name : String },
+    Clear,
+    WaitStarted { frames : u32 },
+    WaitEnded,
+    SpeakerChanged { name : String },
+    AnnotationOpened(Annotation),
+    AnnotationClosed(Annotation),
+    // Reached a vo command, the host plays the clip
+    VoStarted { clip : String },
+    // A command the game handles, if blocking the cursor waits for DialogueEngine::resume_custom_command.
+    Custom { name : String, args : Vec<String>, blocking : bool },
+}
+
+impl DialogueEvent {
+    // Short name for hosts that can't match on the enum, eg GameMaker.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DialogueEvent::Blip { .. } => "blip",
+            DialogueEvent::LineStarted { .. } => "line_started",
+            DialogueEvent::LineFinished => "line_finished",
+            DialogueEvent::SectionFinished { .. } => "section_finished",
+            DialogueEvent::Clear => "clear",
+            DialogueEvent::WaitStarted { .. } => "wait_started",
+            DialogueEvent::WaitEnded => "wait_ended",
+            DialogueEvent::SpeakerChanged { .. } => "speaker_changed",
+            DialogueEvent::AnnotationOpened(_) => "annotation_opened",
+            DialogueEvent::AnnotationClosed(_) => "annotation_closed",
+            DialogueEvent::VoStarted { .. } => "vo_started",
+            DialogueEvent::Custom { .. } => "custom",
+        }
+    }
+
+    pub fn arg(&self) -> String {
+        match self {
+            DialogueEvent::Blip { sound, .. } => sound.clone(),
+            DialogueEvent::LineStarted { talker } => talker.clone().unwrap_or_default(),
+            DialogueEvent::SectionFinished { name } => name.clone(),
+            DialogueEvent::WaitStarted { frames } => frames.to_string(),
+            DialogueEvent::SpeakerChanged { name } => name.clone(),
+            DialogueEvent::VoStarted { clip } => clip.clone(),
+            DialogueEvent::AnnotationOpened(annotation) | DialogueEvent::AnnotationClosed(annotation) => annotation.name().to_owned(),
+            DialogueEvent::Custom { name, args, .. } => std::iter::once(name).chain(args.iter()).cloned().collect::<Vec<_>>().join(" "),
+            DialogueEvent::LineFinished | DialogueEvent::Clear | DialogueEvent::WaitEnded => String::new(),
+        }
+    }
+}
+
+// Alternative to draining, see DialogueEngine::dispatch_events.
+pub trait DialogueObserver {
+    fn on_event(&mut self, event : &DialogueEvent);
+}
+
+impl<F : FnMut(&DialogueEvent)> DialogueObserver for F {
+    fn on_event(&mut self, event : &DialogueEvent) {
+        self(event)
+    }
+}
@@ -1,19 +1,58 @@
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::raw::c_char;
 
-use crate::dialogue_engine::DialogueEngine;
-use crate::dialogue::{Dialogue, DialogueCache};
+use crate::dialogue_engine::{DialogueEngine, RestoreOutcome};
+use crate::event::DialogueEvent;
+use crate::dialogue::{self, ChoiceOption, Dialogue, DialogueCache, SectionTarget};
 use crate::interop::iter_wrapper::IterWrapper;
 use crate::interop::queue_params::QueueParams;
+use crate::seen::SeenKey;
+use crate::snapshot::{SeenSnapshot, Snapshot, SnapshotError};
+use crate::variables::Variables;
 
+// Name of the instance queues go to unless they say otherwise
+pub const MAIN_INSTANCE : &str = "";
+
+// Playback of one conversation. Instances share files, variables and seen lines but each has
+// its own cursor and options, so two NPCs can bark at once.
+#[derive(Default)]
+pub struct EngineInstance
+{
+    pub engine : DialogueEngine,
+    pub iter_wrapper : Option<IterWrapper>,
+    // Event last popped by next_event
+    pub current_event : Option<DialogueEvent>,
+}
 
 #[derive(Default)]
 pub struct GlobalState
 {
     pub path : String,
-    pub engine : DialogueEngine,
+    pub main : EngineInstance,
+    // The rest by lowercased name, ordered so they always tick in the same order
+    pub instances : BTreeMap<String, EngineInstance>,
     pub cache : DialogueCache,
-    pub one_shot_cache : HashSet<FilenameSectionPair>,
-    pub iter_wrapper : Option<IterWrapper>,
+    // Also holds the seen lines, which oneshots go by
+    pub variables : Variables,
+    // Keeps the last string handed out over FFI alive until the next one
+    pub return_c_string : Option<CString>,
+    pub hot_reload : HotReload,
+}
+
+// Polls the cached files for changes every so many ticks, for dev builds where writers
+// edit text while the game runs. Off by default.
+#[derive(Default)]
+pub struct HotReload
+{
+    // In ticks, 0 is off
+    pub interval : f32,
+    // Start a playing conversation over if its sections changed, otherwise it carries on
+    // with the text it had
+    pub restart : bool,
+    t : f32,
+    // Changed since the host last asked
+    reloaded : Vec<String>,
 }
 
 impl GlobalState
@@ -22,62 +61,543 @@ impl GlobalState
         self.path.clone() + filename + ".adlib"
     }
 
+    // filename without the base path. Some keys are lowercased whole, so the path is
+    // matched ignoring case.
+    fn relative_filename<'f>(&self, filename : &'f str) -> &'f str {
+        match filename.get(..self.path.len()) {
+            Some(prefix) if unicase::eq_ascii(prefix, &self.path) => &filename[self.path.len()..],
+            _ => filename,
+        }
+    }
+
+    // The host copies returned strings straight away, so one buffer is enough.
+    pub fn return_string(&mut self, s : &str) -> *const c_char {
+        self.return_c_string = Some(CString::new(s).unwrap_or_default());
+        self.return_c_string.as_ref().unwrap().as_ptr()
+    }
+
     pub fn preload(&mut self, filename : &str) {
         self.cache.preload(&self.full_filename(filename));
     }
 
+    // Loads filename and returns the full filename to read section from, see
+    // DialogueCache::resolve_section_file.
+    fn section_file(&mut self, filename : &str, section : &str) -> String {
+        self.preload(filename);
+        let section_file = self.cache.resolve_section_file(&self.full_filename(filename), section);
+        self.cache.preload(&section_file);
+        section_file
+    }
+
+    // How many times a section has started, or with a line how many times that line has.
+    // Lines are their #id: tag or their 1-based number in the section.
+    pub fn seen_count(&self, filename : &str, section : &str, line : Option<&str>) -> u32 {
+        self.variables.seen.count(&SeenKey::new(&self.full_filename(filename), section, line))
+    }
+
+    // Lines of a section that have never been shown, eg to mark an NPC as having something
+    // new to say. Empty if there's no such section.
+    pub fn unseen_lines(&mut self, filename : &str, section : &str) -> Vec<String> {
+        let section_file = self.section_file(filename, section);
+        let dialogue = match self.cache.get(&section_file).and_then(|x| x.get(section)) {
+            Some(dialogue) => dialogue,
+            None => return vec![],
+        };
+
+        dialogue.line_keys().into_iter()
+            .filter(|line| self.variables.seen.count(&SeenKey::new(&dialogue.base_filename, section, Some(line))) == 0)
+            .collect()
+    }
+
+    // Seen counts on their own, to keep them across save slots or new games. Filenames are
+    // relative to the base path like in save.
+    pub fn export_seen(&self) -> String {
+        let mut snapshot = SeenSnapshot {
+            seen : self.variables.seen.sorted(),
+        };
+        snapshot.map_filenames(&|x| self.relative_filename(x).to_owned());
+        snapshot.to_string()
+    }
+
+    // Replaces every seen count with the exported ones.
+    pub fn import_seen(&mut self, exported : &str) -> Result<(), SnapshotError> {
+        let mut snapshot = SeenSnapshot::parse(exported)?;
+        snapshot.map_filenames(&|x| self.path.clone() + x);
+
+        self.variables.seen.clear();
+        for (key, count) in snapshot.seen {
+            self.variables.seen.set(key, count);
+        }
+        Ok(())
+    }
+
+    pub fn instance(&self, name : &str) -> Option<&EngineInstance> {
+        if (name.is_empty()) {
+            Some(&self.main)
+        }
+        else {
+            self.instances.get(&name.to_ascii_lowercase())
+        }
+    }
+
+    pub fn instance_mut(&mut self, name : &str) -> Option<&mut EngineInstance> {
+        if (name.is_empty()) {
+            Some(&mut self.main)
+        }
+        else {
+            self.instances.get_mut(&name.to_ascii_lowercase())
+        }
+    }
+
+    // Finds name's instance, making it if there isn't one. New instances start with the main
+    // instance's options and their own random seed.
+    pub fn create_instance(&mut self, name : &str) -> &mut EngineInstance {
+        if (name.is_empty()) {
+            return &mut self.main;
+        }
+
+        let options = &self.main.engine.options;
+        let key = name.to_ascii_lowercase();
+        let seed = dialogue::fnv1a(key.as_bytes());
+        self.instances.entry(key).or_insert_with(|| {
+            let mut instance = EngineInstance::default();
+            instance.engine.options = options.clone();
+            instance.engine.variants.seed(seed);
+            instance
+        })
+    }
+
+    // Stops and forgets an instance, returns false if there was none. The main one can't go.
+    pub fn destroy_instance(&mut self, name : &str) -> bool {
+        !name.is_empty() && self.instances.remove(&name.to_ascii_lowercase()).is_some()
+    }
+
+    // Runs f on an instance's engine along with the state they share, None if there's no
+    // such instance.
+    fn with_engine<T>(&mut self, name : &str, f : impl FnOnce(&mut DialogueEngine, &mut DialogueCache, &mut Variables) -> T) -> Option<T> {
+        let instance = if (name.is_empty()) {
+            &mut self.main
+        }
+        else {
+            self.instances.get_mut(&name.to_ascii_lowercase())?
+        };
+
+        Some(f(&mut instance.engine, &mut self.cache, &mut self.variables))
+    }
 }
 
-impl<'a> GlobalState
-{
-    pub fn queue(&mut self, queue_args: QueueParams<'a>) {
-        self.preload(queue_args.filename);
+// Follow gotos and calls, bounded so a loop of jumps with no text can't hang a frame.
+const MAX_JUMPS_PER_TICK : usize = 16;
 
-        if (queue_args.oneshot) {
-            let cache_key = FilenameSectionPair  {
-                filename: queue_args.filename.to_owned(),
-                section: queue_args.section.to_owned(),
-            };
+fn resolve_jumps(engine : &mut DialogueEngine, cache : &mut DialogueCache) {
+    for _ in 0..MAX_JUMPS_PER_TICK {
+        match engine.pending_jump().cloned() {
+            Some(target) => jump(engine, cache, &target),
+            None => break,
+        }
+    }
+}
+
+fn jump(engine : &mut DialogueEngine, cache : &mut DialogueCache, target : &SectionTarget) {
+    let filename = match engine.current_filename() {
+        Some(filename) => target.resolve_filename(cache.base_filename(filename)),
+        None => return,
+    };
+
+    let filename = cache.resolve_section_file(&filename, &target.section);
+    cache.preload(&filename);
 
-            if (!self.one_shot_cache.insert(cache_key))
-            {
-                return;
+    if let Some(dialogue) = cache.get(&filename).and_then(|x| x.get(&target.section)) {
+        engine.jump(dialogue);
+    }
+    else if let Some(errors) = cache.get_errors(&filename) {
+        engine.play(&Dialogue::from_parse_errors(errors));
+    }
+    else {
+        engine.play(&Dialogue::from_error(&format!("No section {}", target.section)));
+    }
+}
+
+// These act on the named instance and do nothing if there isn't one, except tick which
+// runs them all.
+impl GlobalState
+{
+    pub fn tick(&mut self, dt_norm : f32) {
+        if (self.hot_reload.interval > 0.0) {
+            self.hot_reload.t += dt_norm;
+            if (self.hot_reload.t >= self.hot_reload.interval) {
+                self.hot_reload.t = 0.0;
+                let changed = self.reload(self.hot_reload.restart);
+                self.hot_reload.reloaded.extend(changed);
             }
         }
 
-        if let Some(dialogue_file) = self.cache.get(&self.full_filename(queue_args.filename)) {
-            if let Some(dialogue) = dialogue_file.get(queue_args.section) {
-                self.engine.queue(dialogue);
+        for instance in std::iter::once(&mut self.main).chain(self.instances.values_mut()) {
+            instance.engine.tick(dt_norm, &mut self.variables);
+            resolve_jumps(&mut instance.engine, &mut self.cache);
+        }
+    }
+
+    pub fn advance(&mut self, instance : &str) -> bool {
+        self.with_engine(instance, |engine, cache, variables| {
+            let advanced = engine.advance(variables);
+            resolve_jumps(engine, cache);
+            advanced
+        }).unwrap_or(false)
+    }
+
+    pub fn complete_line(&mut self, instance : &str) -> bool {
+        self.with_engine(instance, |engine, cache, variables| {
+            let completed = engine.complete_line(variables);
+            resolve_jumps(engine, cache);
+            completed
+        }).unwrap_or(false)
+    }
+
+    // Skipping follows gotos and calls too, so the whole conversation goes.
+    pub fn skip_section(&mut self, instance : &str) -> bool {
+        self.with_engine(instance, |engine, cache, variables| {
+            let mut skipped = false;
+            for _ in 0..MAX_JUMPS_PER_TICK {
+                skipped |= engine.skip_section(variables);
+
+                match engine.pending_jump().cloned() {
+                    Some(target) => jump(engine, cache, &target),
+                    None => break,
+                }
             }
-            else {
-                self.engine.queue(&Dialogue::from_error(&format!("No section {}", queue_args.section)));
+
+            skipped
+        }).unwrap_or(false)
+    }
+
+    pub fn choose(&mut self, instance : &str, index : usize) {
+        self.with_engine(instance, |engine, cache, _| {
+            if let Some(ChoiceOption { target : Some(target), .. }) = engine.choose(index) {
+                jump(engine, cache, &target);
+            }
+        });
+    }
+}
+
+impl GlobalState
+{
+    // Reparses changed files and returns their names as the host queues them. With restart,
+    // a conversation playing a changed section starts over from the new text, and stops if
+    // the section is gone. Otherwise it finishes with the old text.
+    pub fn reload(&mut self, restart : bool) -> Vec<String> {
+        let changed = self.cache.reload_changed();
+
+        if (restart) {
+            for instance in std::iter::once(&mut self.main).chain(self.instances.values_mut()) {
+                let snapshot = instance.engine.snapshot();
+                let playing_changed = snapshot.cursor.as_ref().map(|cursor| {
+                    changed.contains(&cursor.root_filename) ||
+                        changed.contains(&cursor.dialogue.filename) ||
+                        cursor.call_stack.iter().any(|(caller, _)| changed.contains(&caller.filename))
+                }).unwrap_or(false) || snapshot.queued.iter().any(|x| changed.contains(&x.filename));
+
+                if (playing_changed) {
+                    // Restoring in place keeps whatever didn't change
+                    let cache = &self.cache;
+                    let lookup = |filename : &str, section : &str| cache.get(filename).and_then(|x| x.get(section)).cloned();
+                    instance.engine.restore(&snapshot, &lookup);
+                }
             }
         }
-        else {
-            self.engine.queue(&Dialogue::from_error(&format!("No file {}", queue_args.filename)));
+
+        changed.iter()
+            .map(|x| self.relative_filename(x))
+            .map(|x| x.strip_suffix(".adlib").unwrap_or(x).to_owned())
+            .collect()
+    }
+
+    // Files the hot reload picked up since the last call.
+    pub fn take_reloaded(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.hot_reload.reloaded)
+    }
+
+    // Filenames are saved relative to the base path so saves survive the game moving. Only
+    // the main instance's playback is kept, the others are for barks.
+    pub fn save(&self) -> String {
+        let mut snapshot = Snapshot {
+            engine : self.main.engine.snapshot(),
+            variables : self.variables.iter().map(|(k, v)| (k.to_owned(), v.clone())).collect(),
+            seen : self.variables.seen.sorted(),
+        };
+        snapshot.map_filenames(&|x| self.relative_filename(x).to_owned());
+
+        snapshot.to_string()
+    }
+
+    // Replaces variables, seen lines and playback. If a section that was playing has changed
+    // since the save its conversation starts again, if it's gone nothing plays. Other
+    // instances stop.
+    pub fn restore(&mut self, saved : &str) -> Result<RestoreOutcome, SnapshotError> {
+        let mut snapshot = Snapshot::parse(saved)?;
+        snapshot.map_filenames(&|x| self.path.clone() + x);
+
+        self.variables.clear();
+        for (name, value) in snapshot.variables {
+            self.variables.set(&name, value);
+        }
+
+        self.variables.seen.clear();
+        for (key, count) in snapshot.seen {
+            self.variables.seen.set(key, count);
+        }
+
+        // Preload first so lookup can stay a plain Fn over the cache
+        if let Some(cursor) = &snapshot.engine.cursor {
+            self.cache.preload(&cursor.root_filename);
+            self.cache.preload(&cursor.dialogue.filename);
+            for (caller, _) in &cursor.call_stack {
+                self.cache.preload(&caller.filename);
+            }
+        }
+        for queued in &snapshot.engine.queued {
+            self.cache.preload(&queued.filename);
         }
+
+        for instance in self.instances.values_mut() {
+            instance.engine.clear();
+            instance.engine.drain_events();
+        }
+
+        let cache = &self.cache;
+        let lookup = |filename : &str, section : &str| cache.get(filename).and_then(|x| x.get(section)).cloned();
+        Ok(self.main.engine.restore(&snapshot.engine, &lookup))
     }
 }
 
-#[derive(Debug)]
-pub struct FilenameSectionPair
+impl<'a> GlobalState
 {
-    filename: String,
-    section : String,
-}
+    // Goes to the main instance unless the params name another, which is made if need be.
+    // See DialogueEngine::queue_with for what happens if it's busy. Oneshots are dropped once
+    // their section has been seen.
+    pub fn queue(&mut self, queue_args: QueueParams<'a>) {
+        let filename = self.section_file(queue_args.filename, queue_args.section);
+
+        if (queue_args.oneshot && self.seen_count(queue_args.filename, queue_args.section, None) > 0) {
+            return;
+        }
+
+        let instance = queue_args.instance.unwrap_or(MAIN_INSTANCE);
+        self.create_instance(instance);
+        self.with_engine(instance, |engine, cache, _| {
+            let queue = |engine : &mut DialogueEngine, dialogue : &Dialogue| engine.queue_with(dialogue, &queue_args.options);
 
-impl PartialEq for FilenameSectionPair {
-    fn eq(&self, other : &Self) -> bool {
-        unicase::eq_ascii(&self.filename, &other.filename) && 
-            unicase::eq_ascii(&self.section, &other.section)
+            if let Some(dialogue_file) = cache.get(&filename) {
+                if let Some(dialogue) = dialogue_file.get(queue_args.section) {
+                    queue(engine, dialogue);
+                }
+                else {
+                    queue(engine, &Dialogue::from_error(&format!("No section {}", queue_args.section)));
+                }
+            }
+            else if let Some(errors) = cache.get_errors(&filename) {
+                queue(engine, &Dialogue::from_parse_errors(errors));
+            }
+            else {
+                queue(engine, &Dialogue::from_error(&format!("No file {}", queue_args.filename)));
+            }
+        });
     }
 }
 
-impl Eq for FilenameSectionPair {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variables::Value;
+
+    fn text(instance : &EngineInstance) -> String {
+        let mut text = String::new();
+        let mut iter = instance.engine.current_string_iter();
+        while let Some((span, _)) = iter.next() {
+            text.push_str(span);
+        }
+        text
+    }
+
+    #[test]
+    fn test_instances()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_instances");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("barks.adlib"), "[honk]\nhonk (set honks += 1)\n[story]\nonce upon a time\n> go on -> story").unwrap();
+
+        let mut state = GlobalState {
+            path : dir.to_string_lossy().into_owned() + "/",
+            ..Default::default()
+        };
+        state.main.engine.options.text_rate = 100.0;
+
+        state.queue(QueueParams::parse("barks|story").unwrap());
+        state.queue(QueueParams::parse("barks|honk|instance=Goose").unwrap());
+        state.queue(QueueParams::parse("barks|honk|instance=toad").unwrap());
+        assert_eq!(state.instances.keys().collect::<Vec<_>>(), vec!["goose", "toad"]);
+        assert_eq!(state.instance("GOOSE").unwrap().engine.options.text_rate, 100.0);
+
+        state.tick(1.0);
+        assert_eq!(text(&state.main), "once upon a time#");
+        assert_eq!(text(state.instance("goose").unwrap()), "honk#");
+        assert_eq!(text(state.instance("toad").unwrap()), "honk#");
+        assert_eq!(state.variables.get("honks"), Some(&Value::Number(2.0)));
+
+        // Choices and skipping only touch the named instance
+        assert!(state.instance("goose").unwrap().engine.choices().is_none());
+        assert!(state.skip_section("goose"));
+        assert!(!state.skip_section("nobody"));
+        assert_eq!(state.main.engine.choices().map(|x| x.len()), Some(1));
+        assert!(state.instance("toad").unwrap().engine.current_filename().is_some());
+
+        // Restoring stops the barks but keeps the instances
+        let saved = state.save();
+        state.restore(&saved).unwrap();
+        assert!(state.instance("toad").unwrap().engine.current_filename().is_none());
+        assert_eq!(state.main.engine.choices().map(|x| x.len()), Some(1));
+
+        assert!(state.destroy_instance("Toad"));
+        assert!(!state.destroy_instance(MAIN_INSTANCE));
+        assert!(state.instance("toad").is_none());
+    }
+
+    #[test]
+    fn test_seen()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_seen");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("npc.adlib"), "[greet]\nhello #id:hello\n(if seen greet >= 2)\nback again\n(else)\nnice to meet you\n(endif)").unwrap();
+
+        let mut state = GlobalState {
+            path : dir.to_string_lossy().into_owned() + "/",
+            ..Default::default()
+        };
+        state.main.engine.options.text_rate = 100.0;
+        let play = |state : &mut GlobalState, params : &str| {
+            state.queue(QueueParams::parse(params).unwrap());
+            for _ in 0..1000 {
+                state.tick(1.0);
+            }
+            assert!(state.main.engine.current_filename().is_none());
+        };
+
+        assert_eq!(state.unseen_lines("npc", "greet"), vec!["hello", "2", "3"]);
+        play(&mut state, "npc|greet|oneshot");
+        assert_eq!(state.seen_count("npc", "GREET", None), 1);
+        assert_eq!(state.seen_count("npc", "greet", Some("hello")), 1);
+        assert_eq!(state.unseen_lines("npc", "greet"), vec!["2"]);
+
+        // Seen once already, so the oneshot doesn't play but a plain queue does
+        play(&mut state, "npc|greet|oneshot");
+        assert_eq!(state.seen_count("npc", "greet", None), 1);
+        play(&mut state, "npc|greet");
+        assert_eq!(state.seen_count("npc", "greet", Some("hello")), 2);
+        assert!(state.unseen_lines("npc", "greet").is_empty());
+
+        // Saves carry them and so does the export on its own, which is relative to the path
+        let saved = state.save();
+        let exported = state.export_seen();
+        assert!(exported.contains("\nseen npc.adlib greet hello 2\n"));
+
+        let mut other = GlobalState {
+            path : state.path.clone(),
+            ..Default::default()
+        };
+        other.import_seen(&exported).unwrap();
+        assert_eq!(other.seen_count("npc", "greet", Some("3")), 1);
+        assert!(other.import_seen("adlib-seen 2\nseen npc.adlib greet").is_err());
+        assert_eq!(other.seen_count("npc", "greet", None), 2);
+
+        other.variables.seen.clear();
+        other.restore(&saved).unwrap();
+        assert_eq!(other.seen_count("npc", "greet", Some("2")), 1);
+    }
+
+    #[test]
+    fn test_mixed_case_path()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_Test_Mixed_Case");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("bark.adlib"), "[bark]\n(random cycle)\none\n(variant)\ntwo\n(endrandom)").unwrap();
+
+        let new_state = || {
+            let mut state = GlobalState {
+                path : dir.to_string_lossy().into_owned() + "/",
+                ..Default::default()
+            };
+            state.main.engine.options.text_rate = 100.0;
+            state
+        };
+        let play = |state : &mut GlobalState| {
+            state.queue(QueueParams::parse("bark|bark").unwrap());
+            state.tick(1.0);
+            text(&state.main)
+        };
+
+        let mut state = new_state();
+        assert_eq!(play(&mut state), "one#");
+        let saved = state.save();
+        assert!(saved.contains("\nvariant bark.adlib bark "));
+
+        // The cycle carries on from the save
+        let mut restored = new_state();
+        restored.restore(&saved).unwrap();
+        restored.main.engine.clear();
+        assert_eq!(play(&mut restored), "two#");
+
+        // Seen counts too, through saves, exports and version 1 oneshots
+        std::fs::write(dir.join("npc.adlib"), "[greet]\nhello").unwrap();
+        state.queue(QueueParams::parse("npc|greet").unwrap());
+        state.tick(1.0);
+        let saved = state.save();
+        let exported = state.export_seen();
+        assert!(saved.contains("\nseen npc.adlib greet - 1\n"));
+        assert!(exported.contains("\nseen npc.adlib greet - 1\n"));
 
-impl std::hash::Hash for FilenameSectionPair {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.filename.to_ascii_lowercase().hash(state);
-        self.section.to_ascii_lowercase().hash(state);
+        let mut restored = new_state();
+        restored.restore(&saved).unwrap();
+        assert_eq!(restored.seen_count("npc", "greet", None), 1);
+        let mut imported = new_state();
+        imported.import_seen(&exported).unwrap();
+        assert_eq!(imported.seen_count("npc", "greet", None), 1);
+        let mut migrated = new_state();
+        migrated.restore("adlib-save 1\noneshot npc greet").unwrap();
+        assert_eq!(migrated.seen_count("npc", "greet", None), 1);
+
+        // Reloads name files the way the host queues them whatever case the path was set in
+        state.path = state.path.to_lowercase();
+        let bark = dir.join("bark.adlib");
+        std::fs::write(&bark, "[bark]\nthree").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        std::fs::File::options().write(true).open(&bark).unwrap().set_modified(later).unwrap();
+        assert_eq!(state.reload(false), vec!["bark"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_locale_keeps_cycles()
+    {
+        let dir = std::env::temp_dir().join("ad_libber_test_locale_cycles");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("bark.adlib"), "[bark]\n(random cycle)\none\n(variant)\ntwo\n(variant)\nthree\n(endrandom)").unwrap();
+        std::fs::write(dir.join("bark.fr.adlib"), "[bark]\n(random cycle)\nun\n(variant)\ndeux\n(variant)\ntrois\n(endrandom)").unwrap();
+
+        let mut state = GlobalState {
+            path : dir.to_string_lossy().into_owned() + "/",
+            ..Default::default()
+        };
+        state.main.engine.options.text_rate = 100.0;
+        let mut play = |locale : Option<&str>| {
+            state.cache.set_locale(locale);
+            state.main.engine.clear();
+            state.queue(QueueParams::parse("bark|bark").unwrap());
+            state.tick(1.0);
+            text(&state.main)
+        };
+
+        // The translation carries on the base file's cycle and the other way round
+        assert_eq!(play(None), "one#");
+        assert_eq!(play(Some("fr")), "deux#");
+        assert_eq!(play(None), "three#");
+    }
+}
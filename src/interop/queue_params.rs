@@ -1,27 +1,118 @@
+use std::fmt;
+
+use crate::dialogue_engine::{Priority, QueueOptions, QueuePolicy};
+
+#[derive(Default)]
 pub struct QueueParams<'a>
 {
     pub filename: &'a str,
     pub section: &'a str,
     pub oneshot: bool,
+    // See GlobalState::create_instance, None for whichever the caller uses by default
+    pub instance: Option<&'a str>,
+    pub options: QueueOptions,
 }
 
-impl<'a> QueueParams<'a> {
-    pub fn parse(input : &'a str) -> Option<Self> {
-        let mut splits = input.split("|").map(|x| x);
-        let filename = splits.next()?;
-        let section = splits.next()?;
-        let mut oneshot = false;
-
-        while let Some(arg) = splits.next() {
-            if (unicase::eq_ascii(arg, "oneshot")) {
-                oneshot = true;
-            }
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueueParamsError {
+    MissingSection,
+    UnknownOption(String),
+    // Key and value
+    BadValue(String, String),
+}
+
+impl fmt::Display for QueueParamsError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueParamsError::MissingSection => write!(f, "expected filename|section"),
+            QueueParamsError::UnknownOption(option) => write!(f, "unknown queue option '{}'", option),
+            QueueParamsError::BadValue(key, value) => write!(f, "bad value '{}' for {}", value, key),
         }
+    }
+}
+
+impl std::error::Error for QueueParamsError {}
 
-        Some(Self {
+impl<'a> QueueParams<'a> {
+    // "filename|section" then any of oneshot, interrupt, enqueue, drop_if_busy, instance=name,
+    // priority=low|normal|high, rate=<text rate>, linger=<frames>, talker=name and seed=<n>.
+    pub fn parse(input : &'a str) -> Result<Self, QueueParamsError> {
+        let mut splits = input.split('|');
+        let filename = splits.next().unwrap_or_default();
+        let section = splits.next().ok_or(QueueParamsError::MissingSection)?;
+
+        let mut params = Self {
             filename,
             section,
-            oneshot,
-        })
+            ..Default::default()
+        };
+
+        for arg in splits.filter(|x| !x.is_empty()) {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg, None),
+            };
+            let bad_value = || QueueParamsError::BadValue(key.to_owned(), value.unwrap_or_default().to_owned());
+            let options = &mut params.options;
+
+            match value {
+                None if unicase::eq_ascii(key, "oneshot") => params.oneshot = true,
+                None if unicase::eq_ascii(key, "interrupt") => options.policy = QueuePolicy::Interrupt,
+                None if unicase::eq_ascii(key, "enqueue") => options.policy = QueuePolicy::Enqueue,
+                None if unicase::eq_ascii(key, "drop_if_busy") => options.policy = QueuePolicy::DropIfBusy,
+                Some(value) if unicase::eq_ascii(key, "instance") => params.instance = Some(value),
+                Some(value) if unicase::eq_ascii(key, "priority") => {
+                    options.priority = Priority::parse(value).ok_or_else(bad_value)?;
+                },
+                Some(value) if unicase::eq_ascii(key, "rate") => {
+                    options.text_rate = Some(value.parse().ok().filter(|x : &f32| x.is_finite() && *x > 0.0).ok_or_else(bad_value)?);
+                },
+                Some(value) if unicase::eq_ascii(key, "linger") => {
+                    options.line_linger_time = Some(value.parse().ok().filter(|x : &f32| x.is_finite() && *x >= 0.0).ok_or_else(bad_value)?);
+                },
+                Some(value) if unicase::eq_ascii(key, "talker") => {
+                    if (value.trim().is_empty()) {
+                        return Err(bad_value());
+                    }
+                    options.talker = Some(value.trim().to_owned());
+                },
+                Some(value) if unicase::eq_ascii(key, "seed") => {
+                    options.seed = Some(value.parse().map_err(|_| bad_value())?);
+                },
+                _ => return Err(QueueParamsError::UnknownOption(arg.to_owned())),
+            }
+        }
+
+        Ok(params)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse()
+    {
+        let params = QueueParams::parse("barks|honk|oneshot|enqueue|priority=HIGH|rate=1.5|talker=goose|instance=npc3|linger=120|seed=42|").unwrap();
+        assert_eq!((params.filename, params.section, params.oneshot, params.instance), ("barks", "honk", true, Some("npc3")));
+        assert_eq!(params.options, QueueOptions {
+            policy : QueuePolicy::Enqueue,
+            priority : Priority::High,
+            text_rate : Some(1.5),
+            line_linger_time : Some(120.0),
+            talker : Some("goose".to_owned()),
+            seed : Some(42),
+        });
+
+        let params = QueueParams::parse("intro|start").unwrap();
+        assert_eq!((params.oneshot, params.instance, params.options), (false, None, QueueOptions::default()));
+
+        assert_eq!(QueueParams::parse("intro").err(), Some(QueueParamsError::MissingSection));
+        assert_eq!(QueueParams::parse("intro|start|loud").err(), Some(QueueParamsError::UnknownOption("loud".to_owned())));
+        assert_eq!(QueueParams::parse("intro|start|oneshot=1").err(), Some(QueueParamsError::UnknownOption("oneshot=1".to_owned())));
+        assert_eq!(QueueParams::parse("intro|start|rate=-1").err(), Some(QueueParamsError::BadValue("rate".to_owned(), "-1".to_owned())));
+        assert_eq!(QueueParams::parse("intro|start|priority=urgent").err(), Some(QueueParamsError::BadValue("priority".to_owned(), "urgent".to_owned())));
+        assert_eq!(QueueParams::parse("intro|start|seed").err(), Some(QueueParamsError::UnknownOption("seed".to_owned())));
+    }
+}
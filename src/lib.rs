@@ -1,9 +1,21 @@
 #![allow(unused_parens)]
 
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod custom_commands;
 pub mod dialogue;
 pub mod dialogue_engine;
+pub mod event;
 pub mod interop;
+pub mod lint;
+pub mod parse_error;
+pub mod random;
+pub mod seen;
+pub mod snapshot;
+pub mod string_table;
 pub mod talker;
+pub mod transcript;
+pub mod variables;
 
 #[cfg(feature = "gms")]
 pub mod gms {
@@ -15,8 +27,28 @@ pub mod gms {
 
     use gms_binder::*;
 
+    use crate::dialogue::Annotation;
+    use crate::dialogue_engine::RestoreOutcome;
+    use crate::interop::global_state::{EngineInstance, GlobalState};
+    use crate::interop::iter_wrapper::IterWrapper;
+    use crate::interop::queue_params::QueueParams;
+    use crate::variables::Value;
+
     static mut GLOBAL_STATE : Option<GlobalState> = None;
 
+    // Exports acting on one instance take its name first, empty for the main instance. Reads
+    // on an instance that doesn't exist give a default rather than making it.
+    unsafe fn find_instance(name_raw : *const c_char) -> Option<&'static mut EngineInstance> {
+        let name = CStr::from_ptr(name_raw).to_str().unwrap();
+        GLOBAL_STATE.as_mut().unwrap().instance_mut(name)
+    }
+
+    // Settings make the instance, so it can be set up before anything is queued on it.
+    unsafe fn make_instance(name_raw : *const c_char) -> &'static mut EngineInstance {
+        let name = CStr::from_ptr(name_raw).to_str().unwrap();
+        GLOBAL_STATE.as_mut().unwrap().create_instance(name)
+    }
+
     gms_bind_start!("ad_libber", "ad_libber.dll", "ad_lib");
 
     #[no_mangle]
@@ -38,27 +70,165 @@ pub mod gms {
         0.0
     }
 
+    // Eg "fr" to read intro.fr.adlib where it exists, empty for the base files.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_locale(locale_raw : *const c_char) -> f64 {
+        unsafe {
+            let locale = CStr::from_ptr(locale_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().cache.set_locale(Some(locale));
+        }
+        0.0
+    }
+
+    // Sections with no translation in the current locale, one per line.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_missing_translations(filename_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let filename = CStr::from_ptr(filename_raw).to_str().unwrap();
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let full_filename = state.full_filename(filename);
+            let missing = state.cache.missing_translations(&full_filename).join("\n");
+            state.return_string(&missing)
+        }
+    }
+
+    // Reparses .adlib files that changed on disk, returning their names one per line. With
+    // restart a conversation using a changed section starts over, otherwise it keeps the old text.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn reload_changed(restart : f64) -> *const c_char {
+        unsafe {
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let changed = state.reload(restart != 0.0).join("\n");
+            state.return_string(&changed)
+        }
+    }
+
+    // Checks for changed files every interval ticks during tick, 0 turns it off.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_hot_reload(interval : f64, restart : f64) -> f64 {
+        unsafe {
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            state.hot_reload.interval = interval.max(0.0) as f32;
+            state.hot_reload.restart = restart != 0.0;
+            0.0
+        }
+    }
+
+    // Files the hot reload picked up since the last call, one per line.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_reloaded_files() -> *const c_char {
+        unsafe {
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let reloaded = state.take_reloaded().join("\n");
+            state.return_string(&reloaded)
+        }
+    }
+
     #[no_mangle]
     #[gms_bind]
     pub extern "C" fn preload(filename_raw: *const c_char) -> f64 {
         unsafe {
             let state = GLOBAL_STATE.as_mut().unwrap();
             let input = CStr::from_ptr(filename_raw).to_str().unwrap();
-            println!("Calling preload {}", input);
             state.preload(input);
             0.0
         }
     }
 
+    // input of the form "filename|section" with any options after, see QueueParams::parse.
+    // Goes to the main instance unless it has instance=name. -1 if the input couldn't be read.
     #[no_mangle]
     #[gms_bind]
     pub extern "C" fn queue_dialogue(input_raw: *const c_char) -> f64 {
         unsafe {
-            // input of the form "filename|section"
             let state = GLOBAL_STATE.as_mut().unwrap();
             let input = CStr::from_ptr(input_raw).to_str().unwrap();
-            let queue_args = QueueParams::parse(input).expect(&format!("Could not parse {} as queue input", input));
-            state.queue(queue_args);
+            match QueueParams::parse(input) {
+                Ok(queue_args) => {
+                    state.queue(queue_args);
+                    0.0
+                },
+                Err(err) => {
+                    eprintln!("{}: {}", input, err);
+                    -1.0
+                },
+            }
+        }
+    }
+
+    // Each instance plays its own dialogue with its own options and events, eg for NPCs
+    // barking at the same time. Queueing with instance=name or changing a setting makes one
+    // too, this is for making it up front.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn create_instance(name_raw : *const c_char) -> f64 {
+        unsafe {
+            make_instance(name_raw);
+            0.0
+        }
+    }
+
+    // Stops an instance and frees it, 1 if there was one. The main instance stays.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn destroy_instance(name_raw : *const c_char) -> f64 {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            if (GLOBAL_STATE.as_mut().unwrap().destroy_instance(name)) { 1.0 } else { 0.0 }
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_variable_number(name_raw : *const c_char, value : f64) -> f64 {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().variables.set(name, Value::Number(value));
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_variable_string(name_raw : *const c_char, value_raw : *const c_char) -> f64 {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            let value = CStr::from_ptr(value_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().variables.set(name, Value::Text(value.to_owned()));
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_variable_number(name_raw : *const c_char) -> f64 {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            GLOBAL_STATE.as_ref().unwrap().variables.get_number(name)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_variable_string(name_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let value = state.variables.get(name).map(|x| x.to_string()).unwrap_or_default();
+            state.return_string(&value)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn clear_variables() -> f64 {
+        unsafe {
+            GLOBAL_STATE.as_mut().unwrap().variables.clear();
             0.0
         }
     }
@@ -76,20 +246,26 @@ pub mod gms {
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn reset_iterator() -> f64 {
+    // -1 if there's no such instance.
+    pub extern "C" fn reset_iterator(instance_raw : *const c_char) -> f64 {
         unsafe {
-            let iter = GLOBAL_STATE.as_ref().unwrap().engine.current_string_iter();
-            GLOBAL_STATE.as_mut().unwrap().iter_wrapper = Some(IterWrapper::new(iter));
-            0.0
+            match find_instance(instance_raw) {
+                Some(instance) => {
+                    let iter = instance.engine.current_string_iter();
+                    instance.iter_wrapper = Some(IterWrapper::new(iter));
+                    0.0
+                },
+                None => -1.0,
+            }
         }
     }
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn move_iterator() -> f64 {
+    pub extern "C" fn move_iterator(instance_raw : *const c_char) -> f64 {
         unsafe {
-            let iter = GLOBAL_STATE.as_mut().unwrap().iter_wrapper.as_mut().unwrap();
-            if (iter.move_next()) {
+            let iter = find_instance(instance_raw).and_then(|x| x.iter_wrapper.as_mut());
+            if (iter.map(|x| x.move_next()).unwrap_or(false)) {
                 1.0
             }
             else {
@@ -100,21 +276,23 @@ pub mod gms {
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn iterator_cur_string() -> *const c_char {
+    pub extern "C" fn iterator_cur_string(instance_raw : *const c_char) -> *const c_char {
         unsafe {
-            let iter = GLOBAL_STATE.as_ref().unwrap().iter_wrapper.as_ref().unwrap();
-            let ptr = iter.current_c_string.as_ref().unwrap().as_ptr();
-            ptr
+            let iter = find_instance(instance_raw).and_then(|x| x.iter_wrapper.as_ref());
+            match iter.and_then(|x| x.current_c_string.as_ref()) {
+                Some(s) => s.as_ptr(),
+                None => GLOBAL_STATE.as_mut().unwrap().return_string(""),
+            }
         }
     }
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn is_jiggly() -> f64 {
+    pub extern "C" fn is_jiggly(instance_raw : *const c_char) -> f64 {
         unsafe {
-            let iter = GLOBAL_STATE.as_ref().unwrap().iter_wrapper.as_ref().unwrap();
-            let annotations = &iter.current_annotation.as_ref().unwrap().annotations;
-            if (annotations.iter().any(|x| *x == Annotation::Jiggly)) {
+            let iter = find_instance(instance_raw).and_then(|x| x.iter_wrapper.as_ref());
+            let annotation = iter.and_then(|x| x.current_annotation.as_ref());
+            if (annotation.map(|x| x.annotations.contains(&Annotation::Jiggly)).unwrap_or(false)) {
                 1.0
             }
             else {
@@ -125,11 +303,11 @@ pub mod gms {
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn is_wide() -> f64 {
+    pub extern "C" fn is_wide(instance_raw : *const c_char) -> f64 {
         unsafe {
-            let iter = GLOBAL_STATE.as_ref().unwrap().iter_wrapper.as_ref().unwrap();
-            let annotations = &iter.current_annotation.as_ref().unwrap().annotations;
-            if (annotations.iter().any(|x| *x == Annotation::Wide)) {
+            let iter = find_instance(instance_raw).and_then(|x| x.iter_wrapper.as_ref());
+            let annotation = iter.and_then(|x| x.current_annotation.as_ref());
+            if (annotation.map(|x| x.annotations.contains(&Annotation::Wide)).unwrap_or(false)) {
                 1.0
             }
             else {
@@ -139,39 +317,327 @@ pub mod gms {
     }
 
 
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn choice_count(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            find_instance(instance_raw).and_then(|x| x.engine.choices()).map(|x| x.len()).unwrap_or(0) as f64
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn choice_text(instance_raw : *const c_char, index : f64) -> *const c_char {
+        unsafe {
+            let text = find_instance(instance_raw).and_then(|x| x.engine.choices()).and_then(|x| x.get(index as usize)).map(|x| x.text.clone()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&text)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn choose(instance_raw : *const c_char, index : f64) -> f64 {
+        unsafe {
+            let instance = CStr::from_ptr(instance_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().choose(instance, index as usize);
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn advance(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            let instance = CStr::from_ptr(instance_raw).to_str().unwrap();
+            if (GLOBAL_STATE.as_mut().unwrap().advance(instance)) { 1.0 } else { 0.0 }
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn complete_line(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            let instance = CStr::from_ptr(instance_raw).to_str().unwrap();
+            if (GLOBAL_STATE.as_mut().unwrap().complete_line(instance)) { 1.0 } else { 0.0 }
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn skip_section(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            let instance = CStr::from_ptr(instance_raw).to_str().unwrap();
+            if (GLOBAL_STATE.as_mut().unwrap().skip_section(instance)) { 1.0 } else { 0.0 }
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_wait_for_input(instance_raw : *const c_char, wait : f64) -> f64 {
+        unsafe {
+            make_instance(instance_raw).engine.options.wait_for_input = wait != 0.0;
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn is_awaiting_input(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            if (find_instance(instance_raw).map(|x| x.engine.awaiting_input()).unwrap_or(false)) { 1.0 } else { 0.0 }
+        }
+    }
+
+    // Call before preloading, files already loaded keep what they were parsed with.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn register_custom_command(name_raw : *const c_char, blocking : f64) -> f64 {
+        unsafe {
+            let name = CStr::from_ptr(name_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().cache.custom_commands.register(name, blocking != 0.0);
+            0.0
+        }
+    }
+
+    // With pass_through non-zero any unknown command reaches the game instead of being a
+    // parse error. Call before preloading like register_custom_command.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_custom_command_pass_through(pass_through : f64) -> f64 {
+        unsafe {
+            GLOBAL_STATE.as_mut().unwrap().cache.custom_commands.set_pass_through(pass_through != 0.0);
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn resume_custom_command(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            if (find_instance(instance_raw).map(|x| x.engine.resume_custom_command()).unwrap_or(false)) { 1.0 } else { 0.0 }
+        }
+    }
+
     #[no_mangle]
     #[gms_bind]
     pub extern "C" fn tick() -> f64 {
         unsafe {
-            GLOBAL_STATE.as_mut().unwrap().engine.tick(1.0);
+            GLOBAL_STATE.as_mut().unwrap().tick(1.0);
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_text_rate(instance_raw : *const c_char, rate : f64) -> f64 {
+        unsafe {
+            make_instance(instance_raw).engine.options.text_rate = rate as f32;
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_line_linger_time(instance_raw : *const c_char, time : f64) -> f64 {
+        unsafe {
+            make_instance(instance_raw).engine.options.line_linger_time = time as f32;
             0.0
         }
     }
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn set_text_rate(rate : f64) -> f64 {
+    pub extern "C" fn set_blip_interval(instance_raw : *const c_char, interval : f64) -> f64 {
         unsafe {
-            GLOBAL_STATE.as_mut().unwrap().engine.options.text_rate = rate as f32;
+            make_instance(instance_raw).engine.options.blip_interval = interval.max(0.0) as u32;
             0.0
         }
     }
 
+    // Pops the next event, returns 0 when there are none left this frame.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn next_event(instance_raw : *const c_char) -> f64 {
+        unsafe {
+            let instance = match find_instance(instance_raw) {
+                Some(instance) => instance,
+                None => return 0.0,
+            };
+            instance.current_event = instance.engine.pop_event();
+            if (instance.current_event.is_some()) {
+                1.0
+            }
+            else {
+                0.0
+            }
+        }
+    }
+
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn set_line_linger_time(time : f64) -> f64 {
+    pub extern "C" fn event_type(instance_raw : *const c_char) -> *const c_char {
         unsafe {
-            GLOBAL_STATE.as_mut().unwrap().engine.options.line_linger_time = time as f32;
+            let name = find_instance(instance_raw).and_then(|x| x.current_event.as_ref()).map(|x| x.name()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(name)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn event_arg(instance_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let arg = find_instance(instance_raw).and_then(|x| x.current_event.as_ref()).map(|x| x.arg()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&arg)
+        }
+    }
+
+    // Frames, only needed for clips that should pace the text, see set_vo_paced_reveal.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_vo_duration(instance_raw : *const c_char, clip_raw : *const c_char, frames : f64) -> f64 {
+        unsafe {
+            let clip = CStr::from_ptr(clip_raw).to_str().unwrap();
+            make_instance(instance_raw).engine.set_vo_duration(clip, frames as f32);
             0.0
         }
     }
 
     #[no_mangle]
     #[gms_bind]
-    pub extern "C" fn get_current_sprite() -> *const c_char {
+    pub extern "C" fn set_vo_paced_reveal(instance_raw : *const c_char, paced : f64) -> f64 {
+        unsafe {
+            make_instance(instance_raw).engine.options.vo_paced_reveal = paced != 0.0;
+            0.0
+        }
+    }
+
+    // Text to keep in the game's save file, pass it back to load_state.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn save_state() -> *const c_char {
+        unsafe {
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let saved = state.save();
+            state.return_string(&saved)
+        }
+    }
+
+    // -1 if the save couldn't be read, otherwise 0 idle, 1 resumed, 2 restarted because the
+    // file changed, 3 dropped because the section is gone.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn load_state(saved_raw : *const c_char) -> f64 {
+        unsafe {
+            let saved = CStr::from_ptr(saved_raw).to_str().unwrap();
+            match GLOBAL_STATE.as_mut().unwrap().restore(saved) {
+                Ok(RestoreOutcome::Idle) => 0.0,
+                Ok(RestoreOutcome::Resumed) => 1.0,
+                Ok(RestoreOutcome::Restarted) => 2.0,
+                Ok(RestoreOutcome::Dropped) => 3.0,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    -1.0
+                },
+            }
+        }
+    }
+
+    // Times a section has started, or with a line how many times that line has. Lines are
+    // their #id: tag or their 1-based number in the section, empty for the section itself.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_seen_count(filename_raw : *const c_char, section_raw : *const c_char, line_raw : *const c_char) -> f64 {
+        unsafe {
+            let filename = CStr::from_ptr(filename_raw).to_str().unwrap();
+            let section = CStr::from_ptr(section_raw).to_str().unwrap();
+            let line = CStr::from_ptr(line_raw).to_str().unwrap();
+            let line = if (line.is_empty()) { None } else { Some(line) };
+            GLOBAL_STATE.as_ref().unwrap().seen_count(filename, section, line) as f64
+        }
+    }
+
+    // Lines of a section never shown yet, eg for a "new dialogue" marker over an NPC.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_unseen_line_count(filename_raw : *const c_char, section_raw : *const c_char) -> f64 {
+        unsafe {
+            let filename = CStr::from_ptr(filename_raw).to_str().unwrap();
+            let section = CStr::from_ptr(section_raw).to_str().unwrap();
+            GLOBAL_STATE.as_mut().unwrap().unseen_lines(filename, section).len() as f64
+        }
+    }
+
+    // Seen counts on their own, for keeping them outside the save slots. save_state
+    // includes them too.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn save_seen() -> *const c_char {
+        unsafe {
+            let state = GLOBAL_STATE.as_mut().unwrap();
+            let saved = state.export_seen();
+            state.return_string(&saved)
+        }
+    }
+
+    // Replaces the seen counts with ones from save_seen, -1 if they couldn't be read.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn load_seen(saved_raw : *const c_char) -> f64 {
+        unsafe {
+            let saved = CStr::from_ptr(saved_raw).to_str().unwrap();
+            match GLOBAL_STATE.as_mut().unwrap().import_seen(saved) {
+                Ok(()) => 0.0,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    -1.0
+                },
+            }
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn set_seed(instance_raw : *const c_char, seed : f64) -> f64 {
+        unsafe {
+            make_instance(instance_raw).engine.variants.seed(seed as u64);
+            0.0
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_current_talker(instance_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let name = find_instance(instance_raw).and_then(|x| x.engine.current_talker()).map(|x| x.name.clone()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&name)
+        }
+    }
+
+    // Empty if the line has no #id: tag.
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_current_line_id(instance_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let id = find_instance(instance_raw).and_then(|x| x.engine.current_line_id()).unwrap_or_default().to_owned();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&id)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_current_sprite(instance_raw : *const c_char) -> *const c_char {
+        unsafe {
+            let sprite = find_instance(instance_raw).and_then(|x| x.engine.current_talker()).map(|x| x.sprite.clone()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&sprite)
+        }
+    }
+
+    #[no_mangle]
+    #[gms_bind]
+    pub extern "C" fn get_current_sound(instance_raw : *const c_char) -> *const c_char {
         unsafe {
-            todo!()
-            //GLOBAL_STATE.as_mut().unwrap().engine.
+            let sound = find_instance(instance_raw).and_then(|x| x.engine.current_talker()).map(|x| x.sound.clone()).unwrap_or_default();
+            GLOBAL_STATE.as_mut().unwrap().return_string(&sound)
         }
     }
 
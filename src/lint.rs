@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::custom_commands::CustomCommands;
+use crate::dialogue::{column_of, is_command_line, split_talker, tokenize_line, Chunk, Command, DialogueFile};
+use crate::parse_error::{ParseError, ParseErrorKind};
+
+// Parse errors plus the mistakes that still load, for adlib-lint. files are filename and
+// contents pairs, custom commands shouldn't pass through so typos show up as unknown commands.
+//
+// Entry points are sections the game queues itself, as "section" or "file:section" with an
+// optional trailing * to match a prefix. With none given, any section nothing else refers to
+// counts as one, which still catches sections that only refer to each other.
+pub fn lint(files : &[(String, String)], custom_commands : &CustomCommands, entry_points : &[String]) -> Vec<ParseError> {
+    let mut errors = vec![];
+    let mut parsed = vec![];
+    let mut headers = HashMap::new();
+
+    for (filename, contents) in files {
+        for (section, line) in lint_lines(filename, contents, custom_commands, &mut errors) {
+            headers.entry(section_key(filename, &section)).or_insert((filename.clone(), line, section));
+        }
+
+        match DialogueFile::parse_contents_with(filename, contents, custom_commands) {
+            Ok(file) => parsed.push((filename, file)),
+            Err(parse_errors) => errors.extend(parse_errors),
+        }
+    }
+
+    // A broken file's references are unknown, anything could be reached from it
+    if (parsed.len() == files.len()) {
+        for key in unreachable_sections(&parsed, entry_points) {
+            if let Some((filename, line, section)) = headers.get(&key) {
+                errors.push(ParseError::new(filename, *line, 1, section, ParseErrorKind::UnreachableSection));
+            }
+        }
+    }
+
+    errors.sort_by(|a, b| (&a.filename, a.line, a.column).cmp(&(&b.filename, b.line, b.column)));
+    errors
+}
+
+// Lowercased filename and section, names are case insensitive
+type SectionKey = (String, String);
+
+fn section_key(filename : &str, section : &str) -> SectionKey {
+    (filename.to_ascii_lowercase(), section.to_ascii_lowercase())
+}
+
+// Checks the parser has no need for, returning each section and its header line.
+fn lint_lines(filename : &str, contents : &str, custom_commands : &CustomCommands, errors : &mut Vec<ParseError>) -> Vec<(String, usize)> {
+    let mut error = |line_number : usize, column : usize, text : &str, kind : ParseErrorKind| {
+        errors.push(ParseError::new(filename, line_number, column, text, kind));
+    };
+
+    let mut sections : Vec<(String, usize)> = vec![];
+    // Talkers are only known to sections after them, like when parsing
+    let mut talkers : Vec<&str> = vec![];
+    // Header line of the section being read and whether it has anything in it
+    let mut current : Option<(&str, usize, bool)> = None;
+    // Annotation, line, column and token of each open annotation in the section
+    let mut open_annotations = vec![];
+
+    let end_section = |current : Option<(&str, usize, bool)>, open_annotations : &mut Vec<_>, error : &mut dyn FnMut(usize, usize, &str, ParseErrorKind)| {
+        if let Some((header, line_number, false)) = current {
+            error(line_number, 1, header, ParseErrorKind::EmptySection);
+        }
+        for (_, line_number, column, token) in open_annotations.drain(..) {
+            error(line_number, column, token, ParseErrorKind::UnbalancedAnnotation);
+        }
+    };
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        if (line.is_empty() || line.starts_with("#")) {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            end_section(current.take(), &mut open_annotations, &mut error);
+
+            let name = name.trim_end_matches(']');
+            match name.split_once(" ") {
+                Some((keyword, talker)) if unicase::eq_ascii(keyword, "talker") => {
+                    talkers.push(talker.trim());
+                },
+                _ => {
+                    if (sections.iter().any(|(x, _)| unicase::eq_ascii(&x[..], name))) {
+                        error(line_number, 2, name, ParseErrorKind::DuplicateSection);
+                    }
+                    sections.push((name.to_owned(), line_number));
+                    current = Some((line, line_number, false));
+                },
+            }
+            continue;
+        }
+
+        // Talker properties, or text before the first header
+        let (_, _, has_lines) = match &mut current {
+            Some(current) => current,
+            None => continue,
+        };
+        *has_lines = true;
+
+        if (line.starts_with(">")) {
+            continue;
+        }
+
+        let (talker_name, text) = if (is_command_line(line)) {
+            (None, line)
+        }
+        else {
+            split_talker(line)
+        };
+
+        if let Some(talker_name) = talker_name {
+            if (!talkers.iter().any(|x| unicase::eq_ascii(*x, talker_name))) {
+                error(line_number, column_of(line, talker_name), talker_name, ParseErrorKind::UnknownTalker);
+            }
+        }
+
+        for token in tokenize_line(text) {
+            if (!token.starts_with("(") || !token.ends_with(")")) {
+                continue;
+            }
+
+            match Command::parse(&token[1..(token.len() - 1)], custom_commands) {
+                Ok(Command::AnnotationStart(annotation)) => {
+                    open_annotations.push((annotation, line_number, column_of(line, token), token));
+                },
+                Ok(Command::AnnotationEnd(annotation)) => {
+                    match open_annotations.iter().rposition(|(x, _, _, _)| *x == annotation) {
+                        Some(index) => {
+                            open_annotations.remove(index);
+                        },
+                        None => {
+                            error(line_number, column_of(line, token), token, ParseErrorKind::UnbalancedAnnotation);
+                        },
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+    end_section(current.take(), &mut open_annotations, &mut error);
+
+    sections
+}
+
+fn is_entry_point(filename : &str, section : &str, entry_points : &[String]) -> bool {
+    let stem = std::path::Path::new(filename).file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    entry_points.iter().any(|entry_point| {
+        let (file, pattern) = match entry_point.split_once(':') {
+            Some((file, pattern)) => (Some(file), pattern),
+            None => (None, &entry_point[..]),
+        };
+
+        let section_matches = match pattern.strip_suffix('*') {
+            Some(prefix) => section.len() >= prefix.len() && section.is_char_boundary(prefix.len()) && unicase::eq_ascii(&section[..prefix.len()], prefix),
+            None => unicase::eq_ascii(section, pattern),
+        };
+
+        section_matches && file.map(|x| unicase::eq_ascii(x, &stem)).unwrap_or(true)
+    })
+}
+
+// Translations, eg intro.fr.adlib, are reached through their base file. Without intro.adlib
+// among filenames it's just a file with a dot in its name.
+fn is_translation(filename : &str, filenames : &HashSet<String>) -> bool {
+    let stem = filename.strip_suffix(".adlib").unwrap_or(filename);
+    match stem.rsplit_once('.') {
+        Some((base, locale)) if !locale.contains(['/', '\\']) => filenames.contains(&format!("{}.adlib", base).to_ascii_lowercase()),
+        _ => false,
+    }
+}
+
+fn unreachable_sections(files : &[(&String, DialogueFile)], entry_points : &[String]) -> Vec<SectionKey> {
+    let mut targets : HashMap<SectionKey, Vec<SectionKey>> = HashMap::new();
+    let mut referred = HashSet::new();
+    let filenames = files.iter().map(|(x, _)| x.to_ascii_lowercase()).collect::<HashSet<_>>();
+
+    for (filename, file) in files.iter().filter(|(x, _)| !is_translation(x, &filenames)) {
+        for section in &file.sections {
+            let key = section_key(filename, &section.name);
+            let mut section_targets = vec![];
+            for chunk in &section.chunks {
+                match chunk {
+                    Chunk::Command(command) => section_targets.extend(command.section_target()),
+                    Chunk::Choice(options) => section_targets.extend(options.iter().filter_map(|x| x.target.as_ref())),
+                    _ => {},
+                }
+            }
+
+            let section_targets = section_targets.iter()
+                .map(|x| section_key(&x.resolve_filename(filename), &x.section))
+                .filter(|x| *x != key)
+                .collect::<Vec<_>>();
+            referred.extend(section_targets.iter().cloned());
+            targets.insert(key, section_targets);
+        }
+    }
+
+    let mut queue = VecDeque::new();
+    for (filename, file) in files.iter().filter(|(x, _)| !is_translation(x, &filenames)) {
+        for section in &file.sections {
+            let key = section_key(filename, &section.name);
+            let entry = if (entry_points.is_empty()) {
+                !referred.contains(&key)
+            }
+            else {
+                is_entry_point(filename, &section.name, entry_points)
+            };
+
+            if (entry) {
+                queue.push_back(key);
+            }
+        }
+    }
+
+    let mut reached = HashSet::new();
+    while let Some(key) = queue.pop_front() {
+        if (reached.insert(key.clone())) {
+            queue.extend(targets.get(&key).into_iter().flatten().cloned());
+        }
+    }
+
+    let mut unreachable = targets.into_keys().filter(|x| !reached.contains(x)).collect::<Vec<_>>();
+    unreachable.sort();
+    unreachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(errors : &[ParseError]) -> Vec<(usize, usize, ParseErrorKind)> {
+        errors.iter().map(|x| (x.line, x.column, x.kind.clone())).collect()
+    }
+
+    #[test]
+    fn test_lint_lines()
+    {
+        let files = vec![("test.adlib".to_owned(), "[talker goose]
+sound = snd_goose
+
+[intro]
+goose | hello (j) there
+toad | who (/w) me
+(wait soon)
+
+[empty]
+# just a comment
+
+[Intro]
+(goto empty)
+(goto intro)".to_owned())];
+
+        let errors = lint(&files, &CustomCommands::default(), &[]);
+        assert_eq!(summary(&errors), vec![
+            (5, 15, ParseErrorKind::UnbalancedAnnotation),
+            (6, 1, ParseErrorKind::UnknownTalker),
+            (6, 12, ParseErrorKind::UnbalancedAnnotation),
+            (7, 1, ParseErrorKind::BadDuration),
+            (9, 1, ParseErrorKind::EmptySection),
+            (12, 2, ParseErrorKind::DuplicateSection),
+        ]);
+    }
+
+    #[test]
+    fn test_unreachable()
+    {
+        let files = vec![
+            ("dir/robot.adlib".to_owned(), "[robot_0]\n(goto shared)\n[robot_1]\n(goto shared)\n[shared]\nhi\n> more -> lonely:b\n".to_owned()),
+            ("dir/lonely.adlib".to_owned(), "[a]\n(goto b)\n[b]\n(goto a)\n[c]\n(goto c)".to_owned()),
+            ("dir/lonely.fr.adlib".to_owned(), "[c]\nsalut".to_owned()),
+        ];
+
+        // a and b only refer to each other, until shared leads to b
+        let errors = lint(&files[1..], &CustomCommands::default(), &[]);
+        assert_eq!(errors.iter().map(|x| &x.text[..]).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(lint(&files, &CustomCommands::default(), &[]).is_empty());
+
+        let entry_points = vec!["robot:robot_*".to_owned()];
+        let errors = lint(&files, &CustomCommands::default(), &entry_points);
+        assert_eq!(errors.iter().map(|x| (&x.filename[..], &x.text[..])).collect::<Vec<_>>(), vec![("dir/lonely.adlib", "c")]);
+
+        // Nothing is unreachable while a file is broken
+        let mut broken = files.clone();
+        broken.push(("dir/broken.adlib".to_owned(), "[x]\n(nope)".to_owned()));
+        let errors = lint(&broken, &CustomCommands::default(), &entry_points);
+        assert_eq!(summary(&errors), vec![(2, 1, ParseErrorKind::UnknownCommand)]);
+
+        // A dot in the name only makes a translation when the base file is there too
+        let files = vec![("dir/act.1.adlib".to_owned(), "[start]\n(goto end)\n[end]\nbye\n[orphan]\nhi".to_owned())];
+        let errors = lint(&files, &CustomCommands::default(), &["act.1:start".to_owned()]);
+        assert_eq!(errors.iter().map(|x| &x.text[..]).collect::<Vec<_>>(), vec!["orphan"]);
+    }
+}
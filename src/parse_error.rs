@@ -0,0 +1,89 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnknownCommand,
+    MissingArgument,
+    BadDuration,
+    BadRate,
+    UnterminatedCommand,
+    UnterminatedHeader,
+    UnknownSection,
+    BadExpression,
+    UnbalancedIf,
+    UnbalancedRandom,
+    DuplicateLineId,
+    // Only reported by lint, the file still loads
+    UnknownTalker,
+    UnbalancedAnnotation,
+    DuplicateSection,
+    EmptySection,
+    UnreachableSection,
+    // Whole file could not be read, line and column are zero.
+    Io(String),
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownCommand => write!(f, "unknown command"),
+            ParseErrorKind::MissingArgument => write!(f, "missing argument"),
+            ParseErrorKind::BadDuration => write!(f, "could not parse duration"),
+            ParseErrorKind::BadRate => write!(f, "could not parse rate"),
+            ParseErrorKind::UnterminatedCommand => write!(f, "command is missing a closing ')'"),
+            ParseErrorKind::UnterminatedHeader => write!(f, "section header is missing a closing ']'"),
+            ParseErrorKind::UnknownSection => write!(f, "no section with this name"),
+            ParseErrorKind::BadExpression => write!(f, "could not parse expression"),
+            ParseErrorKind::UnbalancedIf => write!(f, "if, else and endif don't match up"),
+            ParseErrorKind::UnbalancedRandom => write!(f, "random, variant and endrandom don't match up"),
+            ParseErrorKind::DuplicateLineId => write!(f, "line id is already used in this file"),
+            ParseErrorKind::UnknownTalker => write!(f, "no talker with this name"),
+            ParseErrorKind::UnbalancedAnnotation => write!(f, "annotation starts and ends don't match up"),
+            ParseErrorKind::DuplicateSection => write!(f, "section name is already used in this file"),
+            ParseErrorKind::EmptySection => write!(f, "section has no lines"),
+            ParseErrorKind::UnreachableSection => write!(f, "nothing leads to this section"),
+            ParseErrorKind::Io(err) => write!(f, "could not read file: {}", err),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError
+{
+    pub filename : String,
+    // 1-based
+    pub line : usize,
+    // 1-based, in bytes
+    pub column : usize,
+    pub text : String,
+    pub kind : ParseErrorKind,
+}
+
+impl ParseError {
+    pub fn new(filename : &str, line : usize, column : usize, text : &str, kind : ParseErrorKind) -> Self {
+        Self {
+            filename : filename.to_owned(),
+            line,
+            column,
+            text : text.to_owned(),
+            kind,
+        }
+    }
+
+    pub fn io(filename : &str, err : &std::io::Error) -> Self {
+        Self::new(filename, 0, 0, "", ParseErrorKind::Io(err.to_string()))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        if (self.line == 0) {
+            write!(f, "{}: {}", self.filename, self.kind)
+        }
+        else {
+            write!(f, "{}:{}:{}: {} '{}'", self.filename, self.line, self.column, self.kind, self.text)
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
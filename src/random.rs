@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+
+use crate::parse_error::ParseErrorKind;
+
+const DEFAULT_SEED : u64 = 0x2545F4914F6CDD1D;
+
+// xorshift64*, small and stable across versions so seeded replays don't change under us.
+#[derive(Clone, Debug)]
+pub struct Rng
+{
+    state : u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED)
+    }
+}
+
+impl Rng {
+    pub fn new(seed : u64) -> Self {
+        // Zero is a fixed point of xorshift
+        let state = if (seed == 0) { DEFAULT_SEED } else { seed };
+        Self { state }
+    }
+
+    // Rng::new(state) carries on from here.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    pub fn below(&mut self, n : u64) -> u64 {
+        if (n == 0) {
+            0
+        }
+        else {
+            self.next_u64() % n
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VariantMode {
+    // Independent weighted pick every time
+    Random,
+    // Weighted pick without repeats until every variant has been seen
+    Shuffle,
+    // Each variant in order
+    Cycle,
+}
+
+impl VariantMode {
+    pub fn parse(s : Option<&str>) -> Result<Self, ParseErrorKind> {
+        match s {
+            None => Ok(VariantMode::Random),
+            Some(x) if unicase::eq_ascii(x, "random") => Ok(VariantMode::Random),
+            Some(x) if unicase::eq_ascii(x, "shuffle") => Ok(VariantMode::Shuffle),
+            Some(x) if unicase::eq_ascii(x, "cycle") => Ok(VariantMode::Cycle),
+            Some(_) => Err(ParseErrorKind::BadExpression),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VariantMode::Random => "random",
+            VariantMode::Shuffle => "shuffle",
+            VariantMode::Cycle => "cycle",
+        }
+    }
+}
+
+// Identifies a random block by file, section and chunk index
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VariantKey
+{
+    pub filename : String,
+    pub section : String,
+    pub chunk : usize,
+}
+
+impl VariantKey {
+    pub fn new(filename : &str, section : &str, chunk : usize) -> Self {
+        Self {
+            filename : filename.to_ascii_lowercase(),
+            section : section.to_ascii_lowercase(),
+            chunk,
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct VariantHistory
+{
+    pub count : usize,
+    // Variants not yet picked this round of a shuffle
+    pub remaining : Vec<usize>,
+    pub last : Option<usize>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct VariantsSnapshot
+{
+    pub rng_state : u64,
+    pub history : Vec<(VariantKey, VariantHistory)>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct Variants
+{
+    rng : Rng,
+    history : HashMap<VariantKey, VariantHistory>,
+}
+
+impl Variants {
+    pub fn seed(&mut self, seed : u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn snapshot(&self) -> VariantsSnapshot {
+        let mut history = self.history.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+        // Same state always saves the same way
+        history.sort_by(|(a, _), (b, _)| (&a.filename, &a.section, a.chunk).cmp(&(&b.filename, &b.section, b.chunk)));
+
+        VariantsSnapshot {
+            rng_state : self.rng.state(),
+            history,
+        }
+    }
+
+    pub fn restore(snapshot : &VariantsSnapshot) -> Self {
+        Self {
+            rng : Rng::new(snapshot.rng_state),
+            history : snapshot.history.iter().cloned().collect(),
+        }
+    }
+
+    // Forget shuffle and cycle positions.
+    pub fn reset_history(&mut self) {
+        self.history.clear();
+    }
+
+    fn pick_weighted(rng : &mut Rng, candidates : &[usize], weights : &[u32]) -> usize {
+        let total : u64 = candidates.iter().map(|x| weights[*x] as u64).sum();
+        let mut roll = rng.below(total);
+        for candidate in candidates {
+            let weight = weights[*candidate] as u64;
+            if (roll < weight) {
+                return *candidate;
+            }
+            roll -= weight;
+        }
+
+        candidates[candidates.len() - 1]
+    }
+
+    // Returns the index of the variant to play.
+    pub fn pick(&mut self, key : VariantKey, mode : VariantMode, weights : &[u32]) -> usize {
+        if (weights.len() <= 1) {
+            return 0;
+        }
+
+        let history = self.history.entry(key).or_default();
+
+        let picked = match mode {
+            VariantMode::Random => {
+                let all = (0..weights.len()).collect::<Vec<_>>();
+                Self::pick_weighted(&mut self.rng, &all, weights)
+            },
+            VariantMode::Shuffle => {
+                if (history.remaining.is_empty()) {
+                    history.remaining = (0..weights.len()).collect();
+                }
+
+                // At the start of a new round, don't repeat the last pick of the previous one
+                let candidates = history.remaining.iter().cloned().filter(|x| Some(*x) != history.last).collect::<Vec<_>>();
+                let candidates = if (candidates.is_empty()) { history.remaining.clone() } else { candidates };
+
+                let picked = Self::pick_weighted(&mut self.rng, &candidates, weights);
+                history.remaining.retain(|x| *x != picked);
+                picked
+            },
+            VariantMode::Cycle => {
+                history.count % weights.len()
+            },
+        };
+
+        history.count += 1;
+        history.last = Some(picked);
+
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_shuffle_and_cycle()
+    {
+        let mut variants = Variants::default();
+        let key = VariantKey::new("test", "bark", 0);
+
+        let mut previous = None;
+        for _ in 0..10 {
+            let mut round = (0..4).map(|_| variants.pick(key.clone(), VariantMode::Shuffle, &[1, 1, 5, 1])).collect::<Vec<_>>();
+            // No back to back repeats even across rounds
+            assert_ne!(Some(round[0]), previous);
+            previous = round.last().cloned();
+
+            round.sort();
+            assert_eq!(round, vec![0, 1, 2, 3]);
+        }
+
+        let cycle_key = VariantKey::new("test", "bark", 1);
+        let cycled = (0..5).map(|_| variants.pick(cycle_key.clone(), VariantMode::Cycle, &[1, 1, 1])).collect::<Vec<_>>();
+        assert_eq!(cycled, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn test_seeded_picks_repeat()
+    {
+        let picks = |seed| {
+            let mut variants = Variants::default();
+            variants.seed(seed);
+            (0..20).map(|_| variants.pick(VariantKey::new("test", "bark", 0), VariantMode::Random, &[1, 2, 3])).collect::<Vec<_>>()
+        };
+
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(43));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use crate::dialogue::SectionTarget;
+
+// A section, or a line in it. Lines are known by their #id: tag, or without one by their
+// 1-based number in the section.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SeenKey
+{
+    pub filename : String,
+    pub section : String,
+    // None for the section itself
+    pub line : Option<String>,
+}
+
+impl SeenKey {
+    // Translations should pass the file they translate, see Dialogue::base_filename.
+    pub fn new(filename : &str, section : &str, line : Option<&str>) -> Self {
+        Self {
+            filename : filename.to_ascii_lowercase(),
+            section : section.to_ascii_lowercase(),
+            line : line.map(|x| x.to_owned()),
+        }
+    }
+}
+
+// How many times each section has started and each line has been shown, for oneshots,
+// (if seen ...) and the host's "new dialogue" markers.
+#[derive(Default, Clone, Debug)]
+pub struct SeenLines
+{
+    counts : HashMap<SeenKey, u32>,
+}
+
+impl SeenLines {
+    pub fn record(&mut self, key : SeenKey) {
+        *self.counts.entry(key).or_default() += 1;
+    }
+
+    pub fn count(&self, key : &SeenKey) -> u32 {
+        self.counts.get(key).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, key : SeenKey, count : u32) {
+        if (count == 0) {
+            self.counts.remove(&key);
+        }
+        else {
+            self.counts.insert(key, count);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+
+    // Sorted so the same counts always save the same way.
+    pub fn sorted(&self) -> Vec<(SeenKey, u32)> {
+        let mut sorted = self.counts.iter().map(|(k, v)| (k.clone(), *v)).collect::<Vec<_>>();
+        sorted.sort();
+        sorted
+    }
+}
+
+// What (if seen ...) looks up, "section" or "file:section" for a section and either with
+// "#line" after for a line in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeenTarget
+{
+    pub target : SectionTarget,
+    pub line : Option<String>,
+}
+
+impl SeenTarget {
+    pub fn parse(s : &str) -> Option<Self> {
+        let (target, line) = match s.split_once('#') {
+            Some((target, line)) if !line.is_empty() => (target, Some(line.to_owned())),
+            Some(_) => return None,
+            None => (s, None),
+        };
+
+        Some(Self {
+            target : SectionTarget::parse(target)?,
+            line,
+        })
+    }
+
+    pub fn key(&self, current_filename : &str) -> SeenKey {
+        SeenKey::new(&self.target.resolve_filename(current_filename), &self.target.section, self.line.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_target()
+    {
+        let target = SeenTarget::parse("other:Intro#hello").unwrap();
+        assert_eq!(target.key("dialogue/barks.adlib"), SeenKey::new("dialogue/other.adlib", "intro", Some("hello")));
+        assert_eq!(SeenTarget::parse("intro").unwrap().key("barks.adlib"), SeenKey::new("barks.adlib", "intro", None));
+        assert_eq!(SeenTarget::parse("intro#3").unwrap().line.as_deref(), Some("3"));
+        assert_eq!(SeenTarget::parse("intro#"), None);
+        assert_eq!(SeenTarget::parse(":intro"), None);
+        assert_eq!(SeenKey::new("dialogue/Act.1.adlib", "intro", None).filename, "dialogue/act.1.adlib");
+
+        let mut seen = SeenLines::default();
+        seen.record(SeenKey::new("barks.adlib", "Intro", Some("3")));
+        seen.record(SeenKey::new("barks.adlib", "intro", Some("3")));
+        assert_eq!(seen.count(&SeenKey::new("BARKS.adlib", "intro", Some("3"))), 2);
+        seen.set(SeenKey::new("barks.adlib", "intro", Some("3")), 0);
+        assert!(seen.sorted().is_empty());
+    }
+}
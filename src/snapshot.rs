@@ -0,0 +1,593 @@
+use std::fmt;
+
+use crate::dialogue::{CursorSnapshot, DialogueRef};
+use crate::dialogue_engine::{EngineSnapshot, Priority, QueueOptions, QueuePolicy, QueuedSnapshot};
+use crate::random::{VariantHistory, VariantKey};
+use crate::seen::SeenKey;
+use crate::talker::Talker;
+use crate::variables::Value;
+
+// Bump when the format changes, parse refuses versions it doesn't know.
+pub const SNAPSHOT_VERSION : u32 = 3;
+
+const HEADER : &str = "adlib-save";
+const SEEN_HEADER : &str = "adlib-seen";
+
+// Everything needed to carry on a conversation after loading a save. Saved as plain text,
+// one record per line, so it can sit inside whatever save format the game already has.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct Snapshot
+{
+    pub engine : EngineSnapshot,
+    pub variables : Vec<(String, Value)>,
+    // Sections and lines seen and how often, version 1 saves only had oneshots
+    pub seen : Vec<(SeenKey, u32)>,
+}
+
+// Just the seen counts, for games that keep them apart from save slots, eg to mark new
+// dialogue across playthroughs. Same records as in a Snapshot under its own header.
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct SeenSnapshot
+{
+    pub seen : Vec<(SeenKey, u32)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion(u32),
+    // 1-based line of the save and the line itself
+    BadRecord(usize, String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a dialogue save"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "save version {} is newer than {}", version, SNAPSHOT_VERSION),
+            SnapshotError::BadRecord(line, text) => write!(f, "could not read save line {}: '{}'", line, text),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// Fields are separated by spaces so anything free form is percent escaped.
+fn escape(s : &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | ' ' | '\t' | '\n' | '\r' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+
+    if (escaped.is_empty()) {
+        // Keep a placeholder so empty strings still take up a field
+        "%".to_owned()
+    }
+    else {
+        escaped
+    }
+}
+
+fn unescape(s : &str) -> Option<String> {
+    if (s == "%") {
+        return Some(String::new());
+    }
+
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if (byte == b'%') {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        }
+        else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+fn optional<T : fmt::Display>(x : &Option<T>) -> String {
+    match x {
+        Some(x) => x.to_string(),
+        None => "-".to_owned(),
+    }
+}
+
+fn parse_optional<T : std::str::FromStr>(s : &str) -> Option<Option<T>> {
+    if (s == "-") {
+        Some(None)
+    }
+    else {
+        s.parse::<T>().ok().map(Some)
+    }
+}
+
+fn flag(x : bool) -> &'static str {
+    if (x) { "1" } else { "0" }
+}
+
+fn parse_flag(s : &str) -> Option<bool> {
+    match s {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn seen_record(key : &SeenKey, count : u32) -> String {
+    let line = key.line.as_ref().map(|x| escape(x)).unwrap_or_else(|| "-".to_owned());
+    format!("seen {} {} {} {}", escape(&key.filename), escape(&key.section), line, count)
+}
+
+fn parse_seen_record(fields : &[&str]) -> Option<(SeenKey, u32)> {
+    let line = match *fields.get(2)? {
+        "-" => None,
+        line => Some(unescape(line)?),
+    };
+
+    let key = SeenKey::new(&unescape(fields.first()?)?, &unescape(fields.get(1)?)?, line.as_deref());
+    Some((key, fields.get(3)?.parse().ok()?))
+}
+
+// Checks the header and hands every non-blank line after it to parse_record, split into fields.
+fn parse_records(s : &str, expected_header : &str, parse_record : &mut dyn FnMut(&[&str]) -> Option<()>) -> Result<(), SnapshotError> {
+    let mut lines = s.lines().enumerate();
+
+    let header = lines.next().map(|(_, x)| x).unwrap_or_default();
+    let version = match header.split_once(' ') {
+        Some((header, version)) if header == expected_header => version.trim().parse::<u32>().map_err(|_| SnapshotError::NotASnapshot)?,
+        _ => return Err(SnapshotError::NotASnapshot),
+    };
+    if (version > SNAPSHOT_VERSION) {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    for (i, line) in lines {
+        if (line.trim().is_empty()) {
+            continue;
+        }
+
+        let fields = line.split(' ').collect::<Vec<_>>();
+        if (parse_record(&fields).is_none()) {
+            return Err(SnapshotError::BadRecord(i + 1, line.to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+fn queue_options(x : &QueueOptions) -> String {
+    let talker = x.talker.as_ref().map(|x| escape(x)).unwrap_or_else(|| "-".to_owned());
+    format!("{} {} {} {} {} {}", x.policy.name(), x.priority.name(), optional(&x.text_rate), optional(&x.line_linger_time), talker, optional(&x.seed))
+}
+
+fn parse_queue_options(fields : &[&str]) -> Option<QueueOptions> {
+    match fields {
+        [policy, priority, text_rate, line_linger_time, talker, seed] => Some(QueueOptions {
+            policy : QueuePolicy::parse(policy)?,
+            priority : Priority::parse(priority)?,
+            text_rate : parse_optional(text_rate)?,
+            line_linger_time : parse_optional(line_linger_time)?,
+            talker : match *talker {
+                "-" => None,
+                talker => Some(unescape(talker)?),
+            },
+            seed : parse_optional(seed)?,
+        }),
+        _ => None,
+    }
+}
+
+fn dialogue_ref(x : &DialogueRef) -> String {
+    format!("{} {} {:016x}", escape(&x.filename), escape(&x.section), x.fingerprint)
+}
+
+fn parse_dialogue_ref(fields : &[&str]) -> Option<DialogueRef> {
+    Some(DialogueRef {
+        filename : unescape(fields.first()?)?,
+        section : unescape(fields.get(1)?)?,
+        fingerprint : u64::from_str_radix(fields.get(2)?, 16).ok()?,
+    })
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", HEADER, SNAPSHOT_VERSION)?;
+
+        for (name, value) in &self.variables {
+            match value {
+                Value::Number(x) => writeln!(f, "var {} n {}", escape(name), x)?,
+                Value::Text(x) => writeln!(f, "var {} s {}", escape(name), escape(x))?,
+            }
+        }
+
+        for (key, count) in &self.seen {
+            writeln!(f, "{}", seen_record(key, *count))?;
+        }
+
+        let engine = &self.engine;
+        writeln!(f, "engine {} {} {} {} {}", engine.t, engine.line_linger_t, engine.blip_counter, flag(engine.awaiting_input), optional(&engine.vo_rate))?;
+        writeln!(f, "rng {:016x}", engine.variants.rng_state)?;
+        for (key, history) in &engine.variants.history {
+            let remaining = history.remaining.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",");
+            writeln!(f, "variant {} {} {} {} {} {}", escape(&key.filename), escape(&key.section), key.chunk, history.count, optional(&history.last), escape(&remaining))?;
+        }
+
+        if let Some(cursor) = &engine.cursor {
+            writeln!(f, "cursor {} {} {} {} {} {} {} {} {}",
+                escape(&cursor.root_filename), escape(&cursor.root_name), dialogue_ref(&cursor.dialogue),
+                cursor.start, cursor.end, cursor.line_i,
+                flag(cursor.in_line), flag(cursor.blocked), flag(cursor.exhausted))?;
+
+            for (caller, call_i) in &cursor.call_stack {
+                writeln!(f, "call {} {}", dialogue_ref(caller), call_i)?;
+            }
+
+            for (start, end) in &cursor.hidden {
+                writeln!(f, "hidden {} {}", start, end)?;
+            }
+
+            if let Some(talker) = &cursor.talker {
+                writeln!(f, "talker {} {} {} {}", escape(&talker.name), escape(&talker.sprite), escape(&talker.sound), optional(&talker.rate))?;
+            }
+
+            if let Some(line_id) = &cursor.line_id {
+                writeln!(f, "line_id {}", escape(line_id))?;
+            }
+        }
+
+        writeln!(f, "playback {}", queue_options(&engine.playback))?;
+        for queued in &engine.queued {
+            writeln!(f, "queued {} {} {}", escape(&queued.filename), escape(&queued.section), queue_options(&queued.options))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    pub fn parse(s : &str) -> Result<Self, SnapshotError> {
+        let mut snapshot = Snapshot::default();
+        parse_records(s, HEADER, &mut |fields| snapshot.parse_record(fields))?;
+        Ok(snapshot)
+    }
+
+    fn parse_record(&mut self, fields : &[&str]) -> Option<()> {
+        match fields {
+            ["var", name, "n", x] => {
+                self.variables.push((unescape(name)?, Value::Number(x.parse().ok()?)));
+            },
+            ["var", name, "s", x] => {
+                self.variables.push((unescape(name)?, Value::Text(unescape(x)?)));
+            },
+            ["seen", fields @ ..] => {
+                self.seen.push(parse_seen_record(fields)?);
+            },
+            // Version 1 kept oneshots by the name they were queued with, which is a section
+            // seen once
+            ["oneshot", filename, section] => {
+                self.seen.push((SeenKey::new(&(unescape(filename)? + ".adlib"), &unescape(section)?, None), 1));
+            },
+            ["engine", t, line_linger_t, blip_counter, awaiting_input, vo_rate] => {
+                self.engine.t = t.parse().ok()?;
+                self.engine.line_linger_t = line_linger_t.parse().ok()?;
+                self.engine.blip_counter = blip_counter.parse().ok()?;
+                self.engine.awaiting_input = parse_flag(awaiting_input)?;
+                self.engine.vo_rate = parse_optional(vo_rate)?;
+            },
+            ["rng", state] => {
+                self.engine.variants.rng_state = u64::from_str_radix(state, 16).ok()?;
+            },
+            ["variant", filename, section, chunk, count, last, remaining] => {
+                let remaining = unescape(remaining)?;
+                let remaining = if (remaining.is_empty()) {
+                    vec![]
+                }
+                else {
+                    remaining.split(',').map(|x| x.parse::<usize>().ok()).collect::<Option<Vec<_>>>()?
+                };
+
+                self.engine.variants.history.push((
+                    VariantKey::new(&unescape(filename)?, &unescape(section)?, chunk.parse().ok()?),
+                    VariantHistory {
+                        count : count.parse().ok()?,
+                        remaining,
+                        last : parse_optional(last)?,
+                    },
+                ));
+            },
+            ["cursor", root_filename, root_name, dialogue @ .., start, end, line_i, in_line, blocked, exhausted] if dialogue.len() == 3 => {
+                self.engine.cursor = Some(CursorSnapshot {
+                    root_name : unescape(root_name)?,
+                    root_filename : unescape(root_filename)?,
+                    dialogue : parse_dialogue_ref(dialogue)?,
+                    call_stack : vec![],
+                    hidden : vec![],
+                    talker : None,
+                    in_line : parse_flag(in_line)?,
+                    line_id : None,
+                    blocked : parse_flag(blocked)?,
+                    start : start.parse().ok()?,
+                    end : end.parse().ok()?,
+                    line_i : line_i.parse().ok()?,
+                    exhausted : parse_flag(exhausted)?,
+                });
+            },
+            // The rest belong to the cursor line before them
+            ["call", caller @ .., call_i] if caller.len() == 3 => {
+                let cursor = self.engine.cursor.as_mut()?;
+                cursor.call_stack.push((parse_dialogue_ref(caller)?, call_i.parse().ok()?));
+            },
+            ["hidden", start, end] => {
+                let cursor = self.engine.cursor.as_mut()?;
+                cursor.hidden.push((start.parse().ok()?, end.parse().ok()?));
+            },
+            ["talker", name, sprite, sound, rate] => {
+                let cursor = self.engine.cursor.as_mut()?;
+                cursor.talker = Some(Talker {
+                    name : unescape(name)?,
+                    sprite : unescape(sprite)?,
+                    sound : unescape(sound)?,
+                    rate : parse_optional(rate)?,
+                });
+            },
+            ["line_id", line_id] => {
+                let cursor = self.engine.cursor.as_mut()?;
+                cursor.line_id = Some(unescape(line_id)?);
+            },
+            // Versions before 3 didn't keep these, saves from then play with defaults
+            ["playback", options @ ..] => {
+                self.engine.playback = parse_queue_options(options)?;
+            },
+            ["queued", filename, section, options @ ..] => {
+                self.engine.queued.push(QueuedSnapshot {
+                    filename : unescape(filename)?,
+                    section : unescape(section)?,
+                    options : parse_queue_options(options)?,
+                });
+            },
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    // Rewrites every filename, eg to store them relative to a base path. Keys go back through
+    // their constructors so they stay normalized.
+    pub fn map_filenames(&mut self, f : &dyn Fn(&str) -> String) {
+        if let Some(cursor) = self.engine.cursor.as_mut() {
+            cursor.root_filename = f(&cursor.root_filename);
+            cursor.dialogue.filename = f(&cursor.dialogue.filename);
+            for (caller, _) in cursor.call_stack.iter_mut() {
+                caller.filename = f(&caller.filename);
+            }
+        }
+
+        for queued in self.engine.queued.iter_mut() {
+            queued.filename = f(&queued.filename);
+        }
+
+        for (key, _) in self.engine.variants.history.iter_mut() {
+            *key = VariantKey::new(&f(&key.filename), &key.section, key.chunk);
+        }
+
+        for (key, _) in self.seen.iter_mut() {
+            *key = SeenKey::new(&f(&key.filename), &key.section, key.line.as_deref());
+        }
+    }
+}
+
+impl fmt::Display for SeenSnapshot {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", SEEN_HEADER, SNAPSHOT_VERSION)?;
+        for (key, count) in &self.seen {
+            writeln!(f, "{}", seen_record(key, *count))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SeenSnapshot {
+    pub fn parse(s : &str) -> Result<Self, SnapshotError> {
+        let mut snapshot = SeenSnapshot::default();
+        parse_records(s, SEEN_HEADER, &mut |fields| {
+            match fields {
+                ["seen", fields @ ..] => snapshot.seen.push(parse_seen_record(fields)?),
+                _ => return None,
+            }
+            Some(())
+        })?;
+        Ok(snapshot)
+    }
+
+    // Same as Snapshot::map_filenames.
+    pub fn map_filenames(&mut self, f : &dyn Fn(&str) -> String) {
+        for (key, _) in self.seen.iter_mut() {
+            *key = SeenKey::new(&f(&key.filename), &key.section, key.line.as_deref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::dialogue::DialogueFile;
+    use crate::dialogue_engine::{DialogueEngine, RestoreOutcome};
+    use crate::variables::Variables;
+
+    const SOURCE : &str = "[talker goose]
+sound = snd_goose
+
+[talker toad]
+rate = 0.5
+
+[intro]
+goose | hello (call aside) there
+(random shuffle)
+one
+(variant)
+two
+(endrandom)
+bye
+
+[aside]
+toad | psst, 100% secret
+and more";
+
+    fn lookup_in(file : &DialogueFile) -> impl Fn(&str, &str) -> Option<crate::dialogue::Dialogue> + '_ {
+        move |_, section| file.get(section).cloned()
+    }
+
+    #[test]
+    fn test_round_trip()
+    {
+        let file = DialogueFile::parse_contents("test", SOURCE).unwrap();
+        let mut engine = DialogueEngine::default();
+        let mut variables = Variables::default();
+        variables.set("mood", Value::Text("very happy".to_owned()));
+        variables.set("count", Value::Number(2.5));
+        engine.queue(file.get("intro").unwrap());
+
+        // Into the called section
+        for _ in 0..20 {
+            engine.tick(1.0, &mut variables);
+            if let Some(target) = engine.pending_jump().cloned() {
+                engine.jump(file.get(&target.section).unwrap());
+            }
+        }
+
+        let snapshot = Snapshot {
+            engine : engine.snapshot(),
+            variables : variables.iter().map(|(k, v)| (k.to_owned(), v.clone())).collect(),
+            seen : vec![(SeenKey::new("test.adlib", "intro", None), 1), (SeenKey::new("test.adlib", "aside", Some("a line")), 3)],
+        };
+        let saved = snapshot.to_string();
+        assert!(saved.starts_with("adlib-save 3\n"));
+        assert_eq!(Snapshot::parse(&saved).unwrap(), snapshot);
+
+        // Oneshots from version 1 are seen sections
+        let old = Snapshot::parse("adlib-save 1\noneshot test intro").unwrap();
+        assert_eq!(old.seen, vec![(SeenKey::new("test.adlib", "intro", None), 1)]);
+
+        let seen = SeenSnapshot { seen : snapshot.seen.clone() };
+        assert_eq!(SeenSnapshot::parse(&seen.to_string()).unwrap(), seen);
+        assert_eq!(SeenSnapshot::parse(&saved), Err(SnapshotError::NotASnapshot));
+
+        let mut restored = DialogueEngine::default();
+        assert_eq!(restored.restore(&Snapshot::parse(&saved).unwrap().engine, &lookup_in(&file)), RestoreOutcome::Resumed);
+        assert_eq!(restored.current_talker().map(|x| &x.name[..]), Some("toad"));
+
+        // Both carry on the same way, including back out of the call and the shuffle
+        let mut restored_variables = variables.clone();
+        engine.drain_events();
+        for _ in 0..200 {
+            engine.tick(1.0, &mut variables);
+            restored.tick(1.0, &mut restored_variables);
+            assert_eq!(engine.current_string_iter().next().map(|x| x.0.to_owned()), restored.current_string_iter().next().map(|x| x.0.to_owned()));
+            assert_eq!(engine.drain_events(), restored.drain_events());
+        }
+    }
+
+    #[test]
+    fn test_queue_round_trip()
+    {
+        let file = DialogueFile::parse_contents("test", SOURCE).unwrap();
+        let mut engine = DialogueEngine::default();
+        engine.queue_with(file.get("intro").unwrap(), &QueueOptions { priority : Priority::High, ..Default::default() });
+        engine.queue_with(file.get("aside").unwrap(), &QueueOptions { policy : QueuePolicy::Enqueue, priority : Priority::Low, ..Default::default() });
+
+        let snapshot = Snapshot { engine : engine.snapshot(), ..Default::default() };
+        assert_eq!(snapshot.engine.playback.priority, Priority::High);
+        assert_eq!(snapshot.engine.queued.len(), 1);
+        let saved = Snapshot::parse(&snapshot.to_string()).unwrap();
+        assert_eq!(saved, snapshot);
+
+        let mut restored = DialogueEngine::default();
+        assert_eq!(restored.restore(&saved.engine, &lookup_in(&file)), RestoreOutcome::Resumed);
+        assert_eq!(restored.snapshot(), snapshot.engine);
+
+        // Starting over keeps the priority and what's waiting
+        let edited = DialogueFile::parse_contents("test", &SOURCE.replace("bye", "see you")).unwrap();
+        assert_eq!(restored.restore(&saved.engine, &lookup_in(&edited)), RestoreOutcome::Restarted);
+        assert_eq!(restored.snapshot().playback, snapshot.engine.playback);
+        assert_eq!(restored.snapshot().queued, snapshot.engine.queued);
+
+        // With intro gone the queued aside plays
+        let removed = DialogueFile::parse_contents("test", "[aside]\npsst").unwrap();
+        assert_eq!(restored.restore(&saved.engine, &lookup_in(&removed)), RestoreOutcome::Dropped);
+        assert_eq!(restored.current_filename(), Some("test"));
+        assert!(restored.snapshot().queued.is_empty());
+    }
+
+    #[test]
+    fn test_override_round_trip()
+    {
+        let file = DialogueFile::parse_contents("test", SOURCE).unwrap();
+        let options = QueueOptions {
+            text_rate : Some(0.25),
+            line_linger_time : Some(30.0),
+            talker : Some("old toad".to_owned()),
+            seed : Some(42),
+            ..Default::default()
+        };
+        let mut engine = DialogueEngine::default();
+        engine.queue_with(file.get("intro").unwrap(), &options);
+        engine.queue_with(file.get("aside").unwrap(), &QueueOptions { policy : QueuePolicy::Enqueue, ..options.clone() });
+
+        let snapshot = Snapshot { engine : engine.snapshot(), ..Default::default() };
+        let saved = Snapshot::parse(&snapshot.to_string()).unwrap();
+        assert_eq!(saved.engine.playback, options);
+        assert_eq!(saved.engine.queued[0].options.policy, QueuePolicy::Enqueue);
+        assert_eq!(saved, snapshot);
+
+        let mut restored = DialogueEngine::default();
+        assert_eq!(restored.restore(&saved.engine, &lookup_in(&file)), RestoreOutcome::Resumed);
+        assert_eq!(restored.snapshot().playback, options);
+
+        // Same for starting over, and it plays at the overridden rate
+        let edited = DialogueFile::parse_contents("test", &SOURCE.replace("bye", "see you")).unwrap();
+        assert_eq!(restored.restore(&saved.engine, &lookup_in(&edited)), RestoreOutcome::Restarted);
+        assert_eq!(restored.snapshot().playback, options);
+        restored.tick(1.0, &mut Variables::default());
+        assert_eq!(restored.snapshot().t, 0.25);
+    }
+
+    #[test]
+    fn test_changed_source()
+    {
+        let file = DialogueFile::parse_contents("test", SOURCE).unwrap();
+        let mut engine = DialogueEngine::default();
+        engine.queue(file.get("intro").unwrap());
+        for _ in 0..20 {
+            engine.tick(1.0, &mut Variables::default());
+            if let Some(target) = engine.pending_jump().cloned() {
+                engine.jump(file.get(&target.section).unwrap());
+            }
+        }
+        let saved = engine.snapshot();
+
+        // The called section was edited, start the conversation over
+        let edited = DialogueFile::parse_contents("test", &SOURCE.replace("and more", "and even more")).unwrap();
+        let mut restored = DialogueEngine::default();
+        assert_eq!(restored.restore(&saved, &lookup_in(&edited)), RestoreOutcome::Restarted);
+        assert_eq!(restored.current_talker().map(|x| &x.name[..]), Some("goose"));
+
+        // The whole conversation is gone
+        let removed = DialogueFile::parse_contents("test", "[other]\nhi").unwrap();
+        assert_eq!(restored.restore(&saved, &lookup_in(&removed)), RestoreOutcome::Dropped);
+        assert!(restored.current_string_iter().next().map(|x| x.0.is_empty()).unwrap_or(true));
+
+        assert_eq!(Snapshot::parse("adlib-save 99\n"), Err(SnapshotError::UnsupportedVersion(99)));
+        assert_eq!(Snapshot::parse("hello"), Err(SnapshotError::NotASnapshot));
+        assert_eq!(Snapshot::parse("adlib-save 1\nhidden 1 2"), Err(SnapshotError::BadRecord(2, "hidden 1 2".to_owned())));
+    }
+}
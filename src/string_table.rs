@@ -0,0 +1,785 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::custom_commands::CustomCommands;
+use crate::dialogue::{is_command_line, split_choice_option, split_line_id, split_talker, tokenize_line, DialogueFile, LINE_ID_PREFIX};
+use crate::parse_error::ParseError;
+
+// One translatable line, either text or a choice option.
+// Commands inside the text become {0}, {1}... placeholders that translators can move but must keep.
+// A literal { in the source is written as {{ so it can't be mistaken for one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringEntry
+{
+    // "file:line id" for lines with an #id: tag, otherwise "file:section:index" which is only
+    // stable as long as lines aren't added or removed above it in its section
+    pub id : String,
+    pub file : String,
+    pub section : String,
+    // Index among the translatable lines of the section
+    pub index : usize,
+    pub talker : Option<String>,
+    pub text : String,
+    // Command for each placeholder, in order
+    pub placeholders : Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StringTableErrorKind {
+    MissingPlaceholder(usize),
+    RepeatedPlaceholder(usize),
+    UnknownPlaceholder(usize),
+    // The table itself couldn't be read
+    BadTable(String),
+    // The base file or the translated result doesn't parse
+    Parse(ParseError),
+}
+
+impl fmt::Display for StringTableErrorKind {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StringTableErrorKind::MissingPlaceholder(n) => write!(f, "translation is missing {{{}}}", n),
+            StringTableErrorKind::RepeatedPlaceholder(n) => write!(f, "translation uses {{{}}} more than once", n),
+            StringTableErrorKind::UnknownPlaceholder(n) => write!(f, "source has no {{{}}}", n),
+            StringTableErrorKind::BadTable(err) => write!(f, "could not read table: {}", err),
+            StringTableErrorKind::Parse(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StringTableError
+{
+    // Empty when the error isn't about one line
+    pub id : String,
+    pub kind : StringTableErrorKind,
+}
+
+impl StringTableError {
+    fn new(id : &str, kind : StringTableErrorKind) -> Self {
+        Self {
+            id : id.to_owned(),
+            kind,
+        }
+    }
+
+    fn parse_errors(errors : Vec<ParseError>) -> Vec<Self> {
+        errors.into_iter().map(|x| Self::new("", StringTableErrorKind::Parse(x))).collect()
+    }
+}
+
+impl fmt::Display for StringTableError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        if (self.id.is_empty()) {
+            write!(f, "{}", self.kind)
+        }
+        else {
+            write!(f, "{}: {}", self.id, self.kind)
+        }
+    }
+}
+
+// Result of applying a table, the translated file and the lines that kept their source text.
+#[derive(Clone, Debug, Default)]
+pub struct Translated
+{
+    pub contents : String,
+    pub untranslated : Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum LineKind {
+    Text,
+    Choice,
+}
+
+// Where a translatable line is in the source
+struct SourceLine<'a>
+{
+    // Index into the lines of the file
+    line_i : usize,
+    section : &'a str,
+    kind : LineKind,
+    // The part of the line that gets translated, the rest is kept as is
+    body : &'a str,
+    talker : Option<&'a str>,
+    // Without the #id: prefix
+    line_id : Option<&'a str>,
+}
+
+// Walks the file the same way DialogueFile::parse_contents does.
+fn source_lines<'a>(lines : &[&'a str]) -> Vec<SourceLine<'a>> {
+    let mut found = vec![];
+    let mut section : Option<&str> = None;
+    for (line_i, line) in lines.iter().enumerate() {
+        if (line.is_empty() || line.starts_with("#")) {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.trim_end_matches(']');
+            section = match name.split_once(" ") {
+                Some((keyword, _)) if unicase::eq_ascii(keyword, "talker") => None,
+                _ => Some(name),
+            };
+            continue;
+        }
+
+        let section = match section {
+            Some(section) => section,
+            // Talker fields or text before the first header
+            None => continue,
+        };
+
+        if (line.starts_with(">")) {
+            let (body, _) = split_choice_option(line);
+            found.push(SourceLine { line_i, section, kind : LineKind::Choice, body, talker : None, line_id : None });
+        }
+        else if (!is_command_line(line)) {
+            let (talker, body) = split_talker(line);
+            let (body, tag) = split_line_id(body);
+            let line_id = tag.map(|x| &x[LINE_ID_PREFIX.len()..]);
+            found.push(SourceLine { line_i, section, kind : LineKind::Text, body, talker, line_id });
+        }
+    }
+
+    found
+}
+
+fn escape_braces(text : &str) -> String {
+    text.replace('{', "{{")
+}
+
+// Text with commands replaced by placeholders, and the commands.
+fn with_placeholders(body : &str) -> (String, Vec<String>) {
+    let mut text = String::new();
+    let mut placeholders = vec![];
+    for token in tokenize_line(body) {
+        if (!text.is_empty()) {
+            text.push(' ');
+        }
+
+        if (token.starts_with("(")) {
+            text.push_str(&format!("{{{}}}", placeholders.len()));
+            placeholders.push(token.to_owned());
+        }
+        else {
+            text.push_str(&escape_braces(token));
+        }
+    }
+
+    (text, placeholders)
+}
+
+// Puts the commands back in a translation, checking every placeholder is used exactly once.
+fn fill_placeholders(id : &str, translation : &str, placeholders : &[String]) -> Result<String, Vec<StringTableError>> {
+    let mut errors = vec![];
+    let mut used = vec![0; placeholders.len()];
+    let mut filled = String::new();
+    let mut rest = translation;
+
+    while let Some(open) = rest.find('{') {
+        if (rest[(open + 1)..].starts_with('{')) {
+            // Escaped brace
+            filled.push_str(&rest[..=open]);
+            rest = &rest[(open + 2)..];
+            continue;
+        }
+
+        let number = rest[(open + 1)..].find('}').and_then(|close| rest[(open + 1)..(open + 1 + close)].parse::<usize>().ok().map(|n| (n, close)));
+        match number {
+            Some((n, close)) => {
+                filled.push_str(&rest[..open]);
+                match placeholders.get(n) {
+                    Some(command) => {
+                        filled.push_str(command);
+                        used[n] += 1;
+                    },
+                    None => errors.push(StringTableError::new(id, StringTableErrorKind::UnknownPlaceholder(n))),
+                }
+                rest = &rest[(open + close + 2)..];
+            },
+            None => {
+                // Just a brace
+                filled.push_str(&rest[..=open]);
+                rest = &rest[(open + 1)..];
+            },
+        }
+    }
+    filled.push_str(rest);
+
+    for (n, count) in used.into_iter().enumerate() {
+        if (count == 0) {
+            errors.push(StringTableError::new(id, StringTableErrorKind::MissingPlaceholder(n)));
+        }
+        else if (count > 1) {
+            errors.push(StringTableError::new(id, StringTableErrorKind::RepeatedPlaceholder(n)));
+        }
+    }
+
+    if (errors.is_empty()) {
+        Ok(filled)
+    }
+    else {
+        Err(errors)
+    }
+}
+
+fn file_id(filename : &str) -> String {
+    std::path::Path::new(filename)
+        .file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|| filename.to_owned())
+}
+
+// Every translatable line in a file, in order. The file has to parse with the game's
+// custom commands.
+pub fn extract(filename : &str, contents : &str, custom_commands : &CustomCommands) -> Result<Vec<StringEntry>, Vec<ParseError>> {
+    DialogueFile::parse_contents_with(filename, contents, custom_commands)?;
+
+    let file = file_id(filename);
+    let lines = contents.lines().collect::<Vec<_>>();
+    let mut entries = vec![];
+    let mut indices : HashMap<String, usize> = HashMap::new();
+    for source_line in source_lines(&lines) {
+        let index = indices.entry(source_line.section.to_ascii_lowercase()).or_default();
+        let (text, placeholders) = match source_line.kind {
+            LineKind::Text => with_placeholders(source_line.body),
+            LineKind::Choice => (escape_braces(source_line.body), vec![]),
+        };
+
+        let id = match source_line.line_id {
+            Some(line_id) => format!("{}:{}", file, line_id),
+            None => format!("{}:{}:{}", file, source_line.section, index),
+        };
+
+        entries.push(StringEntry {
+            id,
+            file : file.clone(),
+            section : source_line.section.to_owned(),
+            index : *index,
+            talker : source_line.talker.map(|x| x.to_owned()),
+            text,
+            placeholders,
+        });
+        *index += 1;
+    }
+
+    Ok(entries)
+}
+
+// Rewrites a base file with translations keyed by StringEntry::id. Lines without a translation
+// keep their source text and are listed in Translated::untranslated.
+pub fn apply(filename : &str, contents : &str, translations : &HashMap<String, String>, custom_commands : &CustomCommands) -> Result<Translated, Vec<StringTableError>> {
+    let entries = extract(filename, contents, custom_commands).map_err(StringTableError::parse_errors)?;
+
+    let original = contents.lines().collect::<Vec<_>>();
+    let mut replaced : HashMap<usize, String> = HashMap::new();
+    let mut errors = vec![];
+    let mut untranslated = vec![];
+
+    for (entry, source_line) in entries.iter().zip(source_lines(&original)) {
+        let translation = match translations.get(&entry.id).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            Some(translation) => translation,
+            None => {
+                untranslated.push(entry.id.clone());
+                continue;
+            },
+        };
+
+        let filled = match fill_placeholders(&entry.id, translation, &entry.placeholders) {
+            Ok(filled) => filled,
+            Err(mut fill_errors) => {
+                errors.append(&mut fill_errors);
+                continue;
+            },
+        };
+
+        // Keep the talker prefix or choice target around the translated part
+        let line = original[source_line.line_i];
+        let body_start = source_line.body.as_ptr() as usize - line.as_ptr() as usize;
+        let body_end = body_start + source_line.body.len();
+        replaced.insert(source_line.line_i, format!("{}{}{}", &line[..body_start], filled, &line[body_end..]));
+    }
+
+    if (!errors.is_empty()) {
+        return Err(errors);
+    }
+
+    // Same as assign_line_ids, only the content of a line changes and never its ending
+    let mut translated = String::with_capacity(contents.len());
+    for (line_i, raw) in contents.split_inclusive('\n').enumerate() {
+        match replaced.get(&line_i) {
+            Some(line) => {
+                translated.push_str(line);
+                translated.push_str(&raw[raw.trim_end_matches(['\n', '\r']).len()..]);
+            },
+            None => translated.push_str(raw),
+        }
+    }
+
+    // Catch translations that break the line, eg an unmatched bracket
+    DialogueFile::parse_contents_with(filename, &translated, custom_commands).map_err(StringTableError::parse_errors)?;
+
+    Ok(Translated {
+        contents : translated,
+        untranslated,
+    })
+}
+
+// Adds an #id: tag to every text line without one, leaving everything else as it was.
+// New ids are the section name and a number, skipping any already used in the file.
+pub fn assign_line_ids(filename : &str, contents : &str, custom_commands : &CustomCommands) -> Result<String, Vec<ParseError>> {
+    DialogueFile::parse_contents_with(filename, contents, custom_commands)?;
+
+    let lines = contents.lines().collect::<Vec<_>>();
+    let source_lines = source_lines(&lines);
+    let mut used = source_lines.iter().filter_map(|x| x.line_id).map(|x| x.to_owned()).collect::<HashSet<_>>();
+    let mut next_numbers : HashMap<String, usize> = HashMap::new();
+    let mut new_ids : HashMap<usize, String> = HashMap::new();
+
+    for source_line in source_lines.iter().filter(|x| x.kind == LineKind::Text && x.line_id.is_none()) {
+        let prefix = source_line.section.split_whitespace().collect::<Vec<_>>().join("_").to_ascii_lowercase();
+        let next_number = next_numbers.entry(prefix.clone()).or_insert(1);
+        let id = loop {
+            let id = format!("{}_{}", prefix, next_number);
+            *next_number += 1;
+            if (used.insert(id.clone())) {
+                break id;
+            }
+        };
+        new_ids.insert(source_line.line_i, id);
+    }
+
+    // split_inclusive keeps line endings as they were, including \r\n
+    let mut out = String::with_capacity(contents.len());
+    for (line_i, raw) in contents.split_inclusive('\n').enumerate() {
+        match new_ids.get(&line_i) {
+            Some(id) => {
+                let content = raw.trim_end_matches(['\n', '\r']);
+                out.push_str(content.trim_end());
+                out.push_str(&format!(" {}{}", LINE_ID_PREFIX, id));
+                out.push_str(&raw[content.len()..]);
+            },
+            None => out.push_str(raw),
+        }
+    }
+
+    Ok(out)
+}
+
+fn csv_field(s : &str) -> String {
+    if (s.contains([',', '"', '\n', '\r'])) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    }
+    else {
+        s.to_owned()
+    }
+}
+
+fn placeholder_notes(entry : &StringEntry) -> String {
+    entry.placeholders.iter().enumerate().map(|(n, x)| format!("{{{}}} = {}", n, x)).collect::<Vec<_>>().join("; ")
+}
+
+// One row per entry with an empty translation column to fill in.
+pub fn to_csv(entries : &[StringEntry]) -> String {
+    let mut out = String::from("id,file,section,line,talker,text,translation,notes\n");
+    for entry in entries {
+        let fields = [
+            csv_field(&entry.id),
+            csv_field(&entry.file),
+            csv_field(&entry.section),
+            entry.index.to_string(),
+            csv_field(entry.talker.as_deref().unwrap_or_default()),
+            csv_field(&entry.text),
+            String::new(),
+            csv_field(&placeholder_notes(entry)),
+        ];
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+
+    out
+}
+
+fn csv_records(contents : &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if (quoted) {
+            if (c == '"') {
+                if (chars.peek() == Some(&'"')) {
+                    field.push('"');
+                    chars.next();
+                }
+                else {
+                    quoted = false;
+                }
+            }
+            else {
+                field.push(c);
+            }
+        }
+        else {
+            match c {
+                '"' => quoted = true,
+                ',' => record.push(std::mem::take(&mut field)),
+                '\r' => {},
+                '\n' => {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                },
+                _ => field.push(c),
+            }
+        }
+    }
+
+    if (quoted) {
+        return Err("unterminated quote".to_owned());
+    }
+
+    if (!field.is_empty() || !record.is_empty()) {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+// Translations by id from a table in the to_csv layout. Only the id and translation columns
+// are needed, so translators can add or reorder columns.
+pub fn read_csv(contents : &str) -> Result<HashMap<String, String>, StringTableError> {
+    let bad_table = |err : &str| StringTableError::new("", StringTableErrorKind::BadTable(err.to_owned()));
+
+    let records = csv_records(contents).map_err(|x| bad_table(&x))?;
+    let header = records.first().ok_or_else(|| bad_table("empty table"))?;
+    let column = |name : &str| header.iter().position(|x| unicase::eq_ascii(x.trim(), name));
+    let id_column = column("id").ok_or_else(|| bad_table("no id column"))?;
+    let translation_column = column("translation").ok_or_else(|| bad_table("no translation column"))?;
+
+    let mut translations = HashMap::new();
+    for record in &records[1..] {
+        if let (Some(id), Some(translation)) = (record.get(id_column), record.get(translation_column)) {
+            if (!id.is_empty()) {
+                translations.insert(id.clone(), translation.clone());
+            }
+        }
+    }
+
+    Ok(translations)
+}
+
+fn xml_escape(s : &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn xml_unescape(s : &str) -> String {
+    s.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+// Placeholders become inline <x/> elements so translation tools protect them. Escaped braces
+// are left alone.
+fn xliff_segment(text : &str, placeholders : usize) -> String {
+    let mut segment = String::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        segment.push_str(&xml_escape(&rest[..open]));
+        let after = &rest[(open + 1)..];
+        if let Some(after) = after.strip_prefix('{') {
+            segment.push_str("{{");
+            rest = after;
+            continue;
+        }
+
+        let number = after.find('}').and_then(|close| after[..close].parse::<usize>().ok().filter(|n| *n < placeholders).map(|n| (n, close)));
+        match number {
+            Some((n, close)) => {
+                segment.push_str(&format!("<x id=\"{}\"/>", n));
+                rest = &after[(close + 1)..];
+            },
+            None => {
+                segment.push('{');
+                rest = after;
+            },
+        }
+    }
+    segment.push_str(&xml_escape(rest));
+
+    segment
+}
+
+// XLIFF 1.2 with a trans-unit per entry and no targets yet.
+pub fn to_xliff(entries : &[StringEntry], source_language : &str, target_language : Option<&str>) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">\n");
+
+    let mut files : Vec<&str> = vec![];
+    for entry in entries {
+        if (!files.contains(&&entry.file[..])) {
+            files.push(&entry.file);
+        }
+    }
+
+    for file in files {
+        let target_attr = target_language.map(|x| format!(" target-language=\"{}\"", xml_escape(x))).unwrap_or_default();
+        out.push_str(&format!("  <file original=\"{}.adlib\" source-language=\"{}\"{} datatype=\"plaintext\">\n    <body>\n", xml_escape(file), xml_escape(source_language), target_attr));
+
+        for entry in entries.iter().filter(|x| x.file == file) {
+            out.push_str(&format!("      <trans-unit id=\"{}\">\n", xml_escape(&entry.id)));
+            out.push_str(&format!("        <source>{}</source>\n", xliff_segment(&entry.text, entry.placeholders.len())));
+            if let Some(talker) = &entry.talker {
+                out.push_str(&format!("        <note from=\"talker\">{}</note>\n", xml_escape(talker)));
+            }
+            if (!entry.placeholders.is_empty()) {
+                out.push_str(&format!("        <note from=\"placeholders\">{}</note>\n", xml_escape(&placeholder_notes(entry))));
+            }
+            out.push_str("      </trans-unit>\n");
+        }
+
+        out.push_str("    </body>\n  </file>\n");
+    }
+
+    out.push_str("</xliff>\n");
+    out
+}
+
+// Value of attribute name in the start tag tag.
+fn xml_attribute(tag : &str, name : &str) -> Option<String> {
+    let start = tag.find(&format!("{}=\"", name))? + name.len() + 2;
+    let end = tag[start..].find('"')? + start;
+    Some(xml_unescape(&tag[start..end]))
+}
+
+// Segment text back to the placeholder form, dropping any other inline markup.
+fn xliff_text(segment : &str) -> String {
+    let mut text = String::new();
+    let mut rest = segment;
+    while let Some(open) = rest.find('<') {
+        text.push_str(&xml_unescape(&rest[..open]));
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => break,
+        };
+
+        let tag = &rest[open..=close];
+        if (tag.starts_with("<x ")) {
+            if let Some(id) = xml_attribute(tag, "id") {
+                text.push_str(&format!("{{{}}}", id));
+            }
+        }
+        rest = &rest[(close + 1)..];
+    }
+    text.push_str(&xml_unescape(rest));
+
+    text
+}
+
+// Translations by id from the targets of an XLIFF file. Only reads what to_xliff writes plus
+// targets, which is what translation tools hand back.
+pub fn read_xliff(contents : &str) -> Result<HashMap<String, String>, StringTableError> {
+    let mut translations = HashMap::new();
+    let mut rest = contents;
+
+    while let Some(unit_start) = rest.find("<trans-unit") {
+        let unit_end = rest[unit_start..].find("</trans-unit>")
+            .ok_or_else(|| StringTableError::new("", StringTableErrorKind::BadTable("unterminated trans-unit".to_owned())))? + unit_start;
+        let unit = &rest[unit_start..unit_end];
+
+        let start_tag = &unit[..unit.find('>').unwrap_or(unit.len())];
+        let id = xml_attribute(start_tag, "id")
+            .ok_or_else(|| StringTableError::new("", StringTableErrorKind::BadTable("trans-unit without an id".to_owned())))?;
+
+        if let Some(target_start) = unit.find("<target") {
+            let content_start = unit[target_start..].find('>').map(|x| target_start + x + 1);
+            let content_end = unit.find("</target>");
+            if let (Some(content_start), Some(content_end)) = (content_start, content_end) {
+                translations.insert(id, xliff_text(&unit[content_start..content_end]));
+            }
+        }
+
+        rest = &rest[(unit_end + "</trans-unit>".len())..];
+    }
+
+    Ok(translations)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SOURCE : &str = "[talker goose]
+sound = snd_goose
+
+[intro]
+goose | hello (j) there, friend (/j)
+(wait 10)
+want a toad?
+> yes -> accept
+> no
+
+[accept]
+here you go
+";
+
+    #[test]
+    fn test_extract()
+    {
+        let entries = extract("dir/intro.adlib", SOURCE, &CustomCommands::default()).unwrap();
+        let summary = entries.iter().map(|x| (&x.id[..], x.talker.as_deref(), &x.text[..])).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            ("intro:intro:0", Some("goose"), "hello {0} there, friend {1}"),
+            ("intro:intro:1", None, "want a toad?"),
+            ("intro:intro:2", None, "yes"),
+            ("intro:intro:3", None, "no"),
+            ("intro:accept:0", None, "here you go"),
+        ]);
+        assert_eq!(entries[0].placeholders, vec!["(j)", "(/j)"]);
+    }
+
+    #[test]
+    fn test_csv_round_trip()
+    {
+        let entries = extract("intro.adlib", SOURCE, &CustomCommands::default()).unwrap();
+        let csv = to_csv(&entries);
+        assert!(csv.contains("intro:intro:0,intro,intro,0,goose,\"hello {0} there, friend {1}\",,{0} = (j); {1} = (/j)\n"));
+
+        // What a translator might send back, with a placeholder moved
+        let filled = csv
+            .replace("\"hello {0} there, friend {1}\",,", "\"hello {0} there, friend {1}\",\"{0} salut, \"\"ami\"\" {1}\",")
+            .replace("yes,,", "yes,oui,");
+        let translations = read_csv(&filled).unwrap();
+        assert_eq!(translations["intro:intro:0"], "{0} salut, \"ami\" {1}");
+
+        let translated = apply("intro.adlib", SOURCE, &translations, &CustomCommands::default()).unwrap();
+        assert!(translated.contents.contains("goose | (j) salut, \"ami\" (/j)\n"));
+        assert!(translated.contents.contains("> oui -> accept\n"));
+        assert!(translated.contents.contains("(wait 10)\n"));
+        assert_eq!(translated.untranslated, vec!["intro:intro:1", "intro:intro:3", "intro:accept:0"]);
+    }
+
+    #[test]
+    fn test_xliff_round_trip()
+    {
+        let entries = extract("intro.adlib", SOURCE, &CustomCommands::default()).unwrap();
+        let xliff = to_xliff(&entries, "en", Some("fr"));
+        assert!(xliff.contains("<source>hello <x id=\"0\"/> there, friend <x id=\"1\"/></source>"));
+
+        let filled = xliff.replace("<source>here you go</source>", "<source>here you go</source>\n        <target>voilà &lt;3</target>")
+            .replace("friend <x id=\"1\"/></source>", "friend <x id=\"1\"/></source>\n        <target>salut <x id=\"1\"/> <x id=\"0\"/></target>");
+        let translations = read_xliff(&filled).unwrap();
+        assert_eq!(translations.len(), 2);
+        assert_eq!(translations["intro:accept:0"], "voilà <3");
+        assert_eq!(translations["intro:intro:0"], "salut {1} {0}");
+    }
+
+    #[test]
+    fn test_placeholders_checked()
+    {
+        let translations = HashMap::from([
+            ("intro:intro:0".to_owned(), "salut {0} {0} {2}".to_owned()),
+        ]);
+
+        let errors = apply("intro.adlib", SOURCE, &translations, &CustomCommands::default()).unwrap_err();
+        let kinds = errors.iter().map(|x| x.kind.clone()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            StringTableErrorKind::UnknownPlaceholder(2),
+            StringTableErrorKind::RepeatedPlaceholder(0),
+            StringTableErrorKind::MissingPlaceholder(1),
+        ]);
+        assert_eq!(errors[2].to_string(), "intro:intro:0: translation is missing {1}");
+
+        // Translations can't sneak in broken commands either
+        let translations = HashMap::from([
+            ("intro:accept:0".to_owned(), "voila (j".to_owned()),
+        ]);
+        let errors = apply("intro.adlib", SOURCE, &translations, &CustomCommands::default()).unwrap_err();
+        assert!(matches!(errors[0].kind, StringTableErrorKind::Parse(_)));
+    }
+
+    #[test]
+    fn test_assign_line_ids()
+    {
+        let source = "[talker goose]\r\nsound = snd_goose\r\n\r\n[intro]\r\ngoose | hello (j) there (/j)\r\n# comment\r\n(wait 10)\r\nsecond line #id:intro_1\r\n> choice\r\nthird\r\n";
+        let assigned = assign_line_ids("intro.adlib", source, &CustomCommands::default()).unwrap();
+        assert_eq!(assigned, "[talker goose]\r\nsound = snd_goose\r\n\r\n[intro]\r\ngoose | hello (j) there (/j) #id:intro_2\r\n# comment\r\n(wait 10)\r\nsecond line #id:intro_1\r\n> choice\r\nthird #id:intro_3\r\n");
+
+        // Running again changes nothing
+        assert_eq!(assign_line_ids("intro.adlib", &assigned, &CustomCommands::default()).unwrap(), assigned);
+
+        // Tagged lines are keyed by their id and keep it through a translation
+        let entries = extract("intro.adlib", &assigned, &CustomCommands::default()).unwrap();
+        assert_eq!(entries[0].id, "intro:intro_2");
+        assert_eq!(entries[0].text, "hello {0} there {1}");
+
+        let translations = HashMap::from([
+            ("intro:intro_2".to_owned(), "{0} salut {1}".to_owned()),
+        ]);
+        let translated = apply("intro.adlib", &assigned, &translations, &CustomCommands::default()).unwrap();
+        assert!(translated.contents.contains("goose | (j) salut (/j) #id:intro_2\r\n"));
+    }
+
+    #[test]
+    fn test_line_endings_kept()
+    {
+        let translations = HashMap::from([
+            ("intro:intro:1".to_owned(), "un crapaud ?".to_owned()),
+            ("intro:accept:0".to_owned(), "voila".to_owned()),
+        ]);
+
+        let crlf = SOURCE.replace('\n', "\r\n");
+        let translated = apply("intro.adlib", &crlf, &translations, &CustomCommands::default()).unwrap();
+        assert_eq!(translated.contents, crlf.replace("want a toad?", "un crapaud ?").replace("here you go", "voila"));
+
+        // No newline at the end stays that way
+        let source = "[intro]\nhere you go";
+        let translations = HashMap::from([
+            ("intro:intro:0".to_owned(), "voila".to_owned()),
+        ]);
+        assert_eq!(apply("intro.adlib", source, &translations, &CustomCommands::default()).unwrap().contents, "[intro]\nvoila");
+    }
+
+    #[test]
+    fn test_literal_braces()
+    {
+        let source = "[intro]\nthe code is {0} (j)\n> pick {1} -> intro\n";
+        let entries = extract("intro.adlib", source, &CustomCommands::default()).unwrap();
+        assert_eq!(entries[0].text, "the code is {{0} {0}");
+        assert_eq!(entries[1].text, "pick {{1}");
+
+        let xliff = to_xliff(&entries, "en", None);
+        assert!(xliff.contains("<source>the code is {{0} <x id=\"0\"/></source>"));
+
+        // Escaped braces come back as they were and aren't counted as placeholders
+        let translations = HashMap::from([
+            ("intro:intro:0".to_owned(), "{0} le code est {{0}".to_owned()),
+            ("intro:intro:1".to_owned(), "choisir {{1}".to_owned()),
+        ]);
+        let translated = apply("intro.adlib", source, &translations, &CustomCommands::default()).unwrap();
+        assert_eq!(translated.contents, "[intro]\n(j) le code est {0}\n> choisir {1} -> intro\n");
+    }
+
+    #[test]
+    fn test_custom_commands()
+    {
+        let source = "[intro]\nhello (shake 3) there\n(camera pan)\n";
+        assert!(extract("intro.adlib", source, &CustomCommands::default()).is_err());
+
+        let custom_commands = CustomCommands::parse_list("# the game's commands\nshake\n\ncamera blocking\n");
+        let entries = extract("intro.adlib", source, &custom_commands).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].text, "hello {0} there");
+        assert_eq!(entries[0].placeholders, vec!["(shake 3)"]);
+        assert_eq!(custom_commands.lookup("camera"), Some(true));
+
+        let translations = HashMap::from([
+            ("intro:intro:0".to_owned(), "salut {0}".to_owned()),
+        ]);
+        let translated = apply("intro.adlib", source, &translations, &custom_commands).unwrap();
+        assert_eq!(translated.contents, "[intro]\nsalut (shake 3)\n(camera pan)\n");
+        assert!(assign_line_ids("intro.adlib", source, &custom_commands).is_ok());
+    }
+}
@@ -1,5 +1,5 @@
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, PartialEq)]
 pub struct Talker
 {
     pub name : String,
@@ -0,0 +1,415 @@
+use std::fmt;
+
+use crate::event::DialogueEvent;
+use crate::interop::global_state::{GlobalState, MAIN_INSTANCE};
+use crate::interop::queue_params::QueueParams;
+
+// Plays a section with a fixed dt and scripted input, writing down every frame where the
+// text changed or events came out. The same file, script and options always give the same
+// transcript, so it can be kept as a golden file and compared with diff.
+
+pub const TRANSCRIPT_VERSION : u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Advance,
+    CompleteLine,
+    SkipSection,
+    // 1-based like the options are shown
+    Choose(usize),
+    Resume,
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Advance => write!(f, "advance"),
+            Input::CompleteLine => write!(f, "complete_line"),
+            Input::SkipSection => write!(f, "skip_section"),
+            Input::Choose(n) => write!(f, "choose {}", n),
+            Input::Resume => write!(f, "resume"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScriptStep
+{
+    // Without a frame the step waits until the engine needs the player, for a choice, a
+    // blocking command or the end of a line in wait for input mode
+    pub frame : Option<u32>,
+    pub input : Input,
+}
+
+// Steps are used in order, one per line as "choose 2" or "@120 advance", # starts a comment.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Script
+{
+    pub steps : Vec<ScriptStep>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptError
+{
+    // 1-based
+    pub line : usize,
+    pub text : String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: could not parse script step '{}'", self.line, self.text)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl Script {
+    pub fn parse(s : &str) -> Result<Self, ScriptError> {
+        let mut steps = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if (line.is_empty() || line.starts_with("#")) {
+                continue;
+            }
+
+            let error = || ScriptError { line : i + 1, text : line.to_owned() };
+            let mut splits = line.split_ascii_whitespace().peekable();
+
+            let frame = match splits.peek().and_then(|x| x.strip_prefix('@')) {
+                Some(frame) => {
+                    let frame = frame.parse().map_err(|_| error())?;
+                    splits.next();
+                    Some(frame)
+                },
+                None => None,
+            };
+
+            let input = match (splits.next(), splits.next()) {
+                (Some(x), None) if unicase::eq_ascii(x, "advance") => Input::Advance,
+                (Some(x), None) if unicase::eq_ascii(x, "complete_line") => Input::CompleteLine,
+                (Some(x), None) if unicase::eq_ascii(x, "skip_section") => Input::SkipSection,
+                (Some(x), None) if unicase::eq_ascii(x, "resume") => Input::Resume,
+                (Some(x), Some(n)) if unicase::eq_ascii(x, "choose") => {
+                    Input::Choose(n.parse().ok().filter(|n| *n > 0).ok_or_else(error)?)
+                },
+                _ => return Err(error()),
+            };
+
+            if (splits.next().is_some()) {
+                return Err(error());
+            }
+
+            steps.push(ScriptStep { frame, input });
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TranscriptOptions
+{
+    pub dt : f32,
+    // Gives up past this, in case the script never gets the dialogue to the end
+    pub max_frames : u32,
+    pub seed : u64,
+    // Blips are left out by default, the text already shows each character arriving
+    pub blips : bool,
+}
+
+impl Default for TranscriptOptions {
+    fn default() -> Self {
+        Self {
+            dt : 1.0,
+            max_frames : 60 * 60 * 10,
+            seed : 0,
+            blips : false,
+        }
+    }
+}
+
+// Queues filename:section on state, as the game would, and plays it to the end. Engine
+// options like wait_for_input are taken from state.
+pub fn record(state : &mut GlobalState, filename : &str, section : &str, script : &Script, options : &TranscriptOptions) -> String {
+    let mut recorder = Recorder {
+        out : format!("adlib-transcript {}\n", TRANSCRIPT_VERSION),
+        text : String::new(),
+        blips : options.blips,
+    };
+
+    state.main.engine.variants.seed(options.seed);
+    state.queue(QueueParams {
+        filename,
+        section,
+        ..Default::default()
+    });
+    recorder.frame(0, state);
+
+    let mut steps = script.steps.iter().peekable();
+    for frame in 1..=options.max_frames {
+        let waiting_on_player = state.main.engine.choices().is_some() ||
+            state.main.engine.blocking_command().is_some() ||
+            state.main.engine.awaiting_input();
+
+        let mut answered = false;
+        while let Some(step) = steps.peek().copied() {
+            let due = match step.frame {
+                Some(step_frame) => step_frame <= frame,
+                None => waiting_on_player,
+            };
+            if (!due) {
+                break;
+            }
+
+            recorder.line(frame, &format!("> {}", step.input));
+            apply(state, step.input);
+            steps.next();
+            answered |= step.frame.is_none();
+
+            // Input without a frame answers one wait, the next waits for the one after
+            if (step.frame.is_none()) {
+                break;
+            }
+        }
+
+        if (waiting_on_player && !answered && steps.peek().is_none()) {
+            recorder.line(frame, "stuck waiting for input");
+            return recorder.out;
+        }
+
+        state.tick(options.dt);
+        recorder.frame(frame, state);
+
+        if (state.main.engine.current_filename().is_none()) {
+            recorder.line(frame, "end");
+            return recorder.out;
+        }
+    }
+
+    recorder.line(options.max_frames, "gave up");
+    recorder.out
+}
+
+fn apply(state : &mut GlobalState, input : Input) {
+    match input {
+        Input::Advance => {
+            state.advance(MAIN_INSTANCE);
+        },
+        Input::CompleteLine => {
+            state.complete_line(MAIN_INSTANCE);
+        },
+        Input::SkipSection => {
+            state.skip_section(MAIN_INSTANCE);
+        },
+        Input::Choose(n) => {
+            state.choose(MAIN_INSTANCE, n - 1);
+        },
+        Input::Resume => {
+            state.main.engine.resume_custom_command();
+        },
+    }
+}
+
+struct Recorder
+{
+    out : String,
+    // As last written, only changes are recorded
+    text : String,
+    blips : bool,
+}
+
+impl Recorder {
+    fn line(&mut self, frame : u32, line : &str) {
+        self.out.push_str(&format!("{} {}\n", frame, line));
+    }
+
+    fn frame(&mut self, frame : u32, state : &mut GlobalState) {
+        for event in state.main.engine.drain_events() {
+            match &event {
+                DialogueEvent::Blip { sound, character } => {
+                    if (self.blips) {
+                        self.line(frame, &format!("blip {:?} {:?}", sound, character));
+                    }
+                },
+                _ => {
+                    let arg = event.arg();
+                    if (arg.is_empty()) {
+                        self.line(frame, event.name());
+                    }
+                    else {
+                        self.line(frame, &format!("{} {:?}", event.name(), arg));
+                    }
+                },
+            }
+        }
+
+        // Spans as "text" with any annotations in front, eg jiggly:"wobbly"
+        let mut text = String::new();
+        let mut iter = state.main.engine.current_string_iter();
+        while let Some((span, annotation)) = iter.next() {
+            if (!text.is_empty()) {
+                text.push(' ');
+            }
+            for annotation in &annotation.annotations {
+                text.push_str(annotation.name());
+                text.push(':');
+            }
+            text.push_str(&format!("{:?}", span));
+        }
+
+        if (text != self.text) {
+            self.line(frame, format!("text {}", text).trim_end());
+            self.text = text;
+        }
+    }
+}
+
+// Line by line differences between a golden transcript and a new one, None if they match.
+// Lines are numbered as in expected for removals and actual for additions.
+pub fn diff(expected : &str, actual : &str) -> Option<String> {
+    let a = expected.lines().collect::<Vec<_>>();
+    let b = actual.lines().collect::<Vec<_>>();
+    if (a == b) {
+        return None;
+    }
+
+    // Longest common subsequence from the ends, lcs[i][j] covers a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if (a[i] == b[j]) {
+                lcs[i + 1][j + 1] + 1
+            }
+            else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if (i < a.len() && j < b.len() && a[i] == b[j]) {
+            i += 1;
+            j += 1;
+        }
+        else if (j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j])) {
+            out.push_str(&format!("+{}: {}\n", j + 1, b[j]));
+            j += 1;
+        }
+        else {
+            out.push_str(&format!("-{}: {}\n", i + 1, a[i]));
+            i += 1;
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::custom_commands::CustomCommands;
+
+    fn state_for(name : &str, contents : &str) -> GlobalState {
+        let dir = std::env::temp_dir().join("ad_libber_test_transcript");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(format!("{}.adlib", name)), contents).unwrap();
+
+        let mut state = GlobalState {
+            path : dir.to_string_lossy().into_owned() + "/",
+            ..Default::default()
+        };
+        state.main.engine.options.text_rate = 1.0;
+        state.main.engine.options.line_linger_time = 3.0;
+        state
+    }
+
+    #[test]
+    fn test_record()
+    {
+        let source = "[talker goose]
+sound = snd_goose
+
+[intro]
+goose | hi (j) yo (/j)
+> again -> intro
+> stop -> outro
+
+[outro]
+(wait 2)
+bye";
+
+        let script = Script::parse("# pick the second one\nchoose 2").unwrap();
+        let transcript = record(&mut state_for("record", source), "record", "intro", &script, &TranscriptOptions::default());
+        assert_eq!(transcript, r##"adlib-transcript 1
+0 speaker_changed "goose"
+0 line_started "goose"
+1 text ""
+2 text "h"
+3 text "hi"
+4 annotation_opened "jiggly"
+4 text "hi" jiggly:""
+6 text "hi" jiggly:"y"
+7 text "hi" jiggly:"yo"
+8 annotation_closed "jiggly"
+8 text "hi" jiggly:"yo" ""
+10 line_finished
+10 text "hi" jiggly:"yo" "#"
+13 > choose 2
+13 clear
+13 wait_started "2"
+13 text "" ""
+15 wait_ended
+15 line_started "goose"
+16 text "" "b"
+17 text "" "by"
+18 text "" "bye"
+19 line_finished
+19 text "" "bye#"
+21 section_finished "intro"
+24 clear
+24 text
+24 end
+"##);
+
+        // Same again, and stuck without the choice
+        assert_eq!(record(&mut state_for("record", source), "record", "intro", &script, &TranscriptOptions::default()), transcript);
+        let stuck = record(&mut state_for("record", source), "record", "intro", &Script::default(), &TranscriptOptions::default());
+        assert!(stuck.ends_with("10 text \"hi\" jiggly:\"yo\" \"#\"\n13 stuck waiting for input\n"));
+    }
+
+    #[test]
+    fn test_custom_command()
+    {
+        let mut state = state_for("shake", "[intro]\nhi (shake 3)\nbye");
+        state.cache.custom_commands = CustomCommands::parse_list("shake blocking");
+        let transcript = record(&mut state, "shake", "intro", &Script::parse("resume").unwrap(), &TranscriptOptions::default());
+        assert!(transcript.contains("custom \"shake 3\"\n"));
+        assert!(transcript.contains("> resume\n"));
+        assert!(transcript.ends_with(" end\n"));
+    }
+
+    #[test]
+    fn test_script()
+    {
+        let script = Script::parse("@30 advance\n  # note\nchoose 1\nresume").unwrap();
+        assert_eq!(script.steps, vec![
+            ScriptStep { frame : Some(30), input : Input::Advance },
+            ScriptStep { frame : None, input : Input::Choose(1) },
+            ScriptStep { frame : None, input : Input::Resume },
+        ]);
+
+        assert_eq!(Script::parse("advance\nchoose 0"), Err(ScriptError { line : 2, text : "choose 0".to_owned() }));
+        assert_eq!(Script::parse("@soon advance").unwrap_err().line, 1);
+        assert_eq!(Script::parse("resume now").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn test_diff()
+    {
+        assert_eq!(diff("a\nb\nc\n", "a\nb\nc"), None);
+        assert_eq!(diff("a\nb\nc\nd", "a\nx\nc\nd\ne").unwrap(), "+2: x\n-2: b\n+5: e\n");
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parse_error::ParseErrorKind;
+use crate::seen::{SeenLines, SeenTarget};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Text(String),
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Number(0.0)
+    }
+}
+
+impl Value {
+    // Bare words that aren't numbers are text, true and false are 1 and 0.
+    pub fn parse(s : &str) -> Self {
+        if (unicase::eq_ascii(s, "true")) {
+            Value::Number(1.0)
+        }
+        else if (unicase::eq_ascii(s, "false")) {
+            Value::Number(0.0)
+        }
+        else if let Ok(x) = s.parse::<f64>() {
+            Value::Number(x)
+        }
+        else {
+            Value::Text(s.trim_matches('"').to_owned())
+        }
+    }
+
+    pub fn truthy(&self) -> bool {
+        match self {
+            Value::Number(x) => *x != 0.0,
+            Value::Text(x) => !x.is_empty(),
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        match self {
+            Value::Number(x) => *x,
+            Value::Text(x) => x.parse::<f64>().unwrap_or(0.0),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(x) => write!(f, "{}", x),
+            Value::Text(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    fn parse(s : &str) -> Option<Self> {
+        match s {
+            "==" | "=" => Some(CompareOp::Eq),
+            "!=" => Some(CompareOp::Ne),
+            "<" => Some(CompareOp::Lt),
+            "<=" => Some(CompareOp::Le),
+            ">" => Some(CompareOp::Gt),
+            ">=" => Some(CompareOp::Ge),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+
+    fn compare(&self, lhs : &Value, rhs : &Value) -> bool {
+        match (lhs, rhs, self) {
+            (Value::Text(x), Value::Text(y), CompareOp::Eq) => unicase::eq_ascii(x, y),
+            (Value::Text(x), Value::Text(y), CompareOp::Ne) => !unicase::eq_ascii(x, y),
+            (_, _, CompareOp::Eq) => lhs.as_number() == rhs.as_number(),
+            (_, _, CompareOp::Ne) => lhs.as_number() != rhs.as_number(),
+            (_, _, CompareOp::Lt) => lhs.as_number() < rhs.as_number(),
+            (_, _, CompareOp::Le) => lhs.as_number() <= rhs.as_number(),
+            (_, _, CompareOp::Gt) => lhs.as_number() > rhs.as_number(),
+            (_, _, CompareOp::Ge) => lhs.as_number() >= rhs.as_number(),
+        }
+    }
+}
+
+// "name", "!name", "not name" or "name op value". "seen target" in place of the name is
+// how many times a section or line has been seen, see SeenTarget.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition
+{
+    pub name : String,
+    pub negate : bool,
+    pub seen : Option<SeenTarget>,
+    pub compare : Option<(CompareOp, Value)>,
+}
+
+impl Condition {
+    pub fn parse(args : &[&str]) -> Result<Self, ParseErrorKind> {
+        let mut negate = false;
+        let mut args = args;
+
+        if let Some(first) = args.first() {
+            if (unicase::eq_ascii(*first, "not")) {
+                negate = true;
+                args = &args[1..];
+            }
+        }
+
+        let name = match args.first() {
+            Some(name) => {
+                if let Some(stripped) = name.strip_prefix('!') {
+                    negate = !negate;
+                    stripped
+                }
+                else {
+                    name
+                }
+            },
+            None => return Err(ParseErrorKind::MissingArgument),
+        };
+
+        if (name.is_empty()) {
+            return Err(ParseErrorKind::MissingArgument);
+        }
+
+        // "seen" on its own or compared is still a variable
+        let seen = match args.get(1) {
+            Some(target) if unicase::eq_ascii(name, "seen") && args.len() != 3 => {
+                args = &args[1..];
+                Some(SeenTarget::parse(target).ok_or(ParseErrorKind::BadExpression)?)
+            },
+            _ => None,
+        };
+
+        let compare = match args.len() {
+            1 => None,
+            3 => {
+                let op = CompareOp::parse(args[1]).ok_or(ParseErrorKind::BadExpression)?;
+                Some((op, Value::parse(args[2])))
+            },
+            _ => return Err(ParseErrorKind::BadExpression),
+        };
+
+        Ok(Self {
+            name : name.to_owned(),
+            negate,
+            seen,
+            compare,
+        })
+    }
+
+    // current_filename resolves seen targets in other files.
+    pub fn evaluate(&self, variables : &Variables, current_filename : &str) -> bool {
+        let value = match &self.seen {
+            Some(target) => Value::Number(variables.seen.count(&target.key(current_filename)) as f64),
+            None => variables.get(&self.name).cloned().unwrap_or_default(),
+        };
+        let result = match &self.compare {
+            Some((op, rhs)) => op.compare(&value, rhs),
+            None => value.truthy(),
+        };
+
+        result != self.negate
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SetOp {
+    Assign,
+    Add,
+    Sub,
+}
+
+impl SetOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            SetOp::Assign => "=",
+            SetOp::Add => "+=",
+            SetOp::Sub => "-=",
+        }
+    }
+}
+
+// "name value", "name += value", "name -= value", or just "name" to set true.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment
+{
+    pub name : String,
+    pub op : SetOp,
+    pub value : Value,
+}
+
+impl Assignment {
+    pub fn parse(args : &[&str]) -> Result<Self, ParseErrorKind> {
+        let name = args.first().ok_or(ParseErrorKind::MissingArgument)?;
+
+        let (op, value) = match args.len() {
+            1 => (SetOp::Assign, Value::Number(1.0)),
+            2 => (SetOp::Assign, Value::parse(args[1])),
+            3 => {
+                let op = match args[1] {
+                    "=" => SetOp::Assign,
+                    "+=" => SetOp::Add,
+                    "-=" => SetOp::Sub,
+                    _ => return Err(ParseErrorKind::BadExpression),
+                };
+                (op, Value::parse(args[2]))
+            },
+            _ => return Err(ParseErrorKind::BadExpression),
+        };
+
+        Ok(Self {
+            name : name.to_string(),
+            op,
+            value,
+        })
+    }
+
+    pub fn apply(&self, variables : &mut Variables) {
+        let value = match self.op {
+            SetOp::Assign => self.value.clone(),
+            SetOp::Add => Value::Number(variables.get_number(&self.name) + self.value.as_number()),
+            SetOp::Sub => Value::Number(variables.get_number(&self.name) - self.value.as_number()),
+        };
+
+        variables.set(&self.name, value);
+    }
+}
+
+// Names are case insensitive like section names.
+#[derive(Default, Clone, Debug)]
+pub struct Variables
+{
+    values : HashMap<String, Value>,
+    // Kept apart from the values, clear doesn't touch it
+    pub seen : SeenLines,
+}
+
+impl Variables {
+    pub fn get(&self, name : &str) -> Option<&Value> {
+        self.values.get(&name.to_ascii_lowercase())
+    }
+
+    pub fn get_number(&self, name : &str) -> f64 {
+        self.get(name).map(|x| x.as_number()).unwrap_or(0.0)
+    }
+
+    pub fn set(&mut self, name : &str, value : Value) {
+        self.values.insert(name.to_ascii_lowercase(), value);
+    }
+
+    pub fn remove(&mut self, name : &str) -> Option<Value> {
+        self.values.remove(&name.to_ascii_lowercase())
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.values.iter().map(|(k, v)| (&k[..], v))
+    }
+}